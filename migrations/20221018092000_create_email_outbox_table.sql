@@ -0,0 +1,12 @@
+CREATE TABLE email_outbox(
+    email_id uuid NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    created_at timestamptz NOT NULL,
+    n_attempts INT NOT NULL,
+    next_attempt_at timestamptz NOT NULL,
+    last_error TEXT NULL,
+    PRIMARY KEY (email_id)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE subscriber_preferences(
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    topics TEXT[] NOT NULL,
+    delivery_mode TEXT NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (subscriber_id)
+);
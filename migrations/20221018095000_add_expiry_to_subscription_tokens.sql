@@ -0,0 +1,11 @@
+BEGIN;
+    ALTER TABLE subscription_tokens ADD COLUMN created_at timestamptz NULL;
+    ALTER TABLE subscription_tokens ADD COLUMN expires_at timestamptz NULL;
+    ALTER TABLE subscription_tokens ADD COLUMN used_at timestamptz NULL;
+    -- 既存のトークンは、マイグレーション時点から2日間有効とする
+    UPDATE subscription_tokens
+        SET created_at = now(), expires_at = now() + interval '48 hours'
+        WHERE created_at IS NULL;
+    ALTER TABLE subscription_tokens ALTER COLUMN created_at SET NOT NULL;
+    ALTER TABLE subscription_tokens ALTER COLUMN expires_at SET NOT NULL;
+COMMIT;
@@ -0,0 +1,7 @@
+BEGIN;
+    -- トークンはサーバの秘密鍵によるHMACとして保存する
+    ALTER TABLE subscription_tokens RENAME COLUMN subscription_token TO subscription_token_hash;
+    -- 既存の行は平文のままなので、アプリケーションの起動時にハッシュ化する
+    ALTER TABLE subscription_tokens ADD COLUMN is_plaintext BOOLEAN NOT NULL DEFAULT true;
+    ALTER TABLE subscription_tokens ALTER COLUMN is_plaintext SET DEFAULT false;
+COMMIT;
@@ -0,0 +1,7 @@
+CREATE TABLE confirmation_resend_attempts(
+    -- "email:<アドレス>" または "ip:<IPアドレス>"
+    throttle_key TEXT NOT NULL,
+    attempted_at timestamptz NOT NULL
+);
+CREATE INDEX confirmation_resend_attempts_throttle_key_idx
+    ON confirmation_resend_attempts (throttle_key, attempted_at);
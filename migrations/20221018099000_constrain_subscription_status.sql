@@ -0,0 +1,10 @@
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check CHECK (
+    status IN (
+        'pending_confirmation',
+        'confirmed',
+        'unsubscribed',
+        'bounced',
+        'complained',
+        'deleted'
+    )
+);
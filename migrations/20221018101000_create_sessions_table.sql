@@ -0,0 +1,5 @@
+CREATE TABLE sessions(
+    session_id TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    expires_at timestamptz NOT NULL
+);
@@ -0,0 +1,11 @@
+-- 既存のユーザーは初期管理者なので、すべての権限を持つownerとする
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'owner';
+ALTER TABLE users ALTER COLUMN role DROP DEFAULT;
+ALTER TABLE users ADD CONSTRAINT users_role_check CHECK (
+    role IN (
+        'viewer',
+        'editor',
+        'publisher',
+        'owner'
+    )
+);
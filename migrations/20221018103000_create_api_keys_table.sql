@@ -0,0 +1,12 @@
+CREATE TABLE api_keys(
+    api_key_id uuid PRIMARY KEY,
+    name TEXT NOT NULL,
+    -- キーそのものは保存せず、SHA-256のハッシュだけを保存する
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    created_by uuid NOT NULL REFERENCES users (user_id),
+    created_at timestamptz NOT NULL,
+    revoked_at timestamptz NULL,
+    last_used_at timestamptz NULL,
+    last_used_ip TEXT NULL
+);
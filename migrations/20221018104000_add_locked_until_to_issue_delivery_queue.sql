@@ -0,0 +1,2 @@
+-- 配信タスクは、送信中もトランザクションを開いたままにせず、期限付きで確保する
+ALTER TABLE issue_delivery_queue ADD COLUMN locked_until timestamptz NULL;
@@ -0,0 +1,7 @@
+-- 同じキーでも、ルートと呼び出し元が違えば別のリクエストとして扱う
+ALTER TABLE idempotency ADD COLUMN scope TEXT NOT NULL DEFAULT '';
+ALTER TABLE idempotency ADD COLUMN request_fingerprint TEXT NOT NULL DEFAULT '';
+ALTER TABLE idempotency ALTER COLUMN scope DROP DEFAULT;
+ALTER TABLE idempotency ALTER COLUMN request_fingerprint DROP DEFAULT;
+ALTER TABLE idempotency DROP CONSTRAINT idempotency_pkey;
+ALTER TABLE idempotency ADD PRIMARY KEY (scope, idempotency_key);
@@ -0,0 +1,5 @@
+-- トピックを指定しない号は、すべての購読者に配信する
+ALTER TABLE newsletter_issues ADD COLUMN topic TEXT NULL;
+-- まとめて受け取る購読者への配信は、この時刻まで送らない
+ALTER TABLE issue_delivery_queue ADD COLUMN deliver_after timestamptz NOT NULL DEFAULT now();
+ALTER TABLE issue_delivery_queue ALTER COLUMN deliver_after DROP DEFAULT;
@@ -0,0 +1,3 @@
+-- 期限切れの記録をまとめて削除するためのインデックス
+CREATE INDEX confirmation_resend_attempts_attempted_at_idx
+    ON confirmation_resend_attempts (attempted_at);
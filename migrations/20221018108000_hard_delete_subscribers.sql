@@ -0,0 +1,20 @@
+-- subscriberの削除は行ごと消す意味に統一する
+-- これまでdeletedとして残していた行と、それを参照する行を削除する
+BEGIN;
+    DELETE FROM subscription_tokens
+    WHERE subscriber_id IN (SELECT id FROM subscriptions WHERE status = 'deleted');
+    DELETE FROM subscriber_preferences
+    WHERE subscriber_id IN (SELECT id FROM subscriptions WHERE status = 'deleted');
+    DELETE FROM subscriptions WHERE status = 'deleted';
+
+    ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_status_check;
+    ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check CHECK (
+        status IN (
+            'pending_confirmation',
+            'confirmed',
+            'unsubscribed',
+            'bounced',
+            'complained'
+        )
+    );
+COMMIT;
@@ -0,0 +1,3 @@
+-- 期限切れのセッションをまとめて削除するためのインデックス
+CREATE INDEX sessions_expires_at_idx
+    ON sessions (expires_at);
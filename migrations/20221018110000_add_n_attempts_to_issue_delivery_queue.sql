@@ -0,0 +1,2 @@
+-- 送信に失敗した配信タスクは、回数を数えながら再試行する
+ALTER TABLE issue_delivery_queue ADD COLUMN n_attempts INT NOT NULL DEFAULT 0;
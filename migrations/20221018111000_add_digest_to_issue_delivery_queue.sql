@@ -0,0 +1 @@
+ALTER TABLE issue_delivery_queue ADD COLUMN digest BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,14 @@
+-- 削除はdeletedへの状態遷移として記録し、行の削除はその後の別の手順で行う
+BEGIN;
+    ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_status_check;
+    ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check CHECK (
+        status IN (
+            'pending_confirmation',
+            'confirmed',
+            'unsubscribed',
+            'bounced',
+            'complained',
+            'deleted'
+        )
+    );
+COMMIT;
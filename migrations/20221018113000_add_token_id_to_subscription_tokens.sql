@@ -0,0 +1,3 @@
+-- 新しく発行するトークンはIDを含み、IDで行を引いてから保存したHMACと比較する
+-- 既存のトークンにはIDがないため、NULLのままにする
+ALTER TABLE subscription_tokens ADD COLUMN token_id uuid NULL UNIQUE;
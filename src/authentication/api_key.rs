@@ -0,0 +1,303 @@
+use crate::authentication::Permission;
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use chrono::{DateTime, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const API_KEY_PREFIX: &str = "z2p_";
+
+// APIキーに許可する操作の範囲
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiScope {
+    SubscribersRead,
+    SubscribersWrite,
+    SubscribersDelete,
+    IssuesPublish,
+}
+
+impl ApiScope {
+    pub fn parse(s: String) -> Result<ApiScope, String> {
+        match s.as_str() {
+            "subscribers:read" => Ok(Self::SubscribersRead),
+            "subscribers:write" => Ok(Self::SubscribersWrite),
+            "subscribers:delete" => Ok(Self::SubscribersDelete),
+            "issues:publish" => Ok(Self::IssuesPublish),
+            _ => Err(format!("{} is not a valid scope.", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SubscribersRead => "subscribers:read",
+            Self::SubscribersWrite => "subscribers:write",
+            Self::SubscribersDelete => "subscribers:delete",
+            Self::IssuesPublish => "issues:publish",
+        }
+    }
+
+    // ルートに宣言された権限を、APIキーで使うために必要なスコープ
+    // Noneの権限は、APIキーでは使えない
+    pub fn required_for(permission: Permission) -> Option<ApiScope> {
+        match permission {
+            Permission::ReadSubscribers => Some(Self::SubscribersRead),
+            // 削除は取り消せないため、追加とは別のスコープにする
+            Permission::WriteSubscribers => Some(Self::SubscribersWrite),
+            Permission::DeleteSubscribers => Some(Self::SubscribersDelete),
+            Permission::PublishIssues => Some(Self::IssuesPublish),
+            Permission::DraftIssues | Permission::ManageApiKeys => None,
+        }
+    }
+}
+
+// Authorization: Bearer <APIキー> で認証したAPIキー
+#[derive(Debug, Clone)]
+pub struct AuthenticatedApiKey {
+    pub api_key_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+}
+
+impl AuthenticatedApiKey {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        match ApiScope::required_for(permission) {
+            Some(scope) => self.scopes.contains(&scope),
+            None => false,
+        }
+    }
+}
+
+// 一覧に表示する情報(キーもハッシュも含めない)
+#[derive(Debug, Serialize)]
+pub struct ApiKeySummary {
+    pub api_key_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+}
+
+// キーは作成時に一度だけ返し、以降は取り出せない
+#[tracing::instrument(name = "Create an API key", skip(pool))]
+pub async fn store_api_key(
+    pool: &PgPool,
+    name: &str,
+    scopes: &[ApiScope],
+    created_by: Uuid,
+) -> Result<(Uuid, String), sqlx::Error> {
+    let api_key_id = Uuid::new_v4();
+    let api_key = generate_api_key();
+    let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+
+    sqlx::query!(
+        r#"INSERT INTO api_keys (api_key_id, name, key_hash, scopes, created_by, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        api_key_id,
+        name,
+        hash_api_key(&api_key),
+        &scopes,
+        created_by,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok((api_key_id, api_key))
+}
+
+#[tracing::instrument(name = "List API keys", skip(pool))]
+pub async fn get_api_keys(pool: &PgPool) -> Result<Vec<ApiKeySummary>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeySummary,
+        r#"SELECT api_key_id, name, scopes, created_at, revoked_at, last_used_at, last_used_ip
+        FROM api_keys
+        ORDER BY created_at DESC"#
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+// 失効させた場合はtrueを返す
+// 存在しないか、すでに失効しているキーの場合はfalseを返す
+#[tracing::instrument(name = "Revoke an API key", skip(pool))]
+pub async fn mark_api_key_as_revoked(pool: &PgPool, api_key_id: Uuid) -> Result<bool, sqlx::Error> {
+    let n_revoked = sqlx::query!(
+        r#"UPDATE api_keys SET revoked_at = now()
+        WHERE api_key_id = $1 AND revoked_at IS NULL"#,
+        api_key_id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+
+    Ok(n_revoked > 0)
+}
+
+// 有効なキーであれば、最後に使われた日時とIPアドレスを記録して返す
+#[tracing::instrument(name = "Authenticate an API key", skip(pool, api_key))]
+pub async fn authenticate_api_key(
+    pool: &PgPool,
+    api_key: &str,
+    client_ip: &str,
+) -> Result<Option<AuthenticatedApiKey>, String> {
+    let row = sqlx::query!(
+        r#"UPDATE api_keys SET last_used_at = now(), last_used_ip = $2
+        WHERE key_hash = $1 AND revoked_at IS NULL
+        RETURNING api_key_id, name, scopes"#,
+        hash_api_key(api_key),
+        client_ip
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e.to_string()
+    })?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let scopes = row
+        .scopes
+        .into_iter()
+        .map(ApiScope::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(AuthenticatedApiKey {
+        api_key_id: row.api_key_id,
+        name: row.name,
+        scopes,
+    }))
+}
+
+// Authorization: Bearer <APIキー>
+// Bearer以外の方式の場合はNoneを返す
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// キーは十分に長いランダムな文字列なので、パスワードと違い低速なハッシュは必要ない
+// 同じキーからは同じハッシュが得られるため、ハッシュでキーを検索できる
+fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+// 接頭辞を付けて、ログやリポジトリに漏れたときにAPIキーだと分かるようにする
+fn generate_api_key() -> String {
+    let mut rng = thread_rng();
+    let random: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(43)
+        .collect();
+    format!("{}{}", API_KEY_PREFIX, random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bearer_token, generate_api_key, hash_api_key, ApiScope, AuthenticatedApiKey};
+    use crate::authentication::Permission;
+    use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+    use claim::assert_err;
+    use uuid::Uuid;
+
+    fn api_key_with(scopes: Vec<ApiScope>) -> AuthenticatedApiKey {
+        AuthenticatedApiKey {
+            api_key_id: Uuid::new_v4(),
+            name: "cms".into(),
+            scopes,
+        }
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        for scope in [
+            ApiScope::SubscribersRead,
+            ApiScope::SubscribersWrite,
+            ApiScope::SubscribersDelete,
+            ApiScope::IssuesPublish,
+        ] {
+            assert_eq!(ApiScope::parse(scope.as_str().to_string()), Ok(scope));
+        }
+    }
+
+    #[test]
+    fn an_unknown_scope_is_rejected() {
+        assert_err!(ApiScope::parse("subscribers:*".to_string()));
+    }
+
+    #[test]
+    fn a_key_is_only_allowed_what_its_scopes_cover() {
+        let api_key = api_key_with(vec![ApiScope::SubscribersRead]);
+
+        assert!(api_key.has_permission(Permission::ReadSubscribers));
+        assert!(!api_key.has_permission(Permission::PublishIssues));
+        assert!(!api_key.has_permission(Permission::DeleteSubscribers));
+    }
+
+    #[test]
+    fn the_write_scope_does_not_allow_deleting_subscribers() {
+        let api_key = api_key_with(vec![ApiScope::SubscribersWrite]);
+
+        assert!(api_key.has_permission(Permission::WriteSubscribers));
+        assert!(!api_key.has_permission(Permission::DeleteSubscribers));
+    }
+
+    #[test]
+    fn a_key_cannot_manage_api_keys() {
+        let api_key = api_key_with(vec![
+            ApiScope::SubscribersRead,
+            ApiScope::SubscribersWrite,
+            ApiScope::IssuesPublish,
+        ]);
+
+        assert!(!api_key.has_permission(Permission::ManageApiKeys));
+        assert!(!api_key.has_permission(Permission::DraftIssues));
+    }
+
+    #[test]
+    fn generated_keys_are_unique_and_hashed_deterministically() {
+        let api_key = generate_api_key();
+
+        assert!(api_key.starts_with("z2p_"));
+        assert_ne!(api_key, generate_api_key());
+        assert_eq!(hash_api_key(&api_key), hash_api_key(&api_key));
+        assert_ne!(hash_api_key(&api_key), api_key);
+    }
+
+    #[test]
+    fn only_bearer_tokens_are_read_from_the_authorization_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer z2p_key"));
+        assert_eq!(bearer_token(&headers), Some("z2p_key"));
+
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Basic YWRtaW46cGFzcw=="),
+        );
+        assert_eq!(bearer_token(&headers), None);
+    }
+}
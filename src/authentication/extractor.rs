@@ -0,0 +1,229 @@
+use crate::authentication::{
+    logged_in_user_id, validate_credentials, AuthError, AuthenticatedApiKey, Credentials, Role,
+};
+use crate::session::Session;
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+use std::future::Future;
+use std::pin::Pin;
+use uuid::Uuid;
+
+// 認証済みの管理者
+// ハンドラの引数に加えると、認証できなかったリクエストはハンドラに届く前に401で拒否される
+// ブラウザからはログイン時のセッションで、APIクライアントからはBasic認証で認証する
+// RequirePermissionで認証済みの場合は、その結果を使い回す
+#[derive(Debug, Clone)]
+pub struct AuthenticatedAdmin {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: Role,
+}
+
+impl FromRequest for AuthenticatedAdmin {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(admin) = request.extensions().get::<AuthenticatedAdmin>().cloned() {
+            return Box::pin(async move { Ok(admin) });
+        }
+
+        let session = request.extensions().get::<Session>().cloned();
+        let credentials = basic_authentication(request.headers());
+        let pool = request.app_data::<web::Data<PgPool>>().cloned();
+
+        Box::pin(async move {
+            let pool = pool.ok_or_else(|| {
+                tracing::error!("The database pool is not registered as app data.");
+                InternalError::from_response("", HttpResponse::InternalServerError().finish())
+            })?;
+
+            if let Some(user_id) = session.and_then(|s| logged_in_user_id(&s).ok().flatten()) {
+                match get_admin(user_id, &pool).await {
+                    Ok(Some(admin)) => return Ok(admin),
+                    // ログイン後にユーザーが削除された場合は、ログインしていないものとして扱う
+                    Ok(None) => {}
+                    Err(_) => {
+                        return Err(InternalError::from_response(
+                            "",
+                            HttpResponse::InternalServerError().finish(),
+                        )
+                        .into())
+                    }
+                }
+            }
+
+            let credentials = credentials.map_err(unauthorized)?;
+
+            let user_id = match validate_credentials(credentials, &pool).await {
+                Ok(user_id) => user_id,
+                Err(AuthError::InvalidCredentials) => {
+                    return Err(unauthorized(AuthError::InvalidCredentials.to_string()))
+                }
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    return Err(InternalError::from_response(
+                        "",
+                        HttpResponse::InternalServerError().finish(),
+                    )
+                    .into());
+                }
+            };
+            match get_admin(user_id, &pool).await {
+                Ok(Some(admin)) => Ok(admin),
+                // 認証の直後にユーザーが削除された場合
+                Ok(None) => Err(unauthorized(AuthError::InvalidCredentials.to_string())),
+                Err(_) => Err(InternalError::from_response(
+                    "",
+                    HttpResponse::InternalServerError().finish(),
+                )
+                .into()),
+            }
+        })
+    }
+}
+
+// 管理者向けAPIの呼び出し元
+// APIキーでの認証はRequirePermissionで行うため、その内側のルートでだけ使う
+#[derive(Debug)]
+pub enum ApiCaller {
+    Admin(AuthenticatedAdmin),
+    ApiKey(AuthenticatedApiKey),
+}
+
+impl ApiCaller {
+    // 名前は変更されうるため、呼び出し元を区別するときはIDを使う
+    pub fn id(&self) -> String {
+        match self {
+            Self::Admin(admin) => format!("admin:{}", admin.user_id),
+            Self::ApiKey(api_key) => format!("api_key:{}", api_key.api_key_id),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiCaller {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Admin(admin) => write!(f, "admin:{}", admin.username),
+            Self::ApiKey(api_key) => write!(f, "api_key:{}", api_key.name),
+        }
+    }
+}
+
+impl FromRequest for ApiCaller {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if let Some(api_key) = request.extensions().get::<AuthenticatedApiKey>().cloned() {
+            return Box::pin(async move { Ok(ApiCaller::ApiKey(api_key)) });
+        }
+
+        let admin = AuthenticatedAdmin::from_request(request, payload);
+        Box::pin(async move { admin.await.map(ApiCaller::Admin) })
+    }
+}
+
+fn unauthorized(message: String) -> actix_web::Error {
+    let mut response = HttpResponse::Unauthorized().finish();
+    response.headers_mut().insert(
+        WWW_AUTHENTICATE,
+        HeaderValue::from_static(r#"Basic realm="admin""#),
+    );
+    InternalError::from_response(message, response).into()
+}
+
+#[tracing::instrument(name = "Get admin", skip(pool))]
+async fn get_admin(user_id: Uuid, pool: &PgPool) -> Result<Option<AuthenticatedAdmin>, String> {
+    let row = sqlx::query!(
+        r#"SELECT username, role FROM users WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e.to_string()
+    })?;
+
+    match row {
+        Some(r) => Ok(Some(AuthenticatedAdmin {
+            user_id,
+            username: r.username,
+            role: Role::parse(r.role).map_err(|e| {
+                tracing::error!("{}", e);
+                e
+            })?,
+        })),
+        None => Ok(None),
+    }
+}
+
+// Authorization: Basic <base64(username:password)>
+fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, String> {
+    let header_value = headers
+        .get(AUTHORIZATION)
+        .ok_or_else(|| "The 'Authorization' header is missing.".to_string())?
+        .to_str()
+        .map_err(|_| "The 'Authorization' header is not a valid string.".to_string())?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .ok_or_else(|| "The authorization scheme is not 'Basic'.".to_string())?;
+    let decoded_bytes = base64::decode(base64encoded_segment)
+        .map_err(|_| "Failed to base64-decode 'Basic' credentials.".to_string())?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .map_err(|_| "The decoded credentials are not valid UTF-8.".to_string())?;
+
+    // パスワードには':'が含まれることがあるため、最初の':'で分割する
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .ok_or_else(|| "A ':' must separate the username and password.".to_string())?;
+
+    Ok(Credentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::basic_authentication;
+    use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+    fn headers_with(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    // Credentialsはパスワードを含むためDebugを実装しておらず、assert_err!は使えない
+    #[test]
+    fn basic_credentials_are_parsed() {
+        let value = format!("Basic {}", base64::encode("admin:pass:word"));
+
+        let credentials = basic_authentication(&headers_with(&value)).unwrap();
+
+        assert_eq!(credentials.username, "admin");
+        assert_eq!(credentials.password, "pass:word");
+    }
+
+    #[test]
+    fn a_missing_header_is_rejected() {
+        assert!(basic_authentication(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn other_schemes_are_rejected() {
+        assert!(basic_authentication(&headers_with("Bearer token")).is_err());
+    }
+
+    #[test]
+    fn credentials_without_a_separator_are_rejected() {
+        let value = format!("Basic {}", base64::encode("admin"));
+
+        assert!(basic_authentication(&headers_with(&value)).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::authentication::{
+    authenticate_api_key, bearer_token, logged_in_user_id, AuthenticatedAdmin, AuthenticatedApiKey,
+    Permission,
+};
+use crate::session::Session;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderValue, LOCATION, WWW_AUTHENTICATE};
+use actix_web::{web, FromRequest, HttpMessage, HttpResponse};
+use sqlx::PgPool;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+// ログインしていないブラウザからの管理画面へのアクセスを、ログインページへリダイレクトする
+// SessionMiddlewareの内側で使う
+pub struct RequireLogin;
+
+impl<S, B> Transform<S, ServiceRequest> for RequireLogin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequireLoginService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireLoginService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequireLoginService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireLoginService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let session = request.extensions().get::<Session>().cloned();
+            let user_id = session.map(|session| logged_in_user_id(&session));
+
+            match user_id {
+                Some(Ok(Some(_))) => service
+                    .call(request)
+                    .await
+                    .map(ServiceResponse::map_into_left_body),
+                _ => {
+                    let response = HttpResponse::SeeOther()
+                        .insert_header((LOCATION, "/login"))
+                        .finish();
+                    Ok(request.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+// 管理者の役割が、ルートに宣言された権限を持たない場合は403を返す
+// 権限が宣言されていないルートは、誰にも許可しない
+pub struct RequirePermission(pub Option<Permission>);
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequirePermissionService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionService {
+            service: Rc::new(service),
+            permission: self.0,
+        }))
+    }
+}
+
+pub struct RequirePermissionService<S> {
+    service: Rc<S>,
+    permission: Option<Permission>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let mut request = request;
+
+            // Bearer認証の場合はAPIキーのスコープで、それ以外は管理者の役割で判定する
+            let allowed = match bearer_token(request.headers()).map(str::to_owned) {
+                Some(api_key) => {
+                    let api_key = match authenticate_bearer(&request, &api_key).await {
+                        Ok(api_key) => api_key,
+                        Err(e) => return Ok(request.error_response(e).map_into_right_body()),
+                    };
+                    let allowed = permission.is_some_and(|p| api_key.has_permission(p));
+                    if !allowed {
+                        tracing::warn!(
+                            api_key = %api_key.name,
+                            "The API key does not have a scope for {:?}.",
+                            permission
+                        );
+                    }
+                    // ハンドラのApiCallerは、ここでの認証結果を使う
+                    request.extensions_mut().insert(api_key);
+                    allowed
+                }
+                None => {
+                    let (http_request, payload) = request.parts_mut();
+                    let admin = match AuthenticatedAdmin::from_request(http_request, payload).await
+                    {
+                        Ok(admin) => admin,
+                        Err(e) => return Ok(request.error_response(e).map_into_right_body()),
+                    };
+                    let allowed = permission.is_some_and(|p| admin.role.has_permission(p));
+                    if !allowed {
+                        tracing::warn!(
+                            username = %admin.username,
+                            role = %admin.role.as_str(),
+                            "The admin does not have the {:?} permission.",
+                            permission
+                        );
+                    }
+                    // ハンドラのAuthenticatedAdminは、ここでの認証結果を使う
+                    request.extensions_mut().insert(admin);
+                    allowed
+                }
+            };
+
+            if permission.is_none() {
+                tracing::error!("No permission is declared for {}.", request.path());
+            }
+            if !allowed {
+                let response = HttpResponse::Forbidden().finish();
+                return Ok(request.into_response(response).map_into_right_body());
+            }
+
+            service
+                .call(request)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+// 無効なキーや失効したキーは401で拒否する
+async fn authenticate_bearer(
+    request: &ServiceRequest,
+    api_key: &str,
+) -> Result<AuthenticatedApiKey, actix_web::Error> {
+    let pool = request
+        .app_data::<web::Data<PgPool>>()
+        .cloned()
+        .ok_or_else(|| {
+            tracing::error!("The database pool is not registered as app data.");
+            actix_web::error::ErrorInternalServerError("")
+        })?;
+    // Forwarded/X-Forwarded-Forヘッダはクライアントが自由に書けるため、接続元のアドレスを記録する
+    let client_ip = request
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    match authenticate_api_key(&pool, api_key, &client_ip).await {
+        Ok(Some(api_key)) => Ok(api_key),
+        Ok(None) => {
+            let mut response = HttpResponse::Unauthorized().finish();
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            Err(InternalError::from_response("Invalid API key.", response).into())
+        }
+        Err(_) => Err(actix_web::error::ErrorInternalServerError("")),
+    }
+}
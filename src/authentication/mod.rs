@@ -0,0 +1,19 @@
+mod api_key;
+mod extractor;
+mod middleware;
+mod password;
+mod role;
+mod session;
+
+pub use api_key::{
+    authenticate_api_key, bearer_token, get_api_keys, mark_api_key_as_revoked, store_api_key,
+    ApiKeySummary, ApiScope, AuthenticatedApiKey,
+};
+pub use extractor::{ApiCaller, AuthenticatedAdmin};
+pub use middleware::{RequireLogin, RequirePermission};
+pub use password::{
+    compute_password_hash, create_user, ensure_initial_admin, validate_credentials, AuthError,
+    Credentials,
+};
+pub use role::{Permission, Role};
+pub use session::{log_in, log_out, logged_in_user_id};
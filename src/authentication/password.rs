@@ -0,0 +1,232 @@
+use crate::authentication::Role;
+use crate::configuration::InitialAdminSettings;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// 存在しないユーザー名でも、存在する場合と同じ計算をさせるためのハッシュ
+// パラメータはcompute_password_hashと揃えておく
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=15000,t=2,p=1$\
+gZiV/M1gPc22ElAH/Jh1Hw$\
+CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno";
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials,
+    Unexpected(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidCredentials => write!(f, "Invalid credentials."),
+            Self::Unexpected(e) => write!(f, "Failed to validate credentials: {}", e),
+        }
+    }
+}
+
+// ユーザーが存在しない場合もダミーのハッシュで検証し、応答時間からユーザー名の有無が分からないようにする
+#[tracing::instrument(
+    name = "Validate credentials",
+    skip(credentials, pool),
+    fields(username = %credentials.username)
+)]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = DUMMY_PASSWORD_HASH.to_string();
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool)
+            .await
+            .map_err(|e| AuthError::Unexpected(e.to_string()))?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(&expected_password_hash, &credentials.password)
+    })
+    .await??;
+
+    user_id.ok_or(AuthError::InvalidCredentials)
+}
+
+// Argon2idでハッシュ化し、パラメータとソルトを含むPHC文字列で返す
+pub fn compute_password_hash(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).map_err(|e| AuthError::Unexpected(e.to_string()))?,
+    )
+    .hash_password(password.as_bytes(), &salt)
+    .map_err(|e| AuthError::Unexpected(e.to_string()))?
+    .to_string();
+
+    Ok(password_hash)
+}
+
+#[tracing::instrument(
+    name = "Create a user",
+    skip(credentials, pool),
+    fields(username = %credentials.username, role = %role.as_str())
+)]
+pub async fn create_user(
+    credentials: Credentials,
+    role: Role,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let user_id = Uuid::new_v4();
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(&credentials.password)).await??;
+
+    sqlx::query!(
+        r#"INSERT INTO users (user_id, username, password_hash, role, created_at)
+        VALUES ($1, $2, $3, $4, $5)"#,
+        user_id,
+        credentials.username,
+        password_hash,
+        role.as_str(),
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        AuthError::Unexpected(e.to_string())
+    })?;
+
+    Ok(user_id)
+}
+
+// ユーザーが一人もいない場合に限り、設定された管理者をownerとして作成する
+// 作成した場合はtrueを返す
+pub async fn ensure_initial_admin(
+    pool: &PgPool,
+    initial_admin: &Option<InitialAdminSettings>,
+) -> Result<bool, AuthError> {
+    let initial_admin = match initial_admin {
+        Some(initial_admin) => initial_admin,
+        None => return Ok(false),
+    };
+
+    let r = sqlx::query!(r#"SELECT EXISTS(SELECT 1 FROM users) AS "exists!""#)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            AuthError::Unexpected(e.to_string())
+        })?;
+    if r.exists {
+        return Ok(false);
+    }
+
+    create_user(
+        Credentials {
+            username: initial_admin.username.clone(),
+            password: initial_admin.password.clone(),
+        },
+        Role::Owner,
+        pool,
+    )
+    .await?;
+
+    Ok(true)
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .map(|row| (row.user_id, row.password_hash));
+
+    Ok(row)
+}
+
+// ハッシュの比較はargon2クレートの中で定数時間で行われる
+#[tracing::instrument(name = "Verify password hash", skip_all)]
+fn verify_password_hash(expected_password_hash: &str, password: &str) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash)
+        .map_err(|e| AuthError::Unexpected(e.to_string()))?;
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &expected_password_hash)
+        .map_err(|_| AuthError::InvalidCredentials)
+}
+
+// ハッシュの計算はCPUを占有するため、非同期のワーカースレッドを塞がないよう別スレッドで行う
+async fn spawn_blocking_with_tracing<F, R>(f: F) -> Result<R, AuthError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+        .await
+        .map_err(|e| AuthError::Unexpected(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_password_hash, verify_password_hash, AuthError, DUMMY_PASSWORD_HASH};
+    use argon2::PasswordHash;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn a_hashed_password_can_be_verified() {
+        let password_hash = compute_password_hash("correct horse").unwrap();
+
+        assert!(password_hash.starts_with("$argon2id$"));
+        assert_ok!(verify_password_hash(&password_hash, "correct horse"));
+    }
+
+    #[test]
+    fn a_wrong_password_is_rejected() {
+        let password_hash = compute_password_hash("correct horse").unwrap();
+
+        assert!(matches!(
+            verify_password_hash(&password_hash, "battery staple"),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn the_same_password_is_hashed_with_different_salts() {
+        assert_ne!(
+            compute_password_hash("correct horse").unwrap(),
+            compute_password_hash("correct horse").unwrap()
+        );
+    }
+
+    #[test]
+    fn the_dummy_hash_uses_the_same_parameters_as_real_hashes() {
+        let password_hash = compute_password_hash("correct horse").unwrap();
+        let real = PasswordHash::new(&password_hash).unwrap();
+        let dummy = PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap();
+
+        assert_eq!(real.algorithm, dummy.algorithm);
+        assert_eq!(real.params, dummy.params);
+        assert_err!(verify_password_hash(DUMMY_PASSWORD_HASH, "correct horse"));
+    }
+}
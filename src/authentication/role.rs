@@ -0,0 +1,111 @@
+// 管理者の役割
+// 後に宣言した役割ほど強く、前の役割の権限をすべて含む
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Editor,
+    Publisher,
+    Owner,
+}
+
+impl Role {
+    pub fn parse(s: String) -> Result<Role, String> {
+        match s.as_str() {
+            "viewer" => Ok(Self::Viewer),
+            "editor" => Ok(Self::Editor),
+            "publisher" => Ok(Self::Publisher),
+            "owner" => Ok(Self::Owner),
+            _ => Err(format!("{} is not a valid role.", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Editor => "editor",
+            Self::Publisher => "publisher",
+            Self::Owner => "owner",
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        *self >= permission.minimum_role()
+    }
+}
+
+// ルートごとに要求する権限
+// 権限はstartup::runでルートと一緒に宣言する
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    ReadSubscribers,
+    WriteSubscribers,
+    DeleteSubscribers,
+    DraftIssues,
+    PublishIssues,
+    ManageApiKeys,
+}
+
+impl Permission {
+    fn minimum_role(&self) -> Role {
+        match self {
+            Self::ReadSubscribers => Role::Viewer,
+            Self::DraftIssues => Role::Editor,
+            Self::WriteSubscribers | Self::PublishIssues => Role::Publisher,
+            Self::DeleteSubscribers | Self::ManageApiKeys => Role::Owner,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Permission::*;
+    use super::Role::{self, *};
+    use claim::assert_err;
+
+    #[test]
+    fn roles_round_trip() {
+        for role in [Viewer, Editor, Publisher, Owner] {
+            assert_eq!(Role::parse(role.as_str().to_string()), Ok(role));
+        }
+    }
+
+    #[test]
+    fn an_unknown_role_is_rejected() {
+        assert_err!(Role::parse("admin".to_string()));
+    }
+
+    #[test]
+    fn a_viewer_can_only_read() {
+        assert!(Viewer.has_permission(ReadSubscribers));
+        assert!(!Viewer.has_permission(DraftIssues));
+        assert!(!Viewer.has_permission(PublishIssues));
+    }
+
+    #[test]
+    fn an_editor_can_draft_but_not_publish() {
+        assert!(Editor.has_permission(DraftIssues));
+        assert!(!Editor.has_permission(PublishIssues));
+    }
+
+    #[test]
+    fn only_an_owner_can_delete_subscribers_and_manage_api_keys() {
+        for permission in [DeleteSubscribers, ManageApiKeys] {
+            assert!(!Publisher.has_permission(permission));
+            assert!(Owner.has_permission(permission));
+        }
+    }
+
+    #[test]
+    fn an_owner_has_every_permission() {
+        for permission in [
+            ReadSubscribers,
+            WriteSubscribers,
+            DeleteSubscribers,
+            DraftIssues,
+            PublishIssues,
+            ManageApiKeys,
+        ] {
+            assert!(Owner.has_permission(permission));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use crate::session::Session;
+use uuid::Uuid;
+
+const USER_ID_KEY: &str = "user_id";
+
+// ログイン前のセッションIDを使い回させないよう、IDを新しくしてから記録する
+pub fn log_in(session: &Session, user_id: Uuid) -> Result<(), serde_json::Error> {
+    session.renew();
+    session.insert(USER_ID_KEY, user_id)
+}
+
+pub fn log_out(session: &Session) {
+    session.purge();
+}
+
+pub fn logged_in_user_id(session: &Session) -> Result<Option<Uuid>, serde_json::Error> {
+    session.get(USER_ID_KEY)
+}
@@ -1,5 +1,22 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    CircuitBreaker, EmailClient, EmailSender, FileEmailClient, InMemoryEmailClient, RetryPolicy,
+    SmtpEmailClient,
+};
+use crate::housekeeping::HousekeepingJob;
+use crate::pending_subscribers::PendingSubscriberJob;
+use crate::routes::{ConfirmationRedirects, PreferencesLinkSigner};
+use crate::session::{InMemorySessionStore, PostgresSessionStore, SessionMiddleware, SessionStore};
+use crate::subscription_tokens::SubscriptionTokenHasher;
+use actix_web::cookie::Key;
+use hkdf::Hkdf;
 use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+// 導出する鍵の強さがhmac_secretの推測しにくさで決まるため、短すぎる値は受け付けない
+const MIN_HMAC_SECRET_LENGTH: usize = 32;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
@@ -8,6 +25,22 @@ pub struct Settings {
     pub email_client: EmailClientSettings,
 }
 
+impl Settings {
+    // 起動後に初めて失敗する(panicする)ことがないよう、読み込み時に値を確認する
+    pub fn validate(&self) -> Result<(), String> {
+        if self.email_client.batch_size == 0 {
+            return Err("email_client.batch_size must be greater than 0.".into());
+        }
+        if self.application.hmac_secret.len() < MIN_HMAC_SECRET_LENGTH {
+            return Err(format!(
+                "application.hmac_secret must be at least {} bytes long.",
+                MIN_HMAC_SECRET_LENGTH
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct DatabaseSettings {
     pub username: String,
@@ -22,17 +55,285 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    // メールテンプレート(`{name}.html`と`{name}.txt`)を置くディレクトリ
+    pub templates_directory: String,
+    // メール内のリンクへの署名と、確認トークンのハッシュ化に使う鍵の元になる秘密鍵
+    // そのまま使わず、用途ごとにderive_keyで導出した鍵を使う
+    pub hmac_secret: String,
+    pub preferences_link_ttl_hours: i64,
+    pub subscription_token_ttl_hours: i64,
+    // 確認リンクへのGETだけで購読を確認する、以前の動作に戻す場合はtrueにする
+    // メールサーバのリンクスキャナによって、本人がクリックしていなくても確認されてしまう
+    pub confirm_on_get: bool,
+    // 確認後にリダイレクトする先(未設定の場合はアプリケーションでページを表示する)
+    // 結果は`outcome`クエリパラメータで渡す
+    pub confirm_success_url: Option<String>,
+    pub confirm_failure_url: Option<String>,
+    pub resend_confirmation: ResendConfirmationSettings,
+    pub pending_subscribers: PendingSubscriberSettings,
+    // ユーザーが一人もいない状態で起動したときに作成する管理者
+    pub initial_admin: Option<InitialAdminSettings>,
+    pub session: SessionSettings,
+}
+
+// 管理画面のログインセッション
+#[derive(Deserialize, Clone)]
+pub struct SessionSettings {
+    pub store: SessionStoreKind,
+    pub ttl_minutes: i64,
+    // HTTPSでのみCookieを送らせる(本番環境ではtrueにする)
+    pub cookie_secure: bool,
+}
+
+// セッションの保存先
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStoreKind {
+    Postgres,
+    InMemory,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct InitialAdminSettings {
+    pub username: String,
+    pub password: String,
+}
+
+// 確認されないままのsubscriberに、いつリマインダーを送り、いつ削除するか
+#[derive(Deserialize, Clone)]
+pub struct PendingSubscriberSettings {
+    pub reminder_delay_hours: i64,
+    pub retention_days: i64,
+}
+
+// 確認メールを送るリクエスト(登録と再送)を、同じアドレス・同じIPアドレスから一定時間に何回まで受け付けるか
+#[derive(Deserialize, Clone)]
+pub struct ResendConfirmationSettings {
+    pub max_attempts_per_address: i64,
+    pub max_attempts_per_ip: i64,
+    pub window_minutes: i64,
+}
+
+// hmac_secretから導出する鍵の用途
+// 用途ごとに別の鍵を使い、ある用途の鍵や出力が他の用途に流用されないようにする
+#[derive(Debug, Clone, Copy)]
+pub enum KeyPurpose {
+    SubscriptionTokens,
+    PreferencesLinks,
+    SessionCookies,
+}
+
+impl KeyPurpose {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::SubscriptionTokens => "zero2prod/subscription-tokens",
+            Self::PreferencesLinks => "zero2prod/preferences-links",
+            Self::SessionCookies => "zero2prod/session-cookies",
+        }
+    }
+
+    // Cookieの鍵は署名用と暗号化用に分けて使われるため、Key::fromが64バイトを要求する
+    fn key_length(&self) -> usize {
+        match self {
+            Self::SessionCookies => 64,
+            Self::SubscriptionTokens | Self::PreferencesLinks => 32,
+        }
+    }
+}
+
+impl ApplicationSettings {
+    // HKDF-SHA256のinfoに用途のラベルを渡して導出する
+    pub fn derive_key(&self, purpose: KeyPurpose) -> Vec<u8> {
+        let mut key = vec![0; purpose.key_length()];
+        Hkdf::<Sha256>::new(None, self.hmac_secret.as_bytes())
+            .expand(purpose.label().as_bytes(), &mut key)
+            .expect("Key lengths up to 255 * 32 bytes are valid for HKDF-SHA256");
+        key
+    }
+
+    pub fn preferences_link_signer(&self) -> PreferencesLinkSigner {
+        PreferencesLinkSigner::new(
+            self.derive_key(KeyPurpose::PreferencesLinks),
+            chrono::Duration::hours(self.preferences_link_ttl_hours),
+        )
+    }
+
+    pub fn confirmation_redirects(&self) -> Result<ConfirmationRedirects, String> {
+        let parse = |url: &Option<String>| {
+            url.as_deref()
+                .map(|url| {
+                    reqwest::Url::parse(url)
+                        .map_err(|e| format!("{} is not a valid URL: {}", url, e))
+                })
+                .transpose()
+        };
+        Ok(ConfirmationRedirects {
+            success_url: parse(&self.confirm_success_url)?,
+            failure_url: parse(&self.confirm_failure_url)?,
+        })
+    }
+
+    pub fn session_middleware(&self, pool: PgPool) -> SessionMiddleware {
+        let store: Arc<dyn SessionStore> = match self.session.store {
+            SessionStoreKind::Postgres => Arc::new(PostgresSessionStore::new(pool)),
+            SessionStoreKind::InMemory => Arc::new(InMemorySessionStore::new()),
+        };
+        SessionMiddleware::new(
+            store,
+            Key::from(&self.derive_key(KeyPurpose::SessionCookies)),
+            chrono::Duration::minutes(self.session.ttl_minutes),
+            self.session.cookie_secure,
+        )
+    }
+
+    pub fn subscription_token_hasher(&self) -> SubscriptionTokenHasher {
+        SubscriptionTokenHasher::new(self.derive_key(KeyPurpose::SubscriptionTokens))
+    }
+
+    pub fn pending_subscriber_job(&self) -> PendingSubscriberJob {
+        PendingSubscriberJob {
+            reminder_delay: chrono::Duration::hours(self.pending_subscribers.reminder_delay_hours),
+            retention: chrono::Duration::days(self.pending_subscribers.retention_days),
+            base_url: self.base_url.clone(),
+            token_ttl: chrono::Duration::hours(self.subscription_token_ttl_hours),
+            token_hasher: self.subscription_token_hasher(),
+        }
+    }
+
+    pub fn housekeeping_job(&self) -> HousekeepingJob {
+        HousekeepingJob {
+            resend_window: chrono::Duration::minutes(self.resend_confirmation.window_minutes),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct EmailClientSettings {
+    pub provider: EmailProvider,
     pub base_url: String,
     pub sender_email: String,
     pub api_key: String,
     pub timeout_milliseconds: u64,
+    // 一括送信APIで1リクエストに含めるメッセージの最大数(配信ワーカーが一度に確保するタスクの数)
+    pub batch_size: usize,
+    pub retry: RetrySettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub smtp: Option<SmtpSettings>,
+    pub file: Option<FileSettings>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RetrySettings {
+    pub max_retries: u32,
+    pub base_delay_milliseconds: u64,
+    pub max_delay_milliseconds: u64,
+}
+
+impl RetrySettings {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            base_delay: std::time::Duration::from_millis(self.base_delay_milliseconds),
+            max_delay: std::time::Duration::from_millis(self.max_delay_milliseconds),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    pub failure_threshold: u32,
+    pub cooldown_milliseconds: u64,
+}
+
+impl CircuitBreakerSettings {
+    pub fn circuit_breaker(&self) -> CircuitBreaker {
+        CircuitBreaker::new(
+            self.failure_threshold,
+            std::time::Duration::from_millis(self.cooldown_milliseconds),
+        )
+    }
+}
+
+// メールの送信方法
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailProvider {
+    Http,
+    Smtp,
+    File,
+    InMemory,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // 指定しない場合は、サーバが対応しているPLAINまたはLOGINを使う
+    pub auth_mechanism: Option<SmtpAuthMechanism>,
+    pub pool_max_size: u32,
+}
+
+// SMTPサーバとの接続の暗号化方式
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    // 暗号化しない (ローカルのSMTPサーバ向け)
+    None,
+    // 平文で接続した後、STARTTLSで暗号化する
+    Starttls,
+    // 接続時からTLSで暗号化する
+    Implicit,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpAuthMechanism {
+    Plain,
+    Login,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FileSettings {
+    pub directory: String,
 }
 
 impl EmailClientSettings {
+    pub fn client(self) -> Arc<dyn EmailSender> {
+        let sender_email = self.sender().expect("Invalid sender email address");
+        let timeout = self.timeout();
+
+        match self.provider {
+            EmailProvider::Http => Arc::new(EmailClient::new(
+                self.base_url,
+                sender_email,
+                self.api_key,
+                timeout,
+                self.retry.policy(),
+                self.circuit_breaker.circuit_breaker(),
+                self.batch_size,
+            )),
+            EmailProvider::Smtp => {
+                let smtp = self
+                    .smtp
+                    .expect("Missing email_client.smtp settings for the smtp provider");
+                Arc::new(
+                    SmtpEmailClient::new(&smtp, sender_email, timeout)
+                        .expect("Invalid SMTP settings"),
+                )
+            }
+            EmailProvider::File => {
+                let file = self
+                    .file
+                    .expect("Missing email_client.file settings for the file provider");
+                Arc::new(FileEmailClient::new(file.directory.into(), sender_email))
+            }
+            EmailProvider::InMemory => Arc::new(InMemoryEmailClient::new()),
+        }
+    }
+
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
@@ -97,7 +398,9 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         .build()?;
 
     // Rustの構造体型にデシリアライズする
-    settings.try_deserialize()
+    let settings: Settings = settings.try_deserialize()?;
+    settings.validate().map_err(config::ConfigError::Message)?;
+    Ok(settings)
 }
 
 impl DatabaseSettings {
@@ -115,3 +418,64 @@ impl DatabaseSettings {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyPurpose, Settings};
+    use claim::{assert_err, assert_ok};
+
+    fn settings_with(key: &str, value: &str) -> Settings {
+        config::Config::builder()
+            .add_source(config::File::with_name("configuration/base"))
+            .add_source(config::File::with_name("configuration/local"))
+            .set_override(key, value)
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn the_default_settings_are_valid() {
+        assert_ok!(settings_with("email_client.batch_size", "500").validate());
+    }
+
+    #[test]
+    fn a_batch_size_of_zero_is_rejected() {
+        assert_err!(settings_with("email_client.batch_size", "0").validate());
+    }
+
+    #[test]
+    fn a_short_hmac_secret_is_rejected() {
+        assert_err!(settings_with("application.hmac_secret", "too-short-secret").validate());
+    }
+
+    #[test]
+    fn the_session_cookie_key_is_long_enough_for_signing_and_encryption() {
+        let settings = settings_with("email_client.batch_size", "500").application;
+
+        let cookie_key = settings.derive_key(KeyPurpose::SessionCookies);
+
+        assert_eq!(cookie_key.len(), 64);
+        assert_ne!(
+            &cookie_key[..32],
+            settings.derive_key(KeyPurpose::SubscriptionTokens)
+        );
+    }
+
+    #[test]
+    fn each_purpose_gets_a_different_key() {
+        let settings = settings_with("email_client.batch_size", "500").application;
+
+        let token_key = settings.derive_key(KeyPurpose::SubscriptionTokens);
+        let link_key = settings.derive_key(KeyPurpose::PreferencesLinks);
+
+        assert_ne!(token_key, link_key);
+        assert_ne!(token_key, settings.hmac_secret.as_bytes());
+        assert_eq!(
+            token_key,
+            settings.derive_key(KeyPurpose::SubscriptionTokens)
+        );
+    }
+}
@@ -1,7 +1,11 @@
 mod new_subscriber;
 mod subscriber_email;
 mod subscriber_name;
+mod subscriber_preferences;
+mod subscription_status;
 
 pub use new_subscriber::NewSubscriber;
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
+pub use subscriber_preferences::{DeliveryMode, SubscriberPreferences, Topic, TOPICS};
+pub use subscription_status::SubscriptionStatus;
@@ -0,0 +1,89 @@
+// 購読者が選択できるトピック
+pub const TOPICS: &[&str] = &["announcements", "articles", "events"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topic(String);
+
+impl Topic {
+    pub fn parse(s: String) -> Result<Topic, String> {
+        if TOPICS.contains(&s.as_str()) {
+            Ok(Self(s))
+        } else {
+            Err(format!("{} is not a valid topic.", s))
+        }
+    }
+}
+
+impl AsRef<str> for Topic {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+// ニュースレターを発行のたびに受け取るか、まとめて受け取るか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryMode {
+    Instant,
+    Digest,
+}
+
+impl DeliveryMode {
+    pub fn parse(s: String) -> Result<DeliveryMode, String> {
+        match s.as_str() {
+            "instant" => Ok(Self::Instant),
+            "digest" => Ok(Self::Digest),
+            _ => Err(format!("{} is not a valid delivery mode.", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Instant => "instant",
+            Self::Digest => "digest",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriberPreferences {
+    pub topics: Vec<Topic>,
+    pub delivery_mode: DeliveryMode,
+}
+
+impl Default for SubscriberPreferences {
+    // 設定を変更していない購読者は、すべてのトピックを都度受け取る
+    fn default() -> Self {
+        Self {
+            topics: TOPICS.iter().map(|t| Topic(t.to_string())).collect(),
+            delivery_mode: DeliveryMode::Instant,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{DeliveryMode, Topic};
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn a_known_topic_is_valid() {
+        assert_ok!(Topic::parse("articles".to_string()));
+    }
+
+    #[test]
+    fn an_unknown_topic_is_rejected() {
+        assert_err!(Topic::parse("gossip".to_string()));
+    }
+
+    #[test]
+    fn delivery_modes_round_trip() {
+        for mode in [DeliveryMode::Instant, DeliveryMode::Digest] {
+            assert_eq!(DeliveryMode::parse(mode.as_str().to_string()), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn an_unknown_delivery_mode_is_rejected() {
+        assert_err!(DeliveryMode::parse("weekly".to_string()));
+    }
+}
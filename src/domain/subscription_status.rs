@@ -0,0 +1,149 @@
+// 購読の状態
+// 状態の遷移はtransition_toでのみ判定し、許可されていない遷移はDBに書き込まない
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Complained,
+    Deleted,
+}
+
+impl SubscriptionStatus {
+    pub const ALL: [SubscriptionStatus; 6] = [
+        Self::PendingConfirmation,
+        Self::Confirmed,
+        Self::Unsubscribed,
+        Self::Bounced,
+        Self::Complained,
+        Self::Deleted,
+    ];
+
+    pub fn parse(s: String) -> Result<SubscriptionStatus, String> {
+        match s.as_str() {
+            "pending_confirmation" => Ok(Self::PendingConfirmation),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "bounced" => Ok(Self::Bounced),
+            "complained" => Ok(Self::Complained),
+            "deleted" => Ok(Self::Deleted),
+            _ => Err(format!("{} is not a valid subscription status.", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PendingConfirmation => "pending_confirmation",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Bounced => "bounced",
+            Self::Complained => "complained",
+            Self::Deleted => "deleted",
+        }
+    }
+
+    // 確認済みになれるのは確認待ちの場合だけ
+    // そのため、購読解除した人が古い確認リンクを踏んでも購読は再開されない
+    // 苦情を受けたアドレスへは、再登録があっても確認メールすら送らない
+    // 確認済みでもメールアドレスを変更した場合は、新しいアドレスを確認するまで確認待ちに戻る
+    // 削除済みの行は同じトランザクションで行ごと消すため、削除済みから先への遷移はない
+    pub fn can_transition_to(&self, next: SubscriptionStatus) -> bool {
+        use SubscriptionStatus::*;
+
+        matches!(
+            (self, next),
+            (PendingConfirmation, Confirmed)
+                | (Confirmed | Unsubscribed | Bounced, PendingConfirmation)
+                | (
+                    PendingConfirmation | Confirmed | Bounced | Complained,
+                    Unsubscribed
+                )
+                | (PendingConfirmation | Confirmed, Bounced | Complained)
+                | (
+                    PendingConfirmation | Confirmed | Unsubscribed | Bounced | Complained,
+                    Deleted
+                )
+        )
+    }
+
+    pub fn transition_to(&self, next: SubscriptionStatus) -> Result<SubscriptionStatus, String> {
+        if self.can_transition_to(next) {
+            Ok(next)
+        } else {
+            Err(format!(
+                "A subscription cannot move from {} to {}.",
+                self.as_str(),
+                next.as_str()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriptionStatus;
+    use crate::domain::SubscriptionStatus::*;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn statuses_round_trip() {
+        for status in SubscriptionStatus::ALL {
+            assert_eq!(
+                SubscriptionStatus::parse(status.as_str().to_string()),
+                Ok(status)
+            );
+        }
+    }
+
+    #[test]
+    fn an_unknown_status_is_rejected() {
+        assert_err!(SubscriptionStatus::parse("active".to_string()));
+    }
+
+    #[test]
+    fn only_a_pending_subscription_can_be_confirmed() {
+        assert_ok!(PendingConfirmation.transition_to(Confirmed));
+        for status in [Confirmed, Unsubscribed, Bounced, Complained, Deleted] {
+            assert_err!(status.transition_to(Confirmed));
+        }
+    }
+
+    #[test]
+    fn an_unsubscribed_address_can_sign_up_again() {
+        assert_ok!(Unsubscribed.transition_to(PendingConfirmation));
+    }
+
+    #[test]
+    fn a_confirmed_subscription_goes_back_to_pending_when_its_address_changes() {
+        assert_ok!(Confirmed.transition_to(PendingConfirmation));
+    }
+
+    #[test]
+    fn a_complained_address_cannot_sign_up_again() {
+        assert_err!(Complained.transition_to(PendingConfirmation));
+    }
+
+    #[test]
+    fn any_subscription_can_be_deleted() {
+        for status in SubscriptionStatus::ALL {
+            if status != Deleted {
+                assert_ok!(status.transition_to(Deleted));
+            }
+        }
+    }
+
+    #[test]
+    fn a_deleted_subscription_is_final() {
+        for status in SubscriptionStatus::ALL {
+            assert_err!(Deleted.transition_to(status));
+        }
+    }
+
+    #[test]
+    fn a_status_does_not_transition_to_itself() {
+        for status in SubscriptionStatus::ALL {
+            assert_err!(status.transition_to(status));
+        }
+    }
+}
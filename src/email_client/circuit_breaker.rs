@@ -0,0 +1,196 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    // 通常どおりリクエストを送る
+    Closed,
+    // プロバイダが停止しているとみなし、リクエストを送らずに失敗させる
+    Open,
+    // 待機時間が過ぎたため、試しにリクエストを一つだけ送る
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // HalfOpenで送った試しのリクエストの開始時刻。結果が出るまで他のリクエストは送らない
+    trial_started_at: Option<Instant>,
+}
+
+// 連続してfailure_threshold回失敗するとOpenになり、cooldownの間はリクエストを送らない
+// クローンしたクライアント間で状態を共有する
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            inner: Arc::new(Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                trial_started_at: None,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub fn allow_request(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            // 試しのリクエストの結果が記録されないまま(送信元のFutureが破棄されたなど)
+            // cooldownが過ぎた場合は、次のリクエストを新しい試しとして送る
+            CircuitState::HalfOpen => {
+                let trial_abandoned = inner
+                    .trial_started_at
+                    .map(|started_at| started_at.elapsed() >= self.cooldown)
+                    .unwrap_or(true);
+                if trial_abandoned {
+                    inner.trial_started_at = Some(Instant::now());
+                }
+                trial_abandoned
+            }
+            CircuitState::Open => {
+                let cooled_down = inner
+                    .opened_at
+                    .map(|opened_at| opened_at.elapsed() >= self.cooldown)
+                    .unwrap_or(true);
+                if cooled_down {
+                    inner.trial_started_at = Some(Instant::now());
+                    transition(&mut inner, CircuitState::HalfOpen);
+                }
+                cooled_down
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.trial_started_at = None;
+        if inner.state != CircuitState::Closed {
+            transition(&mut inner, CircuitState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.trial_started_at = None;
+        if inner.state == CircuitState::HalfOpen
+            || (inner.state == CircuitState::Closed
+                && inner.consecutive_failures >= self.failure_threshold)
+        {
+            inner.opened_at = Some(Instant::now());
+            transition(&mut inner, CircuitState::Open);
+        }
+    }
+}
+
+fn transition(inner: &mut Inner, state: CircuitState) {
+    tracing::warn!(
+        circuit_breaker.previous_state = inner.state.as_str(),
+        circuit_breaker.state = state.as_str(),
+        circuit_breaker.consecutive_failures = inner.consecutive_failures,
+        "Email provider circuit breaker changed state"
+    );
+    inner.state = state;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    #[test]
+    fn the_circuit_opens_after_consecutive_failures() {
+        let circuit_breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        circuit_breaker.record_failure();
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+        circuit_breaker.record_failure();
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+        assert!(!circuit_breaker.allow_request());
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let circuit_breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        circuit_breaker.record_failure();
+        circuit_breaker.record_success();
+        circuit_breaker.record_failure();
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn the_circuit_half_opens_after_the_cooldown() {
+        let circuit_breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        circuit_breaker.record_failure();
+        assert!(circuit_breaker.allow_request());
+        assert_eq!(circuit_breaker.state(), CircuitState::HalfOpen);
+
+        // 試しに送ったリクエストが失敗した場合は再びOpenになる
+        circuit_breaker.record_failure();
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+
+        assert!(circuit_breaker.allow_request());
+        circuit_breaker.record_success();
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn only_one_trial_request_is_sent_while_half_open() {
+        let circuit_breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+
+        circuit_breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(circuit_breaker.allow_request());
+        assert_eq!(circuit_breaker.state(), CircuitState::HalfOpen);
+
+        // 試しのリクエストの結果が出るまでは、他のリクエストを送らない
+        assert!(!circuit_breaker.allow_request());
+        assert!(!circuit_breaker.allow_request());
+
+        circuit_breaker.record_success();
+        assert!(circuit_breaker.allow_request());
+        assert!(circuit_breaker.allow_request());
+    }
+
+    #[test]
+    fn an_abandoned_trial_request_is_replaced_after_the_cooldown() {
+        let circuit_breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+
+        circuit_breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(circuit_breaker.allow_request());
+        assert!(!circuit_breaker.allow_request());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(circuit_breaker.allow_request());
+        assert_eq!(circuit_breaker.state(), CircuitState::HalfOpen);
+    }
+}
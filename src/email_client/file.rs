@@ -0,0 +1,60 @@
+use super::{build_mime_message, EmailSender, Message, SendEmailError};
+use crate::domain::SubscriberEmail;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use std::path::PathBuf;
+
+// ローカル開発用に、送信する代わりに.emlファイルとしてディレクトリに書き出す
+pub struct FileEmailClient {
+    sender: SubscriberEmail,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileEmailClient {
+    pub fn new(directory: PathBuf, sender: SubscriberEmail) -> Self {
+        let transport = AsyncFileTransport::<Tokio1Executor>::new(directory);
+
+        Self { sender, transport }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for FileEmailClient {
+    async fn send(&self, message: Message) -> Result<(), SendEmailError> {
+        let (envelope, formatted) = build_mime_message(&self.sender, &message)?;
+        self.transport.send_raw(&envelope, &formatted).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileEmailClient;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::EmailSender;
+    use claim::assert_ok;
+    use fake::faker::internet::en::SafeEmail;
+    use fake::Fake;
+
+    #[tokio::test]
+    async fn send_email_writes_the_message_to_the_directory() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let recipient = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = FileEmailClient::new(directory.clone(), sender);
+
+        let outcome = email_client
+            .send_email(recipient.clone(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let written = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(written.contains(recipient.as_ref()));
+        assert!(written.contains("multipart/alternative"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,835 @@
+use super::{
+    mailbox, BatchOutcome, CircuitBreaker, CircuitState, EmailSender, FailedRecipient, Message,
+    RetryPolicy, SendEmailError,
+};
+use crate::domain::SubscriberEmail;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::Span;
+
+#[derive(Serialize)]
+struct SendEmailRequests<'a> {
+    from: String,
+    to: String,
+    subject: &'a str,
+    text: &'a str,
+    html: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    // toと同じく、複数の宛先はカンマ区切りで渡す
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<String>,
+    // プロバイダはbccをヘッダに含めず、送信先にだけ加える
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bcc: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<HeaderRequest<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<&'a str, &'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_stream: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentRequest<'a>>,
+}
+
+#[derive(Serialize)]
+struct HeaderRequest<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[derive(Serialize)]
+struct AttachmentRequest<'a> {
+    name: &'a str,
+    // base64でエンコードした内容
+    content: String,
+    content_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_id: Option<&'a str>,
+}
+
+impl<'a> SendEmailRequests<'a> {
+    fn new(sender: &SubscriberEmail, message: &'a Message) -> Result<Self, EmailClientError> {
+        let from = mailbox(sender, message.sender_name.as_deref())
+            .map_err(|e| EmailClientError::InvalidMessage(e.to_string()))?;
+        let to = mailbox(&message.recipient, message.recipient_name.as_deref())
+            .map_err(|e| EmailClientError::InvalidMessage(e.to_string()))?;
+
+        Ok(Self {
+            from: from.to_string(),
+            to: to.to_string(),
+            subject: &message.subject,
+            text: &message.text,
+            html: &message.html,
+            reply_to: message.reply_to.as_ref().map(|r| r.as_ref()),
+            cc: address_list(&message.cc),
+            bcc: address_list(&message.bcc),
+            headers: message
+                .headers
+                .iter()
+                .map(|(name, value)| HeaderRequest { name, value })
+                .collect(),
+            tag: message.tag.as_deref(),
+            metadata: message
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            message_stream: message.message_stream.as_deref(),
+            attachments: message
+                .attachments
+                .iter()
+                .map(|a| AttachmentRequest {
+                    name: &a.name,
+                    content: base64::encode(&a.content),
+                    content_type: &a.content_type,
+                    content_id: a.content_id.as_deref(),
+                })
+                .collect(),
+        })
+    }
+}
+
+fn address_list(addresses: &[SubscriberEmail]) -> Option<String> {
+    if addresses.is_empty() {
+        return None;
+    }
+    let addresses: Vec<_> = addresses.iter().map(|a| a.as_ref()).collect();
+    Some(addresses.join(", "))
+}
+
+// 一括送信のレスポンスに含まれる、メッセージごとの結果 (リクエストと同じ順序)
+#[derive(Deserialize)]
+struct BatchMessageResult {
+    error_code: i64,
+    message: String,
+}
+
+#[derive(Clone)]
+pub struct EmailClient {
+    sender: SubscriberEmail,
+    base_url: String,
+    http_client: Client,
+    api_key: String,
+    retry_policy: RetryPolicy,
+    circuit_breaker: CircuitBreaker,
+    batch_size: usize,
+}
+
+#[derive(Debug)]
+pub enum EmailClientError {
+    // プロバイダへのリクエストが失敗した
+    Request(reqwest::Error),
+    // サーキットブレーカーがOpenのため、リクエストを送らなかった
+    CircuitOpen,
+    // メールの内容からリクエストを組み立てられなかった
+    InvalidMessage(String),
+}
+
+impl std::fmt::Display for EmailClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailClientError::Request(e) => {
+                write!(f, "Failed to send a request to the email provider: {}", e)
+            }
+            EmailClientError::CircuitOpen => {
+                write!(f, "The email provider circuit breaker is open")
+            }
+            EmailClientError::InvalidMessage(e) => write!(f, "Failed to build the email: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmailClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmailClientError::Request(e) => Some(e),
+            EmailClientError::CircuitOpen | EmailClientError::InvalidMessage(_) => None,
+        }
+    }
+}
+
+// 1回の送信の結果
+enum AttemptError {
+    // 再送しても成功しない (4xxなど)
+    Permanent(reqwest::Error),
+    // 再送すれば成功する可能性がある (5xx、429、接続エラー、タイムアウト)
+    Transient {
+        error: reqwest::Error,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl EmailClient {
+    pub fn new(
+        base_url: String,
+        sender: SubscriberEmail,
+        api_key: String,
+        timeout: Duration,
+        retry_policy: RetryPolicy,
+        circuit_breaker: CircuitBreaker,
+        batch_size: usize,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+
+        Self {
+            sender,
+            base_url,
+            http_client,
+            api_key,
+            retry_policy,
+            circuit_breaker,
+            batch_size,
+        }
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker.state()
+    }
+
+    #[tracing::instrument(name = "Send an email through the email API", skip_all)]
+    pub async fn send_email(
+        &self,
+        recipiant: SubscriberEmail,
+        subject: &str,
+        html: &str,
+        text: &str,
+    ) -> Result<(), EmailClientError> {
+        self.send(&Message::builder(recipiant, subject, html, text).build())
+            .await
+    }
+
+    #[tracing::instrument(name = "Send a message through the email API", skip_all)]
+    pub async fn send(&self, message: &Message) -> Result<(), EmailClientError> {
+        let request_body = SendEmailRequests::new(&self.sender, message)?;
+
+        self.post_with_retries("messages", &request_body).await?;
+
+        Ok(())
+    }
+
+    // メッセージをbatch_size件ずつに分けて一括送信し、送信に失敗した宛先を返す
+    // 呼び出し元は失敗した宛先だけを再送すればよい
+    // batch_sizeが0にならないことは設定の読み込み時に確認している
+    #[tracing::instrument(
+        name = "Send a batch of emails through the email API",
+        skip_all,
+        fields(n_messages = messages.len())
+    )]
+    pub async fn send_batch(&self, messages: Vec<Message>) -> BatchOutcome {
+        let mut outcome = BatchOutcome::default();
+
+        for chunk in messages.chunks(self.batch_size) {
+            let request_body = match chunk
+                .iter()
+                .map(|message| SendEmailRequests::new(&self.sender, message))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(request_body) => request_body,
+                Err(e) => {
+                    outcome.fail_all(chunk, &e.to_string());
+                    continue;
+                }
+            };
+
+            let results = match self
+                .post_with_retries("messages/batch", &request_body)
+                .await
+            {
+                Ok(response) => response
+                    .json::<Vec<BatchMessageResult>>()
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+
+            match results {
+                Ok(results) if results.len() == chunk.len() => {
+                    for (message, result) in chunk.iter().zip(results) {
+                        if result.error_code != 0 {
+                            outcome.failed.push(FailedRecipient {
+                                recipient: message.recipient.clone(),
+                                error: result.message,
+                            });
+                        }
+                    }
+                }
+                Ok(results) => {
+                    let error = format!(
+                        "Expected {} results in the batch response, got {}",
+                        chunk.len(),
+                        results.len()
+                    );
+                    outcome.fail_all(chunk, &error);
+                }
+                Err(error) => outcome.fail_all(chunk, &error),
+            }
+        }
+
+        outcome
+    }
+
+    #[tracing::instrument(skip_all, fields(path = path, n_retries = tracing::field::Empty))]
+    async fn post_with_retries<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        request_body: &T,
+    ) -> Result<reqwest::Response, EmailClientError> {
+        let mut n_retries = 0;
+        loop {
+            if !self.circuit_breaker.allow_request() {
+                return Err(EmailClientError::CircuitOpen);
+            }
+
+            let outcome = self.try_post(path, request_body).await;
+            Span::current().record("n_retries", &n_retries);
+
+            match outcome {
+                Ok(response) => {
+                    self.circuit_breaker.record_success();
+                    return Ok(response);
+                }
+                // プロバイダは応答しているため、サーキットブレーカーの失敗には数えない
+                Err(AttemptError::Permanent(e)) => {
+                    self.circuit_breaker.record_success();
+                    return Err(EmailClientError::Request(e));
+                }
+                Err(AttemptError::Transient { error, retry_after }) => {
+                    self.circuit_breaker.record_failure();
+                    if n_retries >= self.retry_policy.max_retries {
+                        return Err(EmailClientError::Request(error));
+                    }
+
+                    let delay = retry_after
+                        .map(|d| d.min(self.retry_policy.max_delay))
+                        .unwrap_or_else(|| self.retry_policy.backoff(n_retries));
+                    tracing::warn!(
+                        circuit_breaker.state = self.circuit_breaker.state().as_str(),
+                        "Failed to send an email. Retrying in {:?}: {}",
+                        delay,
+                        error
+                    );
+                    tokio::time::sleep(delay).await;
+                    n_retries += 1;
+                }
+            }
+        }
+    }
+
+    async fn try_post<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        request_body: &T,
+    ) -> Result<reqwest::Response, AttemptError> {
+        let url = format!("{}/{}", self.base_url, path);
+
+        let response = self
+            .http_client
+            .post(&url)
+            .header("Authorization", format!("Basic {}", self.api_key))
+            .json(request_body)
+            .send()
+            .await
+            .map_err(|e| {
+                if e.is_connect() || e.is_timeout() {
+                    AttemptError::Transient {
+                        error: e,
+                        retry_after: None,
+                    }
+                } else {
+                    AttemptError::Permanent(e)
+                }
+            })?;
+
+        let status = response.status();
+        let retry_after = retry_after(&response);
+        // サーバーがエラーを返した場合にResponseをErrに変換
+        response.error_for_status().map_err(|e| {
+            if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                AttemptError::Transient {
+                    error: e,
+                    retry_after,
+                }
+            } else {
+                AttemptError::Permanent(e)
+            }
+        })
+    }
+}
+
+// Retry-Afterヘッダの秒数を読み取る (HTTP-date形式には対応しない)
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[async_trait::async_trait]
+impl EmailSender for EmailClient {
+    async fn send(&self, message: Message) -> Result<(), SendEmailError> {
+        EmailClient::send(self, &message).await?;
+        Ok(())
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    async fn send_batch(&self, messages: Vec<Message>) -> BatchOutcome {
+        EmailClient::send_batch(self, messages).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{CircuitBreaker, CircuitState, EmailClient, Message, RetryPolicy};
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::Sentence;
+    use fake::{Fake, Faker};
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+
+            if let Ok(body) = result {
+                dbg!(&body);
+                body.get("from").is_some()
+                    && body.get("to").is_some()
+                    && body.get("subject").is_some()
+                    && body.get("html").is_some()
+                    && body.get("text").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn content() -> String {
+        Sentence(1..10).fake()
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn message(recipient: SubscriberEmail) -> Message {
+        Message::builder(recipient, &subject(), &content(), &content()).build()
+    }
+
+    fn email_client(base_url: String) -> EmailClient {
+        EmailClient::new(
+            base_url,
+            email(),
+            Faker.fake(),
+            std::time::Duration::from_millis(200),
+            RetryPolicy::no_retries(),
+            CircuitBreaker::new(100, std::time::Duration::from_secs(60)),
+            2,
+        )
+    }
+
+    fn retrying_email_client(base_url: String, max_retries: u32) -> EmailClient {
+        EmailClient::new(
+            base_url,
+            email(),
+            Faker.fake(),
+            std::time::Duration::from_millis(200),
+            RetryPolicy {
+                max_retries,
+                base_delay: std::time::Duration::from_millis(1),
+                max_delay: std::time::Duration::from_millis(10),
+            },
+            CircuitBreaker::new(100, std::time::Duration::from_secs(60)),
+            2,
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_base_url() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // テスト用モックサーバを起動
+        Mock::given(header_exists("Authorization"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/messages"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let _ = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_200() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // 3minの遅延をもたせる
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_if_the_server_returns_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri(), 3);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_after_the_maximum_number_of_retries() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri(), 2);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_if_the_server_returns_400() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri(), 3);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_after_429_using_retry_after() {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri(), 1);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_fast_while_the_circuit_is_open() {
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            email(),
+            Faker.fake(),
+            std::time::Duration::from_millis(200),
+            RetryPolicy::no_retries(),
+            CircuitBreaker::new(2, std::time::Duration::from_secs(60)),
+            2,
+        );
+
+        // 2回失敗した後は、リクエストを送らずに失敗する
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..3 {
+            let outcome = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+            assert_err!(outcome);
+        }
+
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+    }
+
+    // リクエストのメッセージごとに成功の結果を返す
+    // failing_recipientに一致する宛先だけは失敗にする
+    fn batch_responder(failing_recipient: Option<String>) -> impl wiremock::Respond {
+        move |request: &Request| {
+            let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            let results: Vec<_> = messages
+                .iter()
+                .map(|m| {
+                    if Some(m["to"].as_str().unwrap()) == failing_recipient.as_deref() {
+                        serde_json::json!({"error_code": 406, "message": "Inactive recipient"})
+                    } else {
+                        serde_json::json!({"error_code": 0, "message": "OK"})
+                    }
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(results)
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_fires_one_request_per_chunk() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/messages/batch"))
+            .and(method("POST"))
+            .respond_with(batch_responder(None))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let messages = (0..5).map(|_| message(email())).collect();
+        let outcome = email_client.send_batch(messages).await;
+
+        assert!(outcome.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_recipients_that_failed() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients: Vec<_> = (0..4).map(|_| email()).collect();
+        let failing_recipient = recipients[1].clone();
+
+        Mock::given(path("/messages/batch"))
+            .respond_with(batch_responder(Some(failing_recipient.as_ref().to_owned())))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let messages = recipients.iter().cloned().map(message).collect();
+        let outcome = email_client.send_batch(messages).await;
+
+        let failed = outcome.failed_recipients();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].as_ref(), failing_recipient.as_ref());
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_the_whole_chunk_if_the_request_fails() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/messages/batch"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/messages/batch"))
+            .respond_with(batch_responder(None))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recipients: Vec<_> = (0..3).map(|_| email()).collect();
+        let messages = recipients.iter().cloned().map(message).collect();
+        let outcome = email_client.send_batch(messages).await;
+
+        let failed: Vec<_> = outcome
+            .failed_recipients()
+            .iter()
+            .map(|r| r.as_ref().to_owned())
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                recipients[0].as_ref().to_owned(),
+                recipients[1].as_ref().to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_serializes_every_field_of_the_message() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipient = email();
+        let reply_to = email();
+        let (cc, bcc) = (email(), email());
+
+        Mock::given(path("/messages"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let message = Message::builder(recipient.clone(), &subject(), &content(), &content())
+            .recipient_name("Ursula Le Guin")
+            .sender_name("Newsletter")
+            .reply_to(reply_to.clone())
+            .cc(cc.clone())
+            .bcc(bcc.clone())
+            .header("X-Campaign", "spring")
+            .unwrap()
+            .tag("newsletter")
+            .metadata("issue_id", "42")
+            .message_stream("broadcast")
+            .attachment("hello.txt", "text/plain", b"hello".to_vec())
+            .inline_image("logo", "logo.png", "image/png", vec![0, 1, 2])
+            .build();
+        let outcome = email_client.send(&message).await;
+        assert_ok!(outcome);
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["to"],
+            format!("Ursula Le Guin <{}>", recipient.as_ref())
+        );
+        assert!(body["from"].as_str().unwrap().starts_with("Newsletter <"));
+        assert_eq!(body["reply_to"], reply_to.as_ref());
+        assert_eq!(body["cc"], cc.as_ref());
+        assert_eq!(body["bcc"], bcc.as_ref());
+        assert_eq!(
+            body["headers"],
+            serde_json::json!([{"name": "X-Campaign", "value": "spring"}])
+        );
+        assert_eq!(body["tag"], "newsletter");
+        assert_eq!(body["metadata"], serde_json::json!({"issue_id": "42"}));
+        assert_eq!(body["message_stream"], "broadcast");
+        assert_eq!(body["attachments"][0]["content"], "aGVsbG8=");
+        assert!(body["attachments"][0].get("content_id").is_none());
+        assert_eq!(body["attachments"][1]["content_id"], "logo");
+    }
+
+    #[tokio::test]
+    async fn optional_fields_are_omitted_when_unset() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let _ = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        for field in [
+            "reply_to",
+            "cc",
+            "bcc",
+            "headers",
+            "tag",
+            "metadata",
+            "message_stream",
+            "attachments",
+        ] {
+            assert!(body.get(field).is_none(), "{} should be omitted", field);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use super::{EmailSender, Message, SendEmailError};
+use std::sync::{Arc, Mutex};
+
+// テスト用に、送信したメールをメモリ上に記録する
+#[derive(Clone, Default)]
+pub struct InMemoryEmailClient {
+    sent_emails: Arc<Mutex<Vec<Message>>>,
+}
+
+impl InMemoryEmailClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent_emails(&self) -> Vec<Message> {
+        self.sent_emails.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for InMemoryEmailClient {
+    async fn send(&self, message: Message) -> Result<(), SendEmailError> {
+        self.sent_emails.lock().unwrap().push(message);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryEmailClient;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::EmailSender;
+    use fake::faker::internet::en::SafeEmail;
+    use fake::Fake;
+
+    #[tokio::test]
+    async fn send_email_records_the_message() {
+        let email_client = InMemoryEmailClient::new();
+        let recipient = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+
+        email_client
+            .send_email(recipient.clone(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await
+            .unwrap();
+
+        let sent_emails = email_client.sent_emails();
+        assert_eq!(sent_emails.len(), 1);
+        assert_eq!(sent_emails[0].recipient.as_ref(), recipient.as_ref());
+        assert_eq!(sent_emails[0].subject, "Welcome!");
+    }
+}
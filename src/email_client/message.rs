@@ -0,0 +1,214 @@
+use crate::domain::SubscriberEmail;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+    // 設定されている場合は、HTML本文から cid:<content_id> で参照するインライン画像として扱う
+    pub content_id: Option<String>,
+}
+
+// 送信するメール
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub recipient: SubscriberEmail,
+    pub recipient_name: Option<String>,
+    pub sender_name: Option<String>,
+    pub reply_to: Option<SubscriberEmail>,
+    pub cc: Vec<SubscriberEmail>,
+    // 他の宛先に見えないよう、ヘッダには含めずエンベロープ(送信先)にだけ含める
+    pub bcc: Vec<SubscriberEmail>,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    pub headers: Vec<(String, String)>,
+    // プロバイダ側でメールを分類するためのタグ
+    pub tag: Option<String>,
+    // プロバイダのWebhookなどで返される任意のキーと値
+    pub metadata: BTreeMap<String, String>,
+    // トランザクションメールと一斉配信を分けるための、プロバイダのメッセージストリーム名
+    pub message_stream: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    // 宛先と件名・本文以外は任意のため、メソッドをつなげて組み立てる
+    //
+    // Message::builder(recipient, "Welcome!", html, text)
+    //     .recipient_name("Ursula")
+    //     .tag("confirmation")
+    //     .build()
+    pub fn builder(
+        recipient: SubscriberEmail,
+        subject: &str,
+        html: &str,
+        text: &str,
+    ) -> MessageBuilder {
+        MessageBuilder(Message {
+            recipient,
+            recipient_name: None,
+            sender_name: None,
+            reply_to: None,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            subject: subject.to_owned(),
+            html: html.to_owned(),
+            text: text.to_owned(),
+            headers: Vec::new(),
+            tag: None,
+            metadata: BTreeMap::new(),
+            message_stream: None,
+            attachments: Vec::new(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MessageBuilder(Message);
+
+// 専用のメソッドや送信方法が設定するヘッダ
+// headerで上書き・重複させると、宛先や本文の解釈が変わってしまう
+const RESERVED_HEADERS: &[&str] = &[
+    "bcc",
+    "cc",
+    "content-transfer-encoding",
+    "content-type",
+    "date",
+    "from",
+    "list-unsubscribe",
+    "list-unsubscribe-post",
+    "message-id",
+    "mime-version",
+    "reply-to",
+    "return-path",
+    "sender",
+    "subject",
+    "to",
+];
+
+impl MessageBuilder {
+    pub fn recipient_name(mut self, name: &str) -> Self {
+        self.0.recipient_name = Some(name.to_owned());
+        self
+    }
+
+    pub fn sender_name(mut self, name: &str) -> Self {
+        self.0.sender_name = Some(name.to_owned());
+        self
+    }
+
+    pub fn reply_to(mut self, reply_to: SubscriberEmail) -> Self {
+        self.0.reply_to = Some(reply_to);
+        self
+    }
+
+    pub fn cc(mut self, cc: SubscriberEmail) -> Self {
+        self.0.cc.push(cc);
+        self
+    }
+
+    pub fn bcc(mut self, bcc: SubscriberEmail) -> Self {
+        self.0.bcc.push(bcc);
+        self
+    }
+
+    // 宛先や件名などのヘッダは専用のメソッドで設定するため、ここでは受け付けない
+    pub fn header(self, name: &str, value: &str) -> Result<Self, String> {
+        if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(format!(
+                "{} is a reserved header and cannot be set directly.",
+                name
+            ));
+        }
+        Ok(self.push_header(name, value))
+    }
+
+    fn push_header(mut self, name: &str, value: &str) -> Self {
+        self.0.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    // RFC 8058のワンクリック購読解除に対応したヘッダを付与する
+    pub fn list_unsubscribe(self, unsubscribe_link: &str) -> Self {
+        self.push_header("List-Unsubscribe", &format!("<{}>", unsubscribe_link))
+            .push_header("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.0.tag = Some(tag.to_owned());
+        self
+    }
+
+    pub fn metadata(mut self, key: &str, value: &str) -> Self {
+        self.0.metadata.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn message_stream(mut self, message_stream: &str) -> Self {
+        self.0.message_stream = Some(message_stream.to_owned());
+        self
+    }
+
+    pub fn attachment(mut self, name: &str, content_type: &str, content: Vec<u8>) -> Self {
+        self.0.attachments.push(Attachment {
+            name: name.to_owned(),
+            content_type: content_type.to_owned(),
+            content,
+            content_id: None,
+        });
+        self
+    }
+
+    pub fn inline_image(
+        mut self,
+        content_id: &str,
+        name: &str,
+        content_type: &str,
+        content: Vec<u8>,
+    ) -> Self {
+        self.0.attachments.push(Attachment {
+            name: name.to_owned(),
+            content_type: content_type.to_owned(),
+            content,
+            content_id: Some(content_id.to_owned()),
+        });
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Message;
+    use crate::domain::SubscriberEmail;
+    use claim::{assert_err, assert_ok};
+
+    fn builder() -> super::MessageBuilder {
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        Message::builder(recipient, "Welcome!", "<p>Hello</p>", "Hello")
+    }
+
+    #[test]
+    fn reserved_headers_are_rejected_regardless_of_case() {
+        for name in [
+            "From",
+            "subject",
+            "TO",
+            "Bcc",
+            "Content-Type",
+            "List-Unsubscribe",
+        ] {
+            assert_err!(builder().header(name, "value"));
+        }
+    }
+
+    #[test]
+    fn custom_headers_are_accepted() {
+        assert_ok!(builder().header("X-Campaign", "spring"));
+    }
+}
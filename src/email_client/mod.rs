@@ -0,0 +1,170 @@
+mod circuit_breaker;
+mod file;
+mod http;
+mod in_memory;
+mod message;
+mod retry_policy;
+mod smtp;
+
+pub use circuit_breaker::{CircuitBreaker, CircuitState};
+pub use file::FileEmailClient;
+pub use http::{EmailClient, EmailClientError};
+pub use in_memory::InMemoryEmailClient;
+pub use message::{Attachment, Message, MessageBuilder};
+pub use retry_policy::RetryPolicy;
+pub use smtp::SmtpEmailClient;
+
+use crate::domain::SubscriberEmail;
+use lettre::address::Envelope;
+use lettre::message::header::{ContentType, HeaderName, HeaderValue, Headers};
+use lettre::message::{Attachment as MimeAttachment, Mailbox, MultiPart, SinglePart};
+use lettre::Message as MimeMessage;
+
+pub type SendEmailError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub struct FailedRecipient {
+    pub recipient: SubscriberEmail,
+    pub error: String,
+}
+
+// 一括送信の結果
+// 送信に失敗した宛先だけを保持する
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    pub failed: Vec<FailedRecipient>,
+}
+
+impl BatchOutcome {
+    pub fn failed_recipients(&self) -> Vec<SubscriberEmail> {
+        self.failed.iter().map(|f| f.recipient.clone()).collect()
+    }
+
+    fn fail_all(&mut self, messages: &[Message], error: &str) {
+        for message in messages {
+            self.failed.push(FailedRecipient {
+                recipient: message.recipient.clone(),
+                error: error.to_owned(),
+            });
+        }
+    }
+}
+
+// ルーティングやワーカーはこのトレイトに依存し、送信方法は設定で切り替える
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, message: Message) -> Result<(), SendEmailError>;
+
+    async fn send_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html: &str,
+        text: &str,
+    ) -> Result<(), SendEmailError> {
+        self.send(Message::builder(recipient, subject, html, text).build())
+            .await
+    }
+
+    // 1回の一括送信にまとめられるメッセージの最大数
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    // 一括送信に対応していない送信方法では、1通ずつ送信する
+    async fn send_batch(&self, messages: Vec<Message>) -> BatchOutcome {
+        let mut outcome = BatchOutcome::default();
+        for message in messages {
+            let recipient = message.recipient.clone();
+            if let Err(e) = self.send(message).await {
+                outcome.failed.push(FailedRecipient {
+                    recipient,
+                    error: e.to_string(),
+                });
+            }
+        }
+        outcome
+    }
+}
+
+// 表示名がある場合は "表示名 <アドレス>" の形式になる
+fn mailbox(email: &SubscriberEmail, name: Option<&str>) -> Result<Mailbox, SendEmailError> {
+    Ok(Mailbox::new(
+        name.map(String::from),
+        email.as_ref().parse()?,
+    ))
+}
+
+// SMTPやファイル出力で使う、MIME形式のメッセージを組み立てる
+// htmlとtextはmultipart/alternativeにまとめ、インライン画像はhtmlとmultipart/relatedに、
+// 添付ファイルはmultipart/mixedにまとめる
+// タグやメタデータ、メッセージストリームはHTTP API固有の機能のため、ここでは使わない
+fn build_mime_message(
+    sender: &SubscriberEmail,
+    message: &Message,
+) -> Result<(Envelope, Vec<u8>), SendEmailError> {
+    let mut builder = MimeMessage::builder()
+        .from(mailbox(sender, message.sender_name.as_deref())?)
+        .to(mailbox(
+            &message.recipient,
+            message.recipient_name.as_deref(),
+        )?)
+        .subject(message.subject.clone());
+    if let Some(reply_to) = &message.reply_to {
+        builder = builder.reply_to(mailbox(reply_to, None)?);
+    }
+    for cc in &message.cc {
+        builder = builder.cc(mailbox(cc, None)?);
+    }
+    // lettreはエンベロープを組み立てた後にBccヘッダを取り除く
+    for bcc in &message.bcc {
+        builder = builder.bcc(mailbox(bcc, None)?);
+    }
+
+    let (inline_images, attachments): (Vec<_>, Vec<_>) = message
+        .attachments
+        .iter()
+        .partition(|a| a.content_id.is_some());
+
+    let alternative = MultiPart::alternative().singlepart(SinglePart::plain(message.text.clone()));
+    let mut body = if inline_images.is_empty() {
+        alternative.singlepart(SinglePart::html(message.html.clone()))
+    } else {
+        let mut related = MultiPart::related().singlepart(SinglePart::html(message.html.clone()));
+        for image in inline_images {
+            related = related.singlepart(
+                MimeAttachment::new_inline(image.content_id.clone().unwrap()).body(
+                    image.content.clone(),
+                    ContentType::parse(&image.content_type)?,
+                ),
+            );
+        }
+        alternative.multipart(related)
+    };
+
+    if !attachments.is_empty() {
+        let mut mixed = MultiPart::mixed().multipart(body);
+        for attachment in attachments {
+            mixed = mixed.singlepart(MimeAttachment::new(attachment.name.clone()).body(
+                attachment.content.clone(),
+                ContentType::parse(&attachment.content_type)?,
+            ));
+        }
+        body = mixed;
+    }
+
+    let mime_message = builder.multipart(body)?;
+
+    // lettreは任意のヘッダを追加できないため、組み立てたメッセージの先頭に書き足す
+    let mut headers = Headers::new();
+    for (name, value) in &message.headers {
+        headers.insert_raw(HeaderValue::new(
+            HeaderName::new_from_ascii(name.clone())?,
+            value.clone(),
+        ));
+    }
+    let mut formatted = headers.to_string().into_bytes();
+    formatted.extend(mime_message.formatted());
+
+    Ok((mime_message.envelope().clone(), formatted))
+}
@@ -0,0 +1,65 @@
+use rand::Rng;
+use std::time::Duration;
+
+// 一時的な失敗に対する再送の方針
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn no_retries() -> Self {
+        Self {
+            max_retries: 0,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    // 指数関数的に増やした待ち時間を上限とし、その範囲からランダムに選ぶ (full jitter)
+    // 複数のクライアントが同時に再送してプロバイダに負荷が集中するのを避ける
+    pub fn backoff(&self, n_retries: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(n_retries.min(16)));
+        let ceiling = exponential.min(self.max_delay);
+
+        let millis = rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64);
+        Duration::from_millis(millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn backoff_never_exceeds_the_exponential_ceiling() {
+        let retry_policy = retry_policy();
+        for n_retries in 0..4 {
+            let ceiling = Duration::from_millis(100 * 2u64.pow(n_retries));
+            for _ in 0..100 {
+                assert!(retry_policy.backoff(n_retries) <= ceiling);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped_at_the_max_delay() {
+        let retry_policy = retry_policy();
+        for _ in 0..100 {
+            assert!(retry_policy.backoff(30) <= Duration::from_secs(1));
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use super::{build_mime_message, EmailSender, Message, SendEmailError};
+use crate::configuration::{SmtpAuthMechanism, SmtpSettings, SmtpTls};
+use crate::domain::SubscriberEmail;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+#[derive(Clone)]
+pub struct SmtpEmailClient {
+    sender: SubscriberEmail,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpEmailClient {
+    pub fn new(
+        settings: &SmtpSettings,
+        sender: SubscriberEmail,
+        timeout: std::time::Duration,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let builder = match settings.tls {
+            SmtpTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host)
+            }
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)?
+            }
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host)?,
+        };
+
+        let mut builder = builder
+            .port(settings.port)
+            .timeout(Some(timeout))
+            .pool_config(PoolConfig::new().max_size(settings.pool_max_size));
+
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        if let Some(mechanism) = &settings.auth_mechanism {
+            let mechanism = match mechanism {
+                SmtpAuthMechanism::Plain => Mechanism::Plain,
+                SmtpAuthMechanism::Login => Mechanism::Login,
+            };
+            builder = builder.authentication(vec![mechanism]);
+        }
+
+        Ok(Self {
+            sender,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpEmailClient {
+    async fn send(&self, message: Message) -> Result<(), SendEmailError> {
+        let (envelope, formatted) = build_mime_message(&self.sender, &message)?;
+        self.transport.send_raw(&envelope, &formatted).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SmtpEmailClient;
+    use crate::configuration::{SmtpAuthMechanism, SmtpSettings, SmtpTls};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailSender, Message};
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::Fake;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // テスト用のSMTPサーバ
+    // 受信したコマンドを記録し、認証情報が一致する場合のみ送信を受け付ける
+    struct SmtpStandIn {
+        port: u16,
+        commands: Arc<Mutex<Vec<String>>>,
+        connections: Arc<Mutex<u32>>,
+    }
+
+    impl SmtpStandIn {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let connections = Arc::new(Mutex::new(0));
+
+            let (c, n) = (commands.clone(), connections.clone());
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    *n.lock().unwrap() += 1;
+                    tokio::spawn(Self::handle(stream, c.clone()));
+                }
+            });
+
+            Self {
+                port,
+                commands,
+                connections,
+            }
+        }
+
+        async fn handle(stream: tokio::net::TcpStream, commands: Arc<Mutex<Vec<String>>>) {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut in_data = false;
+            let mut in_login = 0;
+
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                commands.lock().unwrap().push(line.clone());
+
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 OK\r\n"
+                } else if in_login > 0 {
+                    in_login -= 1;
+                    match (in_login, line.as_str()) {
+                        (1, "dXNlcg==") => b"334 UGFzc3dvcmQ6\r\n",
+                        (0, "cGFzc3dvcmQ=") => b"235 Authenticated\r\n",
+                        _ => {
+                            in_login = 0;
+                            b"535 Invalid credentials\r\n"
+                        }
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME\r\n"
+                } else if line == "AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=" {
+                    b"235 Authenticated\r\n"
+                } else if line.starts_with("AUTH PLAIN") {
+                    b"535 Invalid credentials\r\n"
+                } else if line == "AUTH LOGIN" {
+                    in_login = 2;
+                    b"334 VXNlcm5hbWU6\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 Start mail input\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+
+        fn connections(&self) -> u32 {
+            *self.connections.lock().unwrap()
+        }
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn settings(port: u16, password: &str, auth_mechanism: SmtpAuthMechanism) -> SmtpSettings {
+        SmtpSettings {
+            host: "127.0.0.1".into(),
+            port,
+            tls: SmtpTls::None,
+            username: Some("user".into()),
+            password: Some(password.into()),
+            auth_mechanism: Some(auth_mechanism),
+            pool_max_size: 2,
+        }
+    }
+
+    fn email_client(settings: &SmtpSettings) -> SmtpEmailClient {
+        SmtpEmailClient::new(settings, email(), std::time::Duration::from_secs(2)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_authenticates_with_plain_and_sends_a_multipart_message() {
+        let smtp_server = SmtpStandIn::start().await;
+        let email_client = email_client(&settings(
+            smtp_server.port,
+            "password",
+            SmtpAuthMechanism::Plain,
+        ));
+        let recipient = email();
+
+        let outcome = email_client
+            .send_email(recipient.clone(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+
+        assert_ok!(outcome);
+        let commands = smtp_server.commands();
+        assert!(commands.contains(&"AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=".to_string()));
+        assert!(commands.contains(&format!("RCPT TO:<{}>", recipient.as_ref())));
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("Content-Type: multipart/alternative")));
+        assert!(commands
+            .iter()
+            .any(|c| c == "Content-Type: text/plain; charset=utf-8"));
+        assert!(commands
+            .iter()
+            .any(|c| c == "Content-Type: text/html; charset=utf-8"));
+    }
+
+    #[tokio::test]
+    async fn send_email_authenticates_with_login() {
+        let smtp_server = SmtpStandIn::start().await;
+        let email_client = email_client(&settings(
+            smtp_server.port,
+            "password",
+            SmtpAuthMechanism::Login,
+        ));
+
+        let outcome = email_client
+            .send_email(email(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+
+        assert_ok!(outcome);
+        assert!(smtp_server.commands().contains(&"AUTH LOGIN".to_string()));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_credentials_are_rejected() {
+        let smtp_server = SmtpStandIn::start().await;
+        let email_client = email_client(&settings(
+            smtp_server.port,
+            "wrong-password",
+            SmtpAuthMechanism::Plain,
+        ));
+
+        let outcome = email_client
+            .send_email(email(), "Welcome!", "<p>Hello</p>", "Hello")
+            .await;
+
+        assert_err!(outcome);
+        assert!(!smtp_server.commands().contains(&"DATA".to_string()));
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_across_emails() {
+        let smtp_server = SmtpStandIn::start().await;
+        let email_client = email_client(&settings(
+            smtp_server.port,
+            "password",
+            SmtpAuthMechanism::Plain,
+        ));
+
+        for _ in 0..5 {
+            email_client
+                .send_email(email(), "Welcome!", "<p>Hello</p>", "Hello")
+                .await
+                .unwrap();
+        }
+
+        // 使い終わった接続はプールに非同期で返却されるため、接続数は送信数より少なければよい
+        assert!(smtp_server.connections() < 5);
+    }
+
+    #[tokio::test]
+    async fn send_includes_custom_headers_and_attachments() {
+        let smtp_server = SmtpStandIn::start().await;
+        let email_client = email_client(&settings(
+            smtp_server.port,
+            "password",
+            SmtpAuthMechanism::Plain,
+        ));
+        let reply_to = email();
+        let (cc, bcc) = (email(), email());
+
+        let message = Message::builder(email(), "Welcome!", "<p>Hello</p>", "Hello")
+            .reply_to(reply_to.clone())
+            .cc(cc.clone())
+            .bcc(bcc.clone())
+            .header("X-Campaign", "spring")
+            .unwrap()
+            .attachment("hello.txt", "text/plain", b"hello".to_vec())
+            .inline_image("logo", "logo.png", "image/png", vec![0, 1, 2])
+            .build();
+        let outcome = email_client.send(message).await;
+
+        assert_ok!(outcome);
+        let commands = smtp_server.commands();
+        assert!(commands.contains(&"X-Campaign: spring".to_string()));
+        assert!(commands.contains(&format!("Reply-To: {}", reply_to.as_ref())));
+        assert!(commands.contains(&format!("Cc: {}", cc.as_ref())));
+        assert!(commands.contains(&format!("RCPT TO:<{}>", cc.as_ref())));
+        // bccは送信先にだけ含め、ヘッダには含めない
+        assert!(commands.contains(&format!("RCPT TO:<{}>", bcc.as_ref())));
+        assert!(!commands
+            .iter()
+            .any(|c| c.contains(bcc.as_ref()) && !c.starts_with("RCPT")));
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("Content-Type: multipart/mixed")));
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("Content-Type: multipart/related")));
+        assert!(commands
+            .iter()
+            .any(|c| c == "Content-Disposition: attachment; filename=\"hello.txt\""));
+        assert!(commands.contains(&"Content-ID: <logo>".to_string()));
+    }
+}
@@ -0,0 +1,240 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailSender, Message};
+use crate::issue_delivery_worker::ExecutionOutcome;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+// この回数だけ送信に失敗したメールは諦めて破棄する
+const MAX_ATTEMPTS: i32 = 10;
+
+// 送信するメールをoutboxに書き込む
+// 呼び出し元のトランザクションがコミットされたときにのみ、ディスパッチャから送信される
+// unsubscribe_linkを渡した場合は、List-Unsubscribeヘッダを付けて送信する
+#[tracing::instrument(
+    name = "Enqueue an email in the outbox",
+    skip(transaction, subject, html_content, text_content, unsubscribe_link)
+)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+    unsubscribe_link: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+
+    sqlx::query!(
+        r#"INSERT INTO email_outbox (
+            email_id, recipient, subject, html_content, text_content,
+            unsubscribe_link, created_at, n_attempts, next_attempt_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, 0, $7)"#,
+        Uuid::new_v4(),
+        recipient.as_ref(),
+        subject,
+        html_content,
+        text_content,
+        unsubscribe_link,
+        now
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+// この関数はアプリケーションが停止したときのみ返される
+pub async fn run_dispatcher_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+) -> Result<(), std::io::Error> {
+    loop {
+        match try_dispatch_email(&pool, email_client.as_ref()).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+struct OutboxEmail {
+    email_id: Uuid,
+    recipient: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    unsubscribe_link: Option<String>,
+    n_attempts: i32,
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(email_id = tracing::field::Empty, recipient = tracing::field::Empty),
+    err
+)]
+pub async fn try_dispatch_email(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    // 送信中はトランザクションを開いたままにせず、next_attempt_atを先送りしてメールを確保する
+    // 確保している間は他のディスパッチャから見えないため、同じメールが二重に送信されない
+    // 送信中にディスパッチャが落ちた場合は、確保の期限が切れてから送信し直す
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"UPDATE email_outbox
+        SET next_attempt_at = $2
+        WHERE email_id = (
+            SELECT email_id
+            FROM email_outbox
+            WHERE next_attempt_at <= $1
+            ORDER BY next_attempt_at
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING email_id, recipient, subject, html_content, text_content, unsubscribe_link,
+            n_attempts"#,
+        Utc::now(),
+        Utc::now() + lease_duration()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let email = match email {
+        Some(email) => email,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("email_id", &display(email.email_id))
+        .record("recipient", &display(&email.recipient));
+
+    let outcome = match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => {
+            let mut message = Message::builder(
+                recipient,
+                &email.subject,
+                &email.html_content,
+                &email.text_content,
+            );
+            if let Some(unsubscribe_link) = &email.unsubscribe_link {
+                message = message.list_unsubscribe(unsubscribe_link);
+            }
+            email_client
+                .send(message.build())
+                .await
+                .map_err(|e| e.to_string())
+        }
+        Err(e) => {
+            tracing::warn!("Discarding an email with an invalid recipient: {}", e);
+            Ok(())
+        }
+    };
+
+    match outcome {
+        Ok(()) => delete_email(pool, email.email_id).await?,
+        Err(e) => {
+            let n_attempts = email.n_attempts + 1;
+            if n_attempts >= MAX_ATTEMPTS {
+                tracing::error!(
+                    "Giving up on an email after {} failed attempts: {}",
+                    n_attempts,
+                    e
+                );
+                delete_email(pool, email.email_id).await?;
+            } else {
+                tracing::warn!("Failed to send an email. Retrying later: {}", e);
+                schedule_retry(pool, email.email_id, n_attempts, &e).await?;
+            }
+        }
+    }
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// 送信の再試行を含めても、これより長くかからないようにしておく
+fn lease_duration() -> chrono::Duration {
+    chrono::Duration::minutes(10)
+}
+
+// 失敗するたびに待ち時間を倍にする (上限は1時間)
+// 号の配信タスクの再試行にも使う
+pub(crate) fn backoff(n_attempts: i32) -> chrono::Duration {
+    let base = chrono::Duration::seconds(2);
+    let max = chrono::Duration::hours(1);
+    let exponent = (n_attempts - 1).clamp(0, 16) as u32;
+
+    std::cmp::min(base * 2i32.pow(exponent), max)
+}
+
+#[tracing::instrument(skip(pool, last_error))]
+async fn schedule_retry(
+    pool: &PgPool,
+    email_id: Uuid,
+    n_attempts: i32,
+    last_error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE email_outbox
+        SET n_attempts = $2, next_attempt_at = $3, last_error = $4
+        WHERE email_id = $1"#,
+        email_id,
+        n_attempts,
+        Utc::now() + backoff(n_attempts),
+        last_error
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn delete_email(pool: &PgPool, email_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM email_outbox WHERE email_id = $1"#, email_id)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backoff;
+
+    #[test]
+    fn backoff_doubles_after_every_failed_attempt() {
+        assert_eq!(backoff(1), chrono::Duration::seconds(2));
+        assert_eq!(backoff(2), chrono::Duration::seconds(4));
+        assert_eq!(backoff(3), chrono::Duration::seconds(8));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_one_hour() {
+        assert_eq!(backoff(30), chrono::Duration::hours(1));
+    }
+}
@@ -0,0 +1,256 @@
+use serde::Serialize;
+use tera::{Context, Tera};
+
+// 起動時に存在しなければならないテンプレート
+// それぞれ`{name}.html`と`{name}.txt`の2つのファイルが必要
+const REQUIRED_TEMPLATES: &[&str] = &[
+    ConfirmationEmail::NAME,
+    ConfirmationReminderEmail::NAME,
+    AlreadySubscribedEmail::NAME,
+    NewsletterEmail::NAME,
+    DigestEmail::NAME,
+];
+
+// テンプレートに渡すコンテキストの型
+// NAMEで描画するテンプレートを指定する
+pub trait EmailTemplate: Serialize {
+    const NAME: &'static str;
+}
+
+#[derive(Serialize)]
+pub struct ConfirmationEmail<'a> {
+    pub subscriber_name: &'a str,
+    pub confirmation_link: &'a str,
+}
+
+impl EmailTemplate for ConfirmationEmail<'_> {
+    const NAME: &'static str = "confirmation";
+}
+
+// 登録から一定時間が経っても確認されていないときに一度だけ送る
+#[derive(Serialize)]
+pub struct ConfirmationReminderEmail<'a> {
+    pub subscriber_name: &'a str,
+    pub confirmation_link: &'a str,
+}
+
+impl EmailTemplate for ConfirmationReminderEmail<'_> {
+    const NAME: &'static str = "confirmation_reminder";
+}
+
+// 確認済みのアドレスで再度登録されたときに送る
+#[derive(Serialize)]
+pub struct AlreadySubscribedEmail<'a> {
+    pub subscriber_name: &'a str,
+}
+
+impl EmailTemplate for AlreadySubscribedEmail<'_> {
+    const NAME: &'static str = "already_subscribed";
+}
+
+// html_contentは編集者が書いたHTMLなので、エスケープせずに埋め込む
+#[derive(Serialize)]
+pub struct NewsletterEmail<'a> {
+    pub title: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub unsubscribe_link: &'a str,
+    pub preferences_link: &'a str,
+}
+
+impl EmailTemplate for NewsletterEmail<'_> {
+    const NAME: &'static str = "newsletter";
+}
+
+// まとめて受け取る購読者に、その日に発行された号を1通にまとめて送る
+#[derive(Serialize)]
+pub struct DigestEmail<'a> {
+    pub issues: Vec<DigestIssue<'a>>,
+    pub unsubscribe_link: &'a str,
+    pub preferences_link: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct DigestIssue<'a> {
+    pub title: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+}
+
+impl EmailTemplate for DigestEmail<'_> {
+    const NAME: &'static str = "digest";
+}
+
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct EmailTemplates {
+    tera: Tera,
+}
+
+impl EmailTemplates {
+    // ディレクトリ内のテンプレートを読み込み、必須テンプレートが揃っているか検証する
+    pub fn from_directory(directory: &str) -> Result<Self, tera::Error> {
+        let mut tera = Tera::new(&format!("{}/**/*", directory.trim_end_matches('/')))?;
+        // HTML版のみ自動でエスケープする
+        tera.autoescape_on(vec![".html"]);
+        tera.set_escape_fn(escape_html);
+
+        let names: Vec<&str> = tera.get_template_names().collect();
+        for name in REQUIRED_TEMPLATES {
+            for variant in [html_template(name), text_template(name)] {
+                if !names.contains(&variant.as_str()) {
+                    return Err(tera::Error::msg(format!(
+                        "Missing required email template `{}` in {}",
+                        variant, directory
+                    )));
+                }
+            }
+        }
+
+        Ok(Self { tera })
+    }
+
+    pub fn render<T: EmailTemplate>(&self, context: &T) -> Result<RenderedEmail, tera::Error> {
+        let context = Context::from_serialize(context)?;
+        let html = self.tera.render(&html_template(T::NAME), &context)?;
+        let text = self.tera.render(&text_template(T::NAME), &context)?;
+        Ok(RenderedEmail { html, text })
+    }
+}
+
+fn html_template(name: &str) -> String {
+    format!("{}.html", name)
+}
+
+fn text_template(name: &str) -> String {
+    format!("{}.txt", name)
+}
+
+// Tera標準のエスケープは`/`も置換してしまい、href内のURLが読みにくくなるため、
+// HTMLとして意味を持つ文字のみを置換する
+fn escape_html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#x27;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfirmationEmail, DigestEmail, DigestIssue, EmailTemplates, NewsletterEmail};
+    use claim::assert_err;
+
+    fn templates() -> EmailTemplates {
+        EmailTemplates::from_directory("templates/emails").unwrap()
+    }
+
+    #[test]
+    fn confirmation_email_contains_the_link_in_both_variants() {
+        let link = "https://my-api.com/subscriptions/confirm?subscription_token=abc";
+        let email = templates()
+            .render(&ConfirmationEmail {
+                subscriber_name: "Ursula",
+                confirmation_link: link,
+            })
+            .unwrap();
+
+        assert!(email.html.contains(&format!("<a href=\"{}\">", link)));
+        assert!(email.text.contains(link));
+        assert!(!email.text.contains("<a"));
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_but_not_in_text() {
+        let email = templates()
+            .render(&ConfirmationEmail {
+                subscriber_name: "<script>alert('x')</script>",
+                confirmation_link: "https://my-api.com",
+            })
+            .unwrap();
+
+        assert!(email
+            .html
+            .contains("&lt;script&gt;alert(&#x27;x&#x27;)&lt;/script&gt;"));
+        assert!(email.text.contains("<script>alert('x')</script>"));
+    }
+
+    #[test]
+    fn newsletter_html_content_is_not_escaped() {
+        let email = templates()
+            .render(&NewsletterEmail {
+                title: "Issue #1",
+                html_content: "<p>Hello</p>",
+                text_content: "Hello",
+                unsubscribe_link: "https://my-api.com/subscriptions/unsubscribe",
+                preferences_link: "https://my-api.com/subscriptions/preferences",
+            })
+            .unwrap();
+
+        assert!(email.html.contains("<p>Hello</p>"));
+        assert!(email.text.contains("Hello"));
+        assert!(email
+            .text
+            .contains("https://my-api.com/subscriptions/unsubscribe"));
+        assert!(email
+            .html
+            .contains("https://my-api.com/subscriptions/preferences"));
+    }
+
+    #[test]
+    fn digest_contains_every_issue() {
+        let email = templates()
+            .render(&DigestEmail {
+                issues: vec![
+                    DigestIssue {
+                        title: "Issue #1",
+                        html_content: "<p>First</p>",
+                        text_content: "First",
+                    },
+                    DigestIssue {
+                        title: "Issue #2",
+                        html_content: "<p>Second</p>",
+                        text_content: "Second",
+                    },
+                ],
+                unsubscribe_link: "https://my-api.com/subscriptions/unsubscribe",
+                preferences_link: "https://my-api.com/subscriptions/preferences",
+            })
+            .unwrap();
+
+        for content in ["Issue #1", "<p>First</p>", "Issue #2", "<p>Second</p>"] {
+            assert!(email.html.contains(content));
+        }
+        for content in ["Issue #1", "First", "Issue #2", "Second"] {
+            assert!(email.text.contains(content));
+        }
+        assert!(email
+            .text
+            .contains("https://my-api.com/subscriptions/unsubscribe"));
+    }
+
+    #[test]
+    fn loading_fails_when_a_required_template_is_missing() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("confirmation.html"), "").unwrap();
+        std::fs::write(directory.join("confirmation.txt"), "").unwrap();
+
+        let result = EmailTemplates::from_directory(directory.to_str().unwrap());
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_err!(result);
+    }
+}
@@ -0,0 +1,60 @@
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+
+// ジョブを実行する間隔
+const RUN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// 残しておく必要がなくなった行を定期的に削除する
+// 確認メールの再送の記録は、スロットリングに使わなくなったものから削除する
+// ログインセッションは、有効期限の切れたものを削除する
+pub struct HousekeepingJob {
+    pub resend_window: Duration,
+}
+
+// この関数はアプリケーションが停止したときのみ返される
+pub async fn run_housekeeping_job_until_stopped(
+    pool: PgPool,
+    job: HousekeepingJob,
+) -> Result<(), std::io::Error> {
+    loop {
+        // 失敗しても次の実行で再試行されるため、ログに残すだけにする
+        let _ = job.purge_resend_attempts(&pool).await;
+        let _ = job.purge_expired_sessions(&pool).await;
+        tokio::time::sleep(RUN_INTERVAL).await;
+    }
+}
+
+impl HousekeepingJob {
+    // スロットリングの期間を過ぎた記録は数えられないため、残しておく必要がない
+    #[tracing::instrument(name = "Purge expired confirmation resend attempts", skip_all, err)]
+    pub async fn purge_resend_attempts(&self, pool: &PgPool) -> Result<u64, sqlx::Error> {
+        let n_attempts = sqlx::query!(
+            r#"DELETE FROM confirmation_resend_attempts WHERE attempted_at <= $1"#,
+            Utc::now() - self.resend_window
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        .rows_affected();
+
+        Ok(n_attempts)
+    }
+
+    // ログインに失敗しただけのセッションも保存されるため、期限切れの行を定期的に削除する
+    #[tracing::instrument(name = "Purge expired sessions", skip_all, err)]
+    pub async fn purge_expired_sessions(&self, pool: &PgPool) -> Result<u64, sqlx::Error> {
+        let n_sessions = sqlx::query!(r#"DELETE FROM sessions WHERE expires_at <= $1"#, Utc::now())
+            .execute(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?
+            .rows_affected();
+
+        Ok(n_sessions)
+    }
+}
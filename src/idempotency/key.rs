@@ -0,0 +1,62 @@
+use actix_web::HttpRequest;
+
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    pub fn parse(s: String) -> Result<IdempotencyKey, String> {
+        if s.trim().is_empty() {
+            return Err("The idempotency key cannot be empty.".into());
+        }
+
+        let max_length = 50;
+        if s.len() >= max_length {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters.",
+                max_length
+            ));
+        }
+
+        Ok(Self(s))
+    }
+
+    // Idempotency-Keyヘッダは任意のため、存在しない場合はNoneを返す
+    pub fn from_request(request: &HttpRequest) -> Result<Option<IdempotencyKey>, String> {
+        match request.headers().get("Idempotency-Key") {
+            None => Ok(None),
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| "The idempotency key is not a valid string.".to_string())?;
+                Self::parse(value.to_owned()).map(Some)
+            }
+        }
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(IdempotencyKey::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_key_of_50_characters_is_rejected() {
+        assert_err!(IdempotencyKey::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_valid_key_is_parsed_successfully() {
+        assert_ok!(IdempotencyKey::parse(uuid::Uuid::new_v4().to_string()));
+    }
+}
@@ -0,0 +1,7 @@
+mod key;
+mod persistence;
+mod request;
+
+pub use key::IdempotencyKey;
+pub use persistence::{save_response, try_processing, NextAction};
+pub use request::IdempotentRequest;
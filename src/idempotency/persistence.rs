@@ -0,0 +1,157 @@
+use super::IdempotentRequest;
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::Utc;
+use sqlx::postgres::{PgHasArrayType, PgTypeInfo};
+use sqlx::{Postgres, Transaction};
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+
+impl PgHasArrayType for HeaderPairRecord {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_header_pair")
+    }
+}
+
+pub enum NextAction {
+    StartProcessing,
+    ReturnSavedResponse(HttpResponse),
+    // 同じキーで内容の違うリクエストが送られた場合
+    RejectMismatchedRequest,
+}
+
+// 同じキーの行を挿入中のトランザクションがある場合、INSERTはそのトランザクションが終わるまで待つ
+// そのため、並行したリクエストは最初のリクエストの処理結果を待ってから保存済みのレスポンスを返す
+#[tracing::instrument(name = "Try processing an idempotent request", skip(transaction))]
+pub async fn try_processing(
+    transaction: &mut Transaction<'_, Postgres>,
+    request: &IdempotentRequest,
+) -> Result<NextAction, sqlx::Error> {
+    let n_inserted_rows = sqlx::query!(
+        r#"INSERT INTO idempotency (scope, idempotency_key, request_fingerprint, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT DO NOTHING"#,
+        request.scope(),
+        request.key().as_ref(),
+        request.fingerprint(),
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+
+    if n_inserted_rows > 0 {
+        Ok(NextAction::StartProcessing)
+    } else {
+        match get_saved_response(transaction, request).await? {
+            Some(SavedResponse::Matching(saved_response)) => {
+                Ok(NextAction::ReturnSavedResponse(saved_response))
+            }
+            Some(SavedResponse::Mismatched) => Ok(NextAction::RejectMismatchedRequest),
+            None => {
+                tracing::error!("We expected a saved response, we didn't find it");
+                Err(sqlx::Error::RowNotFound)
+            }
+        }
+    }
+}
+
+enum SavedResponse {
+    Matching(HttpResponse),
+    Mismatched,
+}
+
+#[tracing::instrument(name = "Get saved response", skip(transaction))]
+async fn get_saved_response(
+    transaction: &mut Transaction<'_, Postgres>,
+    request: &IdempotentRequest,
+) -> Result<Option<SavedResponse>, sqlx::Error> {
+    let saved_response = sqlx::query!(
+        r#"SELECT
+            request_fingerprint,
+            response_status_code as "response_status_code!",
+            response_headers as "response_headers!: Vec<HeaderPairRecord>",
+            response_body as "response_body!"
+        FROM idempotency
+        WHERE scope = $1 AND idempotency_key = $2"#,
+        request.scope(),
+        request.key().as_ref()
+    )
+    .fetch_optional(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    if let Some(r) = saved_response {
+        if r.request_fingerprint != request.fingerprint() {
+            return Ok(Some(SavedResponse::Mismatched));
+        }
+        let status_code = StatusCode::from_u16(r.response_status_code.try_into().unwrap())
+            .expect("Invalid status code stored for an idempotency key");
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPairRecord { name, value } in r.response_headers {
+            response.append_header((name, value));
+        }
+        Ok(Some(SavedResponse::Matching(
+            response.body(r.response_body),
+        )))
+    } else {
+        Ok(None)
+    }
+}
+
+// レスポンスを保存し、同じ内容のレスポンスを組み立て直して返す
+#[tracing::instrument(name = "Save response", skip(transaction, http_response))]
+pub async fn save_response(
+    transaction: &mut Transaction<'_, Postgres>,
+    request: &IdempotentRequest,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+    let (response_head, body) = http_response.into_parts();
+    let body = to_bytes(body).await?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = {
+        let mut h = Vec::with_capacity(response_head.headers().len());
+        for (name, value) in response_head.headers().iter() {
+            let name = name.as_str().to_owned();
+            let value = value.as_bytes().to_owned();
+            h.push(HeaderPairRecord { name, value });
+        }
+        h
+    };
+
+    sqlx::query_unchecked!(
+        r#"UPDATE idempotency
+        SET
+            response_status_code = $2,
+            response_headers = $3,
+            response_body = $4
+        WHERE scope = $5 AND idempotency_key = $1"#,
+        request.key().as_ref(),
+        status_code,
+        headers,
+        body.as_ref(),
+        request.scope()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let http_response = response_head.set_body(body).map_into_boxed_body();
+    Ok(http_response)
+}
@@ -0,0 +1,58 @@
+use super::IdempotencyKey;
+use sha2::{Digest, Sha256};
+
+// 同じキーでも、ルートと呼び出し元(スコープ)が違えば別のリクエストとして扱う
+// 同じスコープでキーが使い回された場合は、リクエストの内容(フィンガープリント)で見分ける
+#[derive(Debug)]
+pub struct IdempotentRequest {
+    key: IdempotencyKey,
+    scope: String,
+    fingerprint: String,
+}
+
+impl IdempotentRequest {
+    pub fn new(key: IdempotencyKey, scope: impl Into<String>, fields: &[&str]) -> Self {
+        Self {
+            key,
+            scope: scope.into(),
+            fingerprint: fingerprint(fields),
+        }
+    }
+
+    pub fn key(&self) -> &IdempotencyKey {
+        &self.key
+    }
+
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+// フィールドの境界が曖昧にならないよう、長さを前に付けてからハッシュを取る
+fn fingerprint(fields: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fingerprint;
+
+    #[test]
+    fn the_same_fields_give_the_same_fingerprint() {
+        assert_eq!(fingerprint(&["a", "b"]), fingerprint(&["a", "b"]));
+    }
+
+    #[test]
+    fn moving_a_field_boundary_changes_the_fingerprint() {
+        assert_ne!(fingerprint(&["ab", "c"]), fingerprint(&["a", "bc"]));
+    }
+}
@@ -0,0 +1,420 @@
+use crate::domain::{SubscriberEmail, SubscriptionStatus};
+use crate::email_client::{EmailSender, Message};
+use crate::email_outbox::backoff;
+use crate::email_templates::{DigestEmail, DigestIssue, EmailTemplates, NewsletterEmail};
+use crate::routes::{unsubscribe_link, PreferencesLinkSigner};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
+use uuid::Uuid;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+// この回数だけ送信に失敗した配信タスクは諦めて破棄する
+const MAX_ATTEMPTS: i32 = 10;
+
+struct DeliveryTask {
+    issue_id: Uuid,
+    email: String,
+    n_attempts: i32,
+    digest: bool,
+}
+
+// キューが空になるまで配信タスクを処理し続ける
+// この関数はアプリケーションが停止したときのみ返される
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    email_templates: Arc<EmailTemplates>,
+    base_url: String,
+    preferences_link_signer: PreferencesLinkSigner,
+) -> Result<(), std::io::Error> {
+    loop {
+        match try_execute_task(
+            &pool,
+            email_client.as_ref(),
+            &email_templates,
+            &base_url,
+            &preferences_link_signer,
+        )
+        .await
+        {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+// 送信方法が一括送信に対応している場合は、まとめて確保したタスクを一度に送る
+#[tracing::instrument(skip_all, fields(n_tasks = tracing::field::Empty), err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    preferences_link_signer: &PreferencesLinkSigner,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let tasks = dequeue_tasks(pool, email_client.max_batch_size()).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("n_tasks", &tasks.len());
+
+    // 送信の失敗は宛先ごとに返されるため、1回の一括送信には同じ宛先を一つまでしか含めない
+    // 残りのタスクは確保を解除し、次の一括送信で送る
+    let mut recipients = HashSet::new();
+    let mut batch = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        if recipients.insert(task.email.clone()) {
+            batch.push(task);
+        } else {
+            release_task(pool, &task).await?;
+        }
+    }
+
+    // ダイジェストのタスクは、同じ宛先の配信時刻を過ぎたものをすべて確保して1通にまとめる
+    let mut deliveries = Vec::with_capacity(batch.len());
+    for task in batch {
+        let mut tasks = if task.digest {
+            lease_digest_tasks(pool, &task.email).await?
+        } else {
+            Vec::new()
+        };
+        tasks.insert(0, task);
+        deliveries.push(tasks);
+    }
+
+    let context = DeliveryContext {
+        email_templates,
+        base_url,
+        preferences_link_signer,
+    };
+    let mut issues = HashMap::new();
+    let mut messages = Vec::with_capacity(deliveries.len());
+    for tasks in &deliveries {
+        for task in tasks {
+            if let Entry::Vacant(entry) = issues.entry(task.issue_id) {
+                entry.insert(get_issue(pool, task.issue_id).await?);
+            }
+        }
+        let mut delivered: Vec<&NewsletterIssue> =
+            tasks.iter().map(|task| &issues[&task.issue_id]).collect();
+        delivered.sort_by_key(|issue| issue.published_at);
+        if let Some(message) = prepare_message(pool, &context, &delivered, &tasks[0].email).await? {
+            messages.push(message);
+        }
+    }
+
+    let outcome = email_client.send_batch(messages).await;
+    let failed: HashMap<&str, &str> = outcome
+        .failed
+        .iter()
+        .map(|failed| (failed.recipient.as_ref(), failed.error.as_str()))
+        .collect();
+
+    // 送信できたタスクと、送る必要がないためスキップしたタスクだけを削除する
+    // 送信に失敗したタスクは間隔を空けて再試行し、上限に達したら諦める
+    for task in deliveries.iter().flatten() {
+        match failed.get(task.email.as_str()) {
+            None => delete_task(pool, task).await?,
+            Some(error) => {
+                let n_attempts = task.n_attempts + 1;
+                if n_attempts >= MAX_ATTEMPTS {
+                    tracing::error!(
+                        subscriber_email = %task.email,
+                        "Giving up on delivering an issue after {} failed attempts: {}",
+                        n_attempts,
+                        error
+                    );
+                    delete_task(pool, task).await?;
+                } else {
+                    tracing::warn!(
+                        subscriber_email = %task.email,
+                        "Failed to deliver an issue. Retrying later: {}",
+                        error
+                    );
+                    schedule_retry(pool, task, n_attempts).await?;
+                }
+            }
+        }
+    }
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+struct DeliveryContext<'a> {
+    email_templates: &'a EmailTemplates,
+    base_url: &'a str,
+    preferences_link_signer: &'a PreferencesLinkSigner,
+}
+
+// 購読者ごとのリンクを埋め込んだメッセージを組み立てる
+// 複数の号を渡した場合は、ダイジェストとして1通にまとめる
+// 送る必要がない、あるいは組み立てられないタスクにはNoneを返す
+#[tracing::instrument(skip_all, fields(subscriber_email = %email))]
+async fn prepare_message(
+    pool: &PgPool,
+    context: &DeliveryContext<'_>,
+    issues: &[&NewsletterIssue],
+    email: &str,
+) -> Result<Option<Message>, sqlx::Error> {
+    let recipient = match SubscriberEmail::parse(email.to_owned()) {
+        Ok(recipient) => recipient,
+        Err(e) => {
+            tracing::warn!(
+                "Skipping a confirmed subscriber. Their stored contact details are invalid: {}",
+                e
+            );
+            return Ok(None);
+        }
+    };
+    let subscriber = match get_confirmed_subscriber(pool, email).await? {
+        Some(subscriber) => subscriber,
+        None => {
+            tracing::info!("Skipping a subscriber who is no longer confirmed.");
+            return Ok(None);
+        }
+    };
+
+    let unsubscribe_link = unsubscribe_link(context.base_url, &subscriber.unsubscribe_token);
+    let preferences_link = context
+        .preferences_link_signer
+        .link(context.base_url, subscriber.id);
+    let (subject, rendered) = match issues {
+        [issue] => (
+            issue.title.clone(),
+            context.email_templates.render(&NewsletterEmail {
+                title: &issue.title,
+                html_content: &issue.html_content,
+                text_content: &issue.text_content,
+                unsubscribe_link: &unsubscribe_link,
+                preferences_link: &preferences_link,
+            }),
+        ),
+        _ => (
+            format!("Newsletter digest: {} issues", issues.len()),
+            context.email_templates.render(&DigestEmail {
+                issues: issues
+                    .iter()
+                    .map(|issue| DigestIssue {
+                        title: &issue.title,
+                        html_content: &issue.html_content,
+                        text_content: &issue.text_content,
+                    })
+                    .collect(),
+                unsubscribe_link: &unsubscribe_link,
+                preferences_link: &preferences_link,
+            }),
+        ),
+    };
+    let body = match rendered {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to render the newsletter issue. Skipping: {:?}", e);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(
+        Message::builder(recipient, &subject, &body.html, &body.text)
+            .list_unsubscribe(&unsubscribe_link)
+            .build(),
+    ))
+}
+
+// 送信中にワーカーが落ちた場合は、この時間が過ぎてから別のワーカーが処理し直す
+// 送信の再試行を含めても、これより長くかからないようにしておく
+const LEASE_DURATION_SECONDS: f64 = 600.0;
+
+// タスクは期限付きで確保してすぐにコミットし、送信中にDBの接続やロックを握り続けないようにする
+// 他のワーカーがロック中、あるいは確保中の行はスキップし、同じタスクが二重に処理されないようにする
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(pool: &PgPool, limit: usize) -> Result<Vec<DeliveryTask>, sqlx::Error> {
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"UPDATE issue_delivery_queue
+        SET locked_until = now() + make_interval(secs => $1)
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT newsletter_issue_id, subscriber_email
+            FROM issue_delivery_queue
+            WHERE deliver_after <= now()
+                AND (locked_until IS NULL OR locked_until < now())
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT $2
+        )
+        RETURNING newsletter_issue_id AS issue_id, subscriber_email AS email, n_attempts, digest"#,
+        LEASE_DURATION_SECONDS,
+        limit as i64
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(tasks)
+}
+
+// 同じ宛先のダイジェストのうち、配信時刻を過ぎたタスクを確保する
+// 呼び出し元が確保済みのタスクは含まない
+#[tracing::instrument(skip_all)]
+async fn lease_digest_tasks(pool: &PgPool, email: &str) -> Result<Vec<DeliveryTask>, sqlx::Error> {
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"UPDATE issue_delivery_queue
+        SET locked_until = now() + make_interval(secs => $1)
+        WHERE (newsletter_issue_id, subscriber_email) IN (
+            SELECT newsletter_issue_id, subscriber_email
+            FROM issue_delivery_queue
+            WHERE subscriber_email = $2
+                AND digest
+                AND deliver_after <= now()
+                AND (locked_until IS NULL OR locked_until < now())
+            FOR UPDATE
+            SKIP LOCKED
+        )
+        RETURNING newsletter_issue_id AS issue_id, subscriber_email AS email, n_attempts, digest"#,
+        LEASE_DURATION_SECONDS,
+        email
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(tasks)
+}
+
+#[tracing::instrument(skip_all)]
+async fn release_task(pool: &PgPool, task: &DeliveryTask) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET locked_until = NULL
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2"#,
+        task.issue_id,
+        task.email
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+// 確保を解除し、待ち時間が過ぎるまで他のワーカーにも処理させない
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    pool: &PgPool,
+    task: &DeliveryTask,
+    n_attempts: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET locked_until = NULL, n_attempts = $3, deliver_after = $4
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2"#,
+        task.issue_id,
+        task.email,
+        n_attempts,
+        Utc::now() + backoff(n_attempts)
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(pool: &PgPool, task: &DeliveryTask) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2"#,
+        task.issue_id,
+        task.email
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+struct ConfirmedSubscriber {
+    id: Uuid,
+    unsubscribe_token: String,
+}
+
+// 配信までの間に購読を解除したsubscriberにはNoneを返す
+#[tracing::instrument(skip_all)]
+async fn get_confirmed_subscriber(
+    pool: &PgPool,
+    email: &str,
+) -> Result<Option<ConfirmedSubscriber>, sqlx::Error> {
+    let subscriber = sqlx::query_as!(
+        ConfirmedSubscriber,
+        r#"SELECT id, unsubscribe_token
+        FROM subscriptions
+        WHERE email = $1 AND status = $2"#,
+        email,
+        SubscriptionStatus::Confirmed.as_str()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(subscriber)
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+    published_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, sqlx::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"SELECT title, text_content, html_content, published_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1"#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(issue)
+}
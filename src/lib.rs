@@ -1,6 +1,15 @@
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod email_outbox;
+pub mod email_templates;
+pub mod housekeeping;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod pending_subscribers;
 pub mod routes;
+pub mod session;
 pub mod startup;
+pub mod subscription_tokens;
 pub mod telemetry;
@@ -0,0 +1,201 @@
+use crate::domain::{SubscriberEmail, SubscriptionStatus};
+use crate::email_outbox::enqueue_email;
+use crate::email_templates::{ConfirmationReminderEmail, EmailTemplates};
+use crate::routes::{
+    change_subscription_status, confirmation_link, purge_deleted_subscribers, store_token,
+    unsubscribe_link, StatusChange,
+};
+use crate::subscription_tokens::SubscriptionTokenHasher;
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// ジョブを実行する間隔
+const RUN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// 確認されないままのsubscriberに一度だけリマインダーを送り、保持期間を過ぎたら削除する
+pub struct PendingSubscriberJob {
+    pub reminder_delay: Duration,
+    pub retention: Duration,
+    pub base_url: String,
+    pub token_ttl: Duration,
+    pub token_hasher: SubscriptionTokenHasher,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PurgeOutcome {
+    pub n_subscribers: u64,
+    pub n_tokens: u64,
+}
+
+// この関数はアプリケーションが停止したときのみ返される
+pub async fn run_pending_subscriber_job_until_stopped(
+    pool: PgPool,
+    email_templates: Arc<EmailTemplates>,
+    job: PendingSubscriberJob,
+) -> Result<(), std::io::Error> {
+    loop {
+        // 失敗しても次の実行で再試行されるため、ログに残すだけにする
+        let _ = job.send_reminders(&pool, &email_templates).await;
+        let _ = job.purge(&pool).await;
+        tokio::time::sleep(RUN_INTERVAL).await;
+    }
+}
+
+struct PendingSubscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    unsubscribe_token: String,
+}
+
+impl PendingSubscriberJob {
+    // 確認メールのトークンはハッシュ化されていて再送できないため、新しいトークンを発行する
+    #[tracing::instrument(name = "Send confirmation reminders", skip_all, err)]
+    pub async fn send_reminders(
+        &self,
+        pool: &PgPool,
+        email_templates: &EmailTemplates,
+    ) -> Result<usize, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        // 他のインスタンスが処理中の行はスキップし、リマインダーが二重に送られないようにする
+        let subscribers = sqlx::query_as!(
+            PendingSubscriber,
+            r#"SELECT id, email, name, unsubscribe_token
+            FROM subscriptions
+            WHERE status = $2
+                AND reminder_sent_at IS NULL
+                AND subscribed_at <= $1
+            FOR UPDATE
+            SKIP LOCKED"#,
+            Utc::now() - self.reminder_delay,
+            SubscriptionStatus::PendingConfirmation.as_str()
+        )
+        .fetch_all(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+
+        let mut n_sent = 0;
+        for subscriber in &subscribers {
+            let email = match SubscriberEmail::parse(subscriber.email.clone()) {
+                Ok(email) => email,
+                Err(e) => {
+                    tracing::warn!("Skipping a reminder to an invalid address: {}", e);
+                    continue;
+                }
+            };
+
+            let subscription_token = store_token(
+                &mut transaction,
+                &self.token_hasher,
+                subscriber.id,
+                self.token_ttl,
+            )
+            .await?;
+
+            let body = match email_templates.render(&ConfirmationReminderEmail {
+                subscriber_name: &subscriber.name,
+                confirmation_link: &confirmation_link(&self.base_url, &subscription_token),
+            }) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!("Failed to render the confirmation reminder: {:?}", e);
+                    continue;
+                }
+            };
+            enqueue_email(
+                &mut transaction,
+                &email,
+                "Please confirm your subscription",
+                &body.html,
+                &body.text,
+                Some(&unsubscribe_link(
+                    &self.base_url,
+                    &subscriber.unsubscribe_token,
+                )),
+            )
+            .await?;
+
+            n_sent += 1;
+        }
+
+        // 送れなかった行も含めて記録し、次回以降に繰り返し処理しないようにする
+        let ids: Vec<Uuid> = subscribers.iter().map(|s| s.id).collect();
+        sqlx::query!(
+            r#"UPDATE subscriptions SET reminder_sent_at = $2 WHERE id = ANY($1)"#,
+            &ids,
+            Utc::now()
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+
+        transaction.commit().await?;
+
+        tracing::info!(
+            pending_subscribers.reminders_sent = n_sent,
+            "Sent {} confirmation reminders",
+            n_sent
+        );
+
+        Ok(n_sent)
+    }
+
+    #[tracing::instrument(name = "Purge stale pending subscribers", skip_all, err)]
+    pub async fn purge(&self, pool: &PgPool) -> Result<PurgeOutcome, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        let ids: Vec<Uuid> = sqlx::query!(
+            r#"SELECT id
+            FROM subscriptions
+            WHERE status = $2 AND subscribed_at <= $1
+            FOR UPDATE
+            SKIP LOCKED"#,
+            Utc::now() - self.retention,
+            SubscriptionStatus::PendingConfirmation.as_str()
+        )
+        .fetch_all(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        .into_iter()
+        .map(|r| r.id)
+        .collect();
+
+        // 状態の遷移を経てから行ごと消す
+        let mut deleted_ids = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let StatusChange::Applied =
+                change_subscription_status(&mut transaction, id, SubscriptionStatus::Deleted)
+                    .await?
+            {
+                deleted_ids.push(id);
+            }
+        }
+        let purged = purge_deleted_subscribers(&mut transaction, &deleted_ids).await?;
+
+        transaction.commit().await?;
+
+        tracing::info!(
+            pending_subscribers.purged_subscribers = purged.n_subscribers,
+            pending_subscribers.purged_tokens = purged.n_tokens,
+            "Purged {} stale pending subscribers",
+            purged.n_subscribers
+        );
+
+        Ok(PurgeOutcome {
+            n_subscribers: purged.n_subscribers,
+            n_tokens: purged.n_tokens,
+        })
+    }
+}
@@ -0,0 +1,68 @@
+use crate::authentication::AuthenticatedAdmin;
+use crate::routes::{count_subscribers_by_status, html_response};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+#[tracing::instrument(name = "Show the admin dashboard", skip(admin, pool), fields(username = %admin.username))]
+pub async fn admin_dashboard(admin: AuthenticatedAdmin, pool: web::Data<PgPool>) -> HttpResponse {
+    let counts = match count_subscribers_by_status(&pool).await {
+        Ok(counts) => counts,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let rows: String = counts
+        .iter()
+        .map(|c| {
+            format!(
+                "<tr><td><a href=\"/admin/subscribers?status={0}\">{0}</a></td><td>{1}</td></tr>\n",
+                c.status, c.count
+            )
+        })
+        .collect();
+    let total: i64 = counts.iter().map(|c| c.count).sum();
+
+    html_response(
+        StatusCode::OK,
+        admin_page(
+            "Admin dashboard",
+            &format!(
+                r#"<p>Welcome {}!</p>
+    <h2>Subscribers</h2>
+    <table>
+        <tr><th>Status</th><th>Count</th></tr>
+        {}<tr><th>Total</th><th>{}</th></tr>
+    </table>"#,
+                htmlescape::encode_minimal(&admin.username),
+                rows,
+                total
+            ),
+        ),
+    )
+}
+
+// 管理画面の各ページで共通のレイアウト
+// bodyはエスケープ済みのHTMLを渡す
+pub(crate) fn admin_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{0}</title>
+</head>
+<body>
+    <nav>
+        <a href="/admin/dashboard">Dashboard</a>
+        <a href="/admin/subscribers">Subscribers</a>
+        <a href="/admin/newsletters">Write an issue</a>
+        <form action="/logout" method="post">
+            <button type="submit">Logout</button>
+        </form>
+    </nav>
+    <h1>{0}</h1>
+    {1}
+</body>
+</html>"#,
+        title, body
+    )
+}
@@ -0,0 +1,234 @@
+use crate::authentication::AuthenticatedAdmin;
+use crate::domain::{Topic, TOPICS};
+use crate::idempotency::{IdempotencyKey, IdempotentRequest};
+use crate::routes::{
+    admin_page, flash_messages_html, html_response, publish_issue, see_other, PublishOutcome,
+};
+use crate::session::{FlashMessage, Session};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct NewsletterFormData {
+    title: String,
+    text_content: String,
+    html_content: String,
+    // 空の場合は、すべての購読者に配信する
+    #[serde(default)]
+    topic: String,
+    idempotency_key: String,
+}
+
+impl NewsletterFormData {
+    // 二重送信を防ぐため、フォームを表示するたびに新しいIdempotency-Keyを埋め込む
+    fn empty() -> Self {
+        Self {
+            title: String::new(),
+            text_content: String::new(),
+            html_content: String::new(),
+            topic: String::new(),
+            idempotency_key: Uuid::new_v4().to_string(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if [&self.title, &self.text_content, &self.html_content]
+            .iter()
+            .any(|s| s.trim().is_empty())
+        {
+            return Err("The title and both contents are required.".into());
+        }
+        self.topic()?;
+        Ok(())
+    }
+
+    fn topic(&self) -> Result<Option<Topic>, String> {
+        if self.topic.is_empty() {
+            return Ok(None);
+        }
+        Topic::parse(self.topic.clone()).map(Some)
+    }
+}
+
+#[tracing::instrument(name = "Show the newsletter form", skip(admin, session), fields(username = %admin.username))]
+pub async fn admin_newsletter_form(admin: AuthenticatedAdmin, session: Session) -> HttpResponse {
+    let flash_messages = match session.take_flash_messages() {
+        Ok(flash_messages) => flash_messages,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    html_response(
+        StatusCode::OK,
+        admin_page(
+            "Write an issue",
+            &format!(
+                "{}{}",
+                flash_messages_html(&flash_messages),
+                newsletter_form(&NewsletterFormData::empty())
+            ),
+        ),
+    )
+}
+
+// 配信する前に、編集中の内容を確認する
+// HTML版はページのスタイルやスクリプトの影響を受けないよう、sandbox付きのiframeに表示する
+#[tracing::instrument(name = "Preview a newsletter issue", skip(admin, form), fields(username = %admin.username))]
+pub async fn admin_preview_newsletter(
+    admin: AuthenticatedAdmin,
+    form: web::Form<NewsletterFormData>,
+) -> HttpResponse {
+    let form = form.0;
+    if let Err(e) = form.validate() {
+        return invalid_form(&form, &e);
+    }
+
+    html_response(
+        StatusCode::OK,
+        admin_page(
+            "Preview",
+            &format!(
+                r#"<h2>{}</h2>
+    <h3>HTML</h3>
+    <iframe sandbox srcdoc="{}"></iframe>
+    <h3>Plain text</h3>
+    <pre>{}</pre>
+    {}"#,
+                htmlescape::encode_minimal(&form.title),
+                htmlescape::encode_attribute(&form.html_content),
+                htmlescape::encode_minimal(&form.text_content),
+                newsletter_form(&form)
+            ),
+        ),
+    )
+}
+
+#[tracing::instrument(
+    name = "Publish a newsletter issue from the admin area",
+    skip(admin, form, pool, session),
+    fields(title = %form.title, username = %admin.username)
+)]
+pub async fn admin_publish_newsletter(
+    admin: AuthenticatedAdmin,
+    form: web::Form<NewsletterFormData>,
+    pool: web::Data<PgPool>,
+    session: Session,
+) -> HttpResponse {
+    let form = form.0;
+    if let Err(e) = form.validate() {
+        return invalid_form(&form, &e);
+    }
+    let topic = match form.topic() {
+        Ok(topic) => topic,
+        Err(e) => return invalid_form(&form, &e),
+    };
+    let idempotency_key = match IdempotencyKey::parse(form.idempotency_key) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    let idempotent_request = IdempotentRequest::new(
+        idempotency_key,
+        format!("POST /admin/newsletters admin:{}", admin.user_id),
+        &[
+            &form.title,
+            &form.text_content,
+            &form.html_content,
+            &form.topic,
+        ],
+    );
+
+    let outcome = match publish_issue(
+        &pool,
+        Some(&idempotent_request),
+        &form.title,
+        &form.text_content,
+        &form.html_content,
+        topic.as_ref(),
+        see_other("/admin/newsletters"),
+    )
+    .await
+    {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            tracing::error!("Failed to publish the newsletter issue: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // 発行を知らせるのは、この呼び出しで発行した場合だけにする
+    let (flash_message, response) = match outcome {
+        PublishOutcome::Published(response) => (
+            FlashMessage::info("The newsletter issue has been published!"),
+            response,
+        ),
+        PublishOutcome::Replayed(response) => (
+            FlashMessage::error("This newsletter issue has already been published."),
+            response,
+        ),
+        PublishOutcome::RejectedMismatchedRequest => (
+            FlashMessage::error(
+                "The form was already submitted with different content. \
+                Please write the issue again.",
+            ),
+            see_other("/admin/newsletters"),
+        ),
+    };
+    if session.add_flash_message(flash_message).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    response
+}
+
+fn invalid_form(form: &NewsletterFormData, error: &str) -> HttpResponse {
+    html_response(
+        StatusCode::BAD_REQUEST,
+        admin_page(
+            "Write an issue",
+            &format!(
+                "<p class=\"error\"><i>{}</i></p>\n{}",
+                htmlescape::encode_minimal(error),
+                newsletter_form(form)
+            ),
+        ),
+    )
+}
+
+// プレビューと配信は同じフォームから送信する
+fn newsletter_form(form: &NewsletterFormData) -> String {
+    let topic_options: String = std::iter::once(("", "All subscribers"))
+        .chain(TOPICS.iter().map(|t| (*t, *t)))
+        .map(|(value, label)| {
+            let selected = if form.topic == value { " selected" } else { "" };
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                value, selected, label
+            )
+        })
+        .collect();
+    format!(
+        r#"<form action="/admin/newsletters" method="post">
+        <label>Title
+            <input type="text" name="title" value="{}">
+        </label>
+        <label>Plain text content
+            <textarea name="text_content" rows="20" cols="50">{}</textarea>
+        </label>
+        <label>HTML content
+            <textarea name="html_content" rows="20" cols="50">{}</textarea>
+        </label>
+        <label>Topic
+            <select name="topic">{}</select>
+        </label>
+        <input hidden type="text" name="idempotency_key" value="{}">
+        <button type="submit" formaction="/admin/newsletters/preview">Preview</button>
+        <button type="submit">Publish</button>
+    </form>"#,
+        htmlescape::encode_attribute(&form.title),
+        htmlescape::encode_minimal(&form.text_content),
+        htmlescape::encode_minimal(&form.html_content),
+        topic_options,
+        htmlescape::encode_attribute(&form.idempotency_key)
+    )
+}
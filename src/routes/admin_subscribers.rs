@@ -0,0 +1,125 @@
+use crate::authentication::AuthenticatedAdmin;
+use crate::domain::SubscriptionStatus;
+use crate::routes::{
+    admin_page, get_subscriber_page, html_response, SubscriberFilter, SubscriberPage,
+    SubscribersQuery,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sqlx::PgPool;
+
+// ページ送りのリンクで、絞り込み条件を引き継ぐためのクエリ文字列
+#[derive(Serialize)]
+struct PageLinkQuery<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    search: Option<&'a str>,
+    page: i64,
+}
+
+#[tracing::instrument(name = "Show the subscriber list", skip(admin, query, pool), fields(username = %admin.username))]
+pub async fn admin_subscribers(
+    admin: AuthenticatedAdmin,
+    query: web::Query<SubscribersQuery>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let filter = match SubscriberFilter::parse(query.0) {
+        Ok(filter) => filter,
+        Err(e) => {
+            return html_response(
+                StatusCode::BAD_REQUEST,
+                admin_page(
+                    "Subscribers",
+                    &format!("<p>{}</p>", htmlescape::encode_minimal(&e)),
+                ),
+            )
+        }
+    };
+    let page = match get_subscriber_page(&pool, &filter).await {
+        Ok(page) => page,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    html_response(
+        StatusCode::OK,
+        admin_page("Subscribers", &subscribers_body(&filter, &page)),
+    )
+}
+
+fn subscribers_body(filter: &SubscriberFilter, page: &SubscriberPage) -> String {
+    let search = filter.search.as_deref().unwrap_or("");
+    let status_options: String = SubscriptionStatus::ALL
+        .iter()
+        .map(|status| {
+            let selected = if filter.status == Some(*status) {
+                " selected"
+            } else {
+                ""
+            };
+            format!(
+                "<option value=\"{0}\"{1}>{0}</option>",
+                status.as_str(),
+                selected
+            )
+        })
+        .collect();
+    let rows: String = page
+        .subscribers
+        .iter()
+        .map(|s| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                htmlescape::encode_minimal(&s.email),
+                htmlescape::encode_minimal(&s.name),
+                s.status,
+                s.subscribed_at.format("%Y-%m-%d %H:%M")
+            )
+        })
+        .collect();
+
+    let page_link = |page_number: i64, label: &str| {
+        let query = serde_urlencoded::to_string(PageLinkQuery {
+            status: filter.status.map(|s| s.as_str()),
+            search: filter.search.as_deref(),
+            page: page_number,
+        })
+        .expect("Failed to serialize the page link.");
+        format!(
+            "<a href=\"/admin/subscribers?{}\">{}</a>",
+            htmlescape::encode_attribute(&query),
+            label
+        )
+    };
+    let mut pagination = Vec::new();
+    if page.page > 1 {
+        pagination.push(page_link(page.page - 1, "Previous"));
+    }
+    pagination.push(format!("Page {} of {}", page.page, page.n_pages()));
+    if page.page < page.n_pages() {
+        pagination.push(page_link(page.page + 1, "Next"));
+    }
+
+    format!(
+        r#"<form action="/admin/subscribers" method="get">
+        <input type="search" name="search" placeholder="Email or name" value="{}">
+        <select name="status">
+            <option value="">all statuses</option>
+            {}
+        </select>
+        <button type="submit">Search</button>
+    </form>
+    <p>{} subscribers found</p>
+    <table>
+        <tr><th>Email</th><th>Name</th><th>Status</th><th>Subscribed at</th></tr>
+        {}
+    </table>
+    <p>{}</p>"#,
+        htmlescape::encode_attribute(search),
+        status_options,
+        page.total,
+        rows,
+        pagination.join(" ")
+    )
+}
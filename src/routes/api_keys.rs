@@ -0,0 +1,68 @@
+use crate::authentication::{
+    get_api_keys, mark_api_key_as_revoked, store_api_key, ApiScope, AuthenticatedAdmin,
+};
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct NewApiKeyData {
+    name: String,
+    scopes: Vec<String>,
+}
+
+// 作成したキーはこのレスポンスでしか返さないため、呼び出し側で保管してもらう
+#[tracing::instrument(
+    name = "Create an API key",
+    skip(admin, body, pool),
+    fields(name = %body.name, username = %admin.username)
+)]
+pub async fn create_api_key(
+    admin: AuthenticatedAdmin,
+    body: web::Json<NewApiKeyData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let body = body.0;
+    if body.name.trim().is_empty() || body.scopes.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let scopes = match body
+        .scopes
+        .into_iter()
+        .map(ApiScope::parse)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(scopes) => scopes,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    match store_api_key(&pool, &body.name, &scopes, admin.user_id).await {
+        Ok((api_key_id, api_key)) => HttpResponse::Ok().json(serde_json::json!({
+            "api_key_id": api_key_id,
+            "api_key": api_key,
+        })),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "List API keys", skip(admin, pool), fields(username = %admin.username))]
+pub async fn list_api_keys(admin: AuthenticatedAdmin, pool: web::Data<PgPool>) -> HttpResponse {
+    match get_api_keys(&pool).await {
+        Ok(api_keys) => HttpResponse::Ok().json(api_keys),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Revoke an API key", skip(admin, pool), fields(username = %admin.username))]
+pub async fn revoke_api_key(
+    admin: AuthenticatedAdmin,
+    api_key_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    match mark_api_key_as_revoked(&pool, *api_key_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
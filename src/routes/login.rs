@@ -0,0 +1,123 @@
+use crate::authentication::{log_in, log_out, validate_credentials, AuthError, Credentials};
+use crate::routes::html_response;
+use crate::session::{FlashLevel, FlashMessage, Session};
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+#[derive(Deserialize)]
+pub struct LoginFormData {
+    username: String,
+    password: String,
+}
+
+#[tracing::instrument(name = "Show the login form", skip(session))]
+pub async fn login_form(session: Session) -> HttpResponse {
+    let flash_messages = match session.take_flash_messages() {
+        Ok(flash_messages) => flash_messages,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    html_response(
+        StatusCode::OK,
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Login</title>
+</head>
+<body>
+    {}
+    <form action="/login" method="post">
+        <label>Username
+            <input type="text" placeholder="Enter Username" name="username">
+        </label>
+        <label>Password
+            <input type="password" placeholder="Enter Password" name="password">
+        </label>
+        <button type="submit">Login</button>
+    </form>
+</body>
+</html>"#,
+            flash_messages_html(&flash_messages)
+        ),
+    )
+}
+
+// 失敗した理由はフラッシュメッセージで次のリクエストのログインページに表示する
+#[tracing::instrument(
+    name = "Log in",
+    skip(form, pool, session),
+    fields(username = %form.username)
+)]
+pub async fn login(
+    form: web::Form<LoginFormData>,
+    pool: web::Data<PgPool>,
+    session: Session,
+) -> HttpResponse {
+    let form = form.0;
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            if log_in(&session, user_id).is_err() {
+                return HttpResponse::InternalServerError().finish();
+            }
+            see_other("/admin/dashboard")
+        }
+        Err(AuthError::InvalidCredentials) => {
+            if session
+                .add_flash_message(FlashMessage::error("Invalid credentials."))
+                .is_err()
+            {
+                return HttpResponse::InternalServerError().finish();
+            }
+            see_other("/login")
+        }
+        Err(e) => {
+            tracing::error!("{}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[tracing::instrument(name = "Log out", skip(session))]
+pub async fn logout(session: Session) -> HttpResponse {
+    log_out(&session);
+    if session
+        .add_flash_message(FlashMessage::info("You have successfully logged out."))
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    see_other("/login")
+}
+
+// フラッシュメッセージは利用者の入力を含みうるため、エスケープして表示する
+pub(crate) fn flash_messages_html(flash_messages: &[FlashMessage]) -> String {
+    flash_messages
+        .iter()
+        .map(|m| {
+            let class = match m.level {
+                FlashLevel::Info => "info",
+                FlashLevel::Error => "error",
+            };
+            format!(
+                "<p class=\"{}\"><i>{}</i></p>\n",
+                class,
+                htmlescape::encode_minimal(&m.content)
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
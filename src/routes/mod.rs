@@ -1,7 +1,27 @@
+mod admin_dashboard;
+mod admin_newsletters;
+mod admin_subscribers;
+mod api_keys;
 mod health_check;
+mod login;
+mod newsletters;
+mod subscribers;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_resend;
+mod subscriptions_unsubscribe;
 
+pub use admin_dashboard::*;
+pub use admin_newsletters::*;
+pub use admin_subscribers::*;
+pub use api_keys::*;
 pub use health_check::*;
+pub use login::*;
+pub use newsletters::*;
+pub use subscribers::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_preferences::*;
+pub use subscriptions_resend::*;
+pub use subscriptions_unsubscribe::*;
@@ -0,0 +1,212 @@
+use crate::authentication::ApiCaller;
+use crate::domain::{DeliveryMode, SubscriptionStatus, Topic};
+use crate::idempotency::{
+    save_response, try_processing, IdempotencyKey, IdempotentRequest, NextAction,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    // 指定しない場合は、すべての購読者に配信する
+    topic: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+// 配信はキューに積むだけで、実際の送信はissue_delivery_workerが行う
+// 発行の権限を持つ管理者か、issues:publishのスコープを持つAPIキーだけが発行できる
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(request, body, pool, caller),
+    fields(title = %body.title, caller = %caller)
+)]
+pub async fn publish_newsletter(
+    caller: ApiCaller,
+    request: HttpRequest,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let idempotency_key = match IdempotencyKey::from_request(&request) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    let topic = match body.topic.clone().map(Topic::parse).transpose() {
+        Ok(topic) => topic,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    let idempotent_request = idempotency_key.map(|key| {
+        IdempotentRequest::new(
+            key,
+            format!("POST /newsletters {}", caller.id()),
+            &[
+                &body.title,
+                &body.content.text,
+                &body.content.html,
+                body.topic.as_deref().unwrap_or_default(),
+            ],
+        )
+    });
+
+    match publish_issue(
+        &pool,
+        idempotent_request.as_ref(),
+        &body.title,
+        &body.content.text,
+        &body.content.html,
+        topic.as_ref(),
+        HttpResponse::Ok().finish(),
+    )
+    .await
+    {
+        Ok(outcome) => outcome.into_response(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// 号の発行結果
+// 管理画面では、この呼び出しで発行したかどうかで表示するメッセージを変える
+pub enum PublishOutcome {
+    Published(HttpResponse),
+    // 同じIdempotency-Keyで処理済みだったため、保存済みのレスポンスを返す
+    Replayed(HttpResponse),
+    // 同じキーで内容の違うリクエストが送られた
+    RejectedMismatchedRequest,
+}
+
+impl PublishOutcome {
+    pub fn into_response(self) -> HttpResponse {
+        match self {
+            Self::Published(response) | Self::Replayed(response) => response,
+            Self::RejectedMismatchedRequest => HttpResponse::UnprocessableEntity().finish(),
+        }
+    }
+}
+
+// 号の保存と配信タスクの登録を一つのトランザクションで行う(APIと管理画面で共通)
+// 同じIdempotency-Keyで処理済みの場合は、保存済みのレスポンスを返す
+#[tracing::instrument(name = "Publish an issue", skip_all)]
+pub async fn publish_issue(
+    pool: &PgPool,
+    idempotent_request: Option<&IdempotentRequest>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    topic: Option<&Topic>,
+    response: HttpResponse,
+) -> Result<PublishOutcome, Box<dyn std::error::Error>> {
+    let mut transaction = pool.begin().await?;
+
+    if let Some(idempotent_request) = idempotent_request {
+        match try_processing(&mut transaction, idempotent_request).await? {
+            NextAction::StartProcessing => {}
+            NextAction::ReturnSavedResponse(saved_response) => {
+                return Ok(PublishOutcome::Replayed(saved_response))
+            }
+            NextAction::RejectMismatchedRequest => {
+                return Ok(PublishOutcome::RejectedMismatchedRequest)
+            }
+        }
+    }
+
+    let issue_id =
+        insert_newsletter_issue(&mut transaction, title, text_content, html_content, topic).await?;
+    enqueue_delivery_tasks(&mut transaction, issue_id, topic).await?;
+
+    let response = match idempotent_request {
+        Some(idempotent_request) => {
+            save_response(&mut transaction, idempotent_request, response).await?
+        }
+        None => response,
+    };
+
+    transaction.commit().await?;
+
+    Ok(PublishOutcome::Published(response))
+}
+
+#[tracing::instrument(name = "Saving newsletter issue details in the database", skip_all)]
+pub async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    topic: Option<&Topic>,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+
+    sqlx::query!(
+        r#"INSERT INTO newsletter_issues (
+            newsletter_issue_id, title, text_content, html_content, topic, published_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        topic.map(|t| t.as_ref()),
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(newsletter_issue_id)
+}
+
+// 購読者の設定に従って配信先を絞り込む
+// 設定を保存したことがない購読者は、すべてのトピックを都度受け取る
+// まとめて受け取る購読者のタスクは翌日(UTC)の始めまで待たせ、
+// ワーカーがその日に発行された号を1通のダイジェストにまとめて送る
+#[tracing::instrument(
+    name = "Enqueue delivery tasks for confirmed subscribers",
+    skip(transaction)
+)]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    topic: Option<&Topic>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO issue_delivery_queue (
+            newsletter_issue_id, subscriber_email, deliver_after, digest
+        )
+        SELECT
+            $1,
+            s.email,
+            CASE WHEN p.delivery_mode = $4
+                THEN date_trunc('day', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'
+                    + interval '1 day'
+                ELSE now()
+            END,
+            COALESCE(p.delivery_mode = $4, false)
+        FROM subscriptions s
+        LEFT JOIN subscriber_preferences p ON p.subscriber_id = s.id
+        WHERE s.status = $2
+            AND ($3::text IS NULL OR p.topics IS NULL OR $3 = ANY(p.topics))"#,
+        newsletter_issue_id,
+        SubscriptionStatus::Confirmed.as_str(),
+        topic.map(|t| t.as_ref()),
+        DeliveryMode::Digest.as_str()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use crate::authentication::ApiCaller;
+use crate::configuration::ResendConfirmationSettings;
+use crate::domain::{NewSubscriber, SubscriptionStatus};
+use crate::email_templates::EmailTemplates;
+use crate::idempotency::{IdempotencyKey, IdempotentRequest};
+use crate::routes::{
+    change_subscription_status, purge_deleted_subscribers, register_subscriber, FormData,
+    StatusChange,
+};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::subscription_tokens::SubscriptionTokenHasher;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const SUBSCRIBERS_PER_PAGE: i64 = 20;
+
+// 一覧の絞り込み条件(JSON APIと管理画面のクエリ文字列で共通)
+#[derive(Debug, Default, Deserialize)]
+pub struct SubscribersQuery {
+    status: Option<String>,
+    search: Option<String>,
+    page: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SubscriberFilter {
+    pub status: Option<SubscriptionStatus>,
+    pub search: Option<String>,
+    pub page: i64,
+}
+
+impl SubscriberFilter {
+    // 空の値は指定されていないものとして扱う
+    pub fn parse(query: SubscribersQuery) -> Result<SubscriberFilter, String> {
+        let status = match query.status.filter(|s| !s.is_empty()) {
+            Some(status) => Some(SubscriptionStatus::parse(status)?),
+            None => None,
+        };
+        let search = query
+            .search
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        let page = query.page.unwrap_or(1);
+        if page < 1 {
+            return Err(format!("{} is not a valid page number.", page));
+        }
+
+        Ok(SubscriberFilter {
+            status,
+            search,
+            page,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriberSummary {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriberPage {
+    pub subscribers: Vec<SubscriberSummary>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+impl SubscriberPage {
+    pub fn n_pages(&self) -> i64 {
+        ((self.total + self.per_page - 1) / self.per_page).max(1)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusCount {
+    pub status: String,
+    pub count: i64,
+}
+
+#[tracing::instrument(name = "List subscribers", skip(caller, query, pool), fields(caller = %caller))]
+pub async fn list_subscribers(
+    caller: ApiCaller,
+    query: web::Query<SubscribersQuery>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let filter = match SubscriberFilter::parse(query.0) {
+        Ok(filter) => filter,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    match get_subscriber_page(&pool, &filter).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Count subscribers by status", skip(caller, pool), fields(caller = %caller))]
+pub async fn subscriber_counts(caller: ApiCaller, pool: web::Data<PgPool>) -> HttpResponse {
+    match count_subscribers_by_status(&pool).await {
+        Ok(counts) => HttpResponse::Ok().json(counts),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// 本人の同意を確認するため、APIから追加した場合も確認メールを送り、確認待ちとして登録する
+// 登録済みのアドレスかどうかを返さないよう、フォームからの登録と同じ処理を通す
+#[tracing::instrument(
+    name = "Create a subscriber",
+    skip(
+        caller,
+        request,
+        body,
+        pool,
+        email_templates,
+        base_url,
+        token_ttl,
+        token_hasher,
+        throttle_settings
+    ),
+    fields(caller = %caller, subscriber_email = %body.email)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_subscriber(
+    caller: ApiCaller,
+    request: HttpRequest,
+    body: web::Json<FormData>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    token_hasher: web::Data<SubscriptionTokenHasher>,
+    throttle_settings: web::Data<ResendConfirmationSettings>,
+) -> HttpResponse {
+    let idempotency_key = match IdempotencyKey::from_request(&request) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    let new_subscriber: NewSubscriber = match body.0.try_into() {
+        Ok(new_subscriber) => new_subscriber,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    let idempotent_request = idempotency_key.map(|key| {
+        IdempotentRequest::new(
+            key,
+            format!("POST /subscribers {}", caller.id()),
+            &[new_subscriber.email.as_ref(), new_subscriber.name.as_ref()],
+        )
+    });
+
+    register_subscriber(
+        &pool,
+        &email_templates,
+        &base_url.0,
+        token_ttl.0,
+        &token_hasher,
+        &throttle_settings,
+        new_subscriber,
+        idempotent_request,
+    )
+    .await
+}
+
+// 削除済みにしてから同じトランザクションで行ごと消し、個人情報を残さない
+#[tracing::instrument(name = "Delete a subscriber", skip(caller, pool), fields(caller = %caller))]
+pub async fn delete_subscriber(
+    caller: ApiCaller,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    match change_subscription_status(
+        &mut transaction,
+        *subscriber_id,
+        SubscriptionStatus::Deleted,
+    )
+    .await
+    {
+        Ok(StatusChange::Applied) => {}
+        Ok(StatusChange::Rejected(_)) => return HttpResponse::InternalServerError().finish(),
+        Err(sqlx::Error::RowNotFound) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    if purge_deleted_subscribers(&mut transaction, &[*subscriber_id])
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+// 検索はメールアドレスと名前の部分一致(大文字小文字を区別しない)
+#[tracing::instrument(name = "Get a page of subscribers", skip(pool))]
+pub async fn get_subscriber_page(
+    pool: &PgPool,
+    filter: &SubscriberFilter,
+) -> Result<SubscriberPage, sqlx::Error> {
+    let status = filter.status.map(|s| s.as_str());
+    let pattern = filter
+        .search
+        .as_ref()
+        .map(|s| format!("%{}%", escape_like_pattern(s)));
+
+    let total = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM subscriptions
+        WHERE ($1::text IS NULL OR status = $1)
+        AND ($2::text IS NULL OR email ILIKE $2 OR name ILIKE $2)"#,
+        status,
+        pattern
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .count;
+
+    let subscribers = sqlx::query_as!(
+        SubscriberSummary,
+        r#"SELECT id, email, name, status, subscribed_at FROM subscriptions
+        WHERE ($1::text IS NULL OR status = $1)
+        AND ($2::text IS NULL OR email ILIKE $2 OR name ILIKE $2)
+        ORDER BY subscribed_at DESC, id
+        LIMIT $3 OFFSET $4"#,
+        status,
+        pattern,
+        SUBSCRIBERS_PER_PAGE,
+        (filter.page - 1) * SUBSCRIBERS_PER_PAGE
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(SubscriberPage {
+        subscribers,
+        page: filter.page,
+        per_page: SUBSCRIBERS_PER_PAGE,
+        total,
+    })
+}
+
+// 購読者がいない状態も0件として含める
+#[tracing::instrument(name = "Get subscriber counts by status", skip(pool))]
+pub async fn count_subscribers_by_status(pool: &PgPool) -> Result<Vec<StatusCount>, sqlx::Error> {
+    let rows =
+        sqlx::query!(r#"SELECT status, COUNT(*) AS "count!" FROM subscriptions GROUP BY status"#)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+
+    Ok(SubscriptionStatus::ALL
+        .iter()
+        .map(|status| StatusCount {
+            status: status.as_str().to_string(),
+            count: rows
+                .iter()
+                .find(|r| r.status == status.as_str())
+                .map(|r| r.count)
+                .unwrap_or(0),
+        })
+        .collect())
+}
+
+// LIKEの特殊文字を、検索語の中ではただの文字として扱う
+fn escape_like_pattern(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_like_pattern, SubscriberFilter, SubscribersQuery};
+    use crate::domain::SubscriptionStatus;
+    use claim::assert_err;
+
+    #[test]
+    fn an_empty_query_matches_every_subscriber() {
+        let filter = SubscriberFilter::parse(SubscribersQuery::default()).unwrap();
+
+        assert_eq!(
+            filter,
+            SubscriberFilter {
+                status: None,
+                search: None,
+                page: 1
+            }
+        );
+    }
+
+    #[test]
+    fn empty_values_are_ignored() {
+        let filter = SubscriberFilter::parse(SubscribersQuery {
+            status: Some("".into()),
+            search: Some("  ".into()),
+            page: None,
+        })
+        .unwrap();
+
+        assert_eq!(filter.status, None);
+        assert_eq!(filter.search, None);
+    }
+
+    #[test]
+    fn a_known_status_is_parsed() {
+        let filter = SubscriberFilter::parse(SubscribersQuery {
+            status: Some("confirmed".into()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(filter.status, Some(SubscriptionStatus::Confirmed));
+    }
+
+    #[test]
+    fn an_unknown_status_is_rejected() {
+        assert_err!(SubscriberFilter::parse(SubscribersQuery {
+            status: Some("active".into()),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn a_page_below_one_is_rejected() {
+        assert_err!(SubscriberFilter::parse(SubscribersQuery {
+            page: Some(0),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like_pattern(r"50%_off\"), r"50\%\_off\\");
+    }
+}
@@ -1,7 +1,14 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use crate::email_client::EmailClient;
-use crate::startup::ApplicationBaseUrl;
-use actix_web::{web, HttpResponse};
+use crate::configuration::ResendConfirmationSettings;
+use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus};
+use crate::email_outbox::enqueue_email;
+use crate::email_templates::{AlreadySubscribedEmail, ConfirmationEmail, EmailTemplates};
+use crate::idempotency::{
+    save_response, try_processing, IdempotencyKey, IdempotentRequest, NextAction,
+};
+use crate::routes::{throttle_confirmation_emails, unsubscribe_link, Throttle};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::subscription_tokens::{generate_token, SubscriptionTokenHasher};
+use actix_web::{web, HttpRequest, HttpResponse};
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
@@ -32,50 +39,197 @@ impl TryFrom<FormData> for NewSubscriber {
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(
+        request,
+        form,
+        pool,
+        email_templates,
+        base_url,
+        token_ttl,
+        token_hasher,
+        throttle_settings
+    ),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
     )
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn subscribe(
+    request: HttpRequest,
     form: web::Form<FormData>,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
+    email_templates: web::Data<EmailTemplates>,
     base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    token_hasher: web::Data<SubscriptionTokenHasher>,
+    throttle_settings: web::Data<ResendConfirmationSettings>,
 ) -> HttpResponse {
-    let new_subscriber = match form.0.try_into() {
+    let idempotency_key = match IdempotencyKey::from_request(&request) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    let new_subscriber: NewSubscriber = match form.0.try_into() {
         Ok(form) => form,
         Err(_) => return HttpResponse::BadRequest().finish(),
     };
+    // 購読の登録は誰でもできるため、呼び出し元は区別できない
+    let idempotent_request = idempotency_key.map(|key| {
+        IdempotentRequest::new(
+            key,
+            "POST /subscriptions",
+            &[new_subscriber.email.as_ref(), new_subscriber.name.as_ref()],
+        )
+    });
 
+    register_subscriber(
+        &pool,
+        &email_templates,
+        &base_url.0,
+        token_ttl.0,
+        &token_hasher,
+        &throttle_settings,
+        new_subscriber,
+        idempotent_request,
+    )
+    .await
+}
+
+// 購読の登録を受け付ける(フォームからの登録とAPIからの追加で共通)
+// 登録済みのアドレスかどうかでレスポンスを変えると、誰が購読しているか調べられてしまう
+// そのため、送るメールの内容だけを変えて、レスポンスは常に同じにする
+#[tracing::instrument(name = "Register a subscriber", skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn register_subscriber(
+    pool: &PgPool,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    token_ttl: chrono::Duration,
+    token_hasher: &SubscriptionTokenHasher,
+    throttle_settings: &ResendConfirmationSettings,
+    new_subscriber: NewSubscriber,
+    idempotent_request: Option<IdempotentRequest>,
+) -> HttpResponse {
     // トランザクションを開始
     let mut transaction = match pool.begin().await {
         Ok(transaction) => transaction,
         Err(_) => return HttpResponse::InternalServerError().finish(),
     };
 
-    // 新しいsubscriberのデータをDBに追加
-    let subscriber_id = match insert_subscriber(&mut transaction, &new_subscriber).await {
-        Ok(subscriber_id) => subscriber_id,
+    // 同じIdempotency-Keyで処理済みの場合は、保存済みのレスポンスを返す
+    if let Some(idempotent_request) = &idempotent_request {
+        match try_processing(&mut transaction, idempotent_request).await {
+            Ok(NextAction::StartProcessing) => {}
+            Ok(NextAction::ReturnSavedResponse(saved_response)) => return saved_response,
+            Ok(NextAction::RejectMismatchedRequest) => {
+                return HttpResponse::UnprocessableEntity().finish()
+            }
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    // 登録済みのアドレスでも毎回メールを送るため、再送と同じアドレスごとの上限をかける
+    // プロキシの後ろでは全員の接続元が同じになるため、IPアドレスごとには制限しない
+    match throttle_confirmation_emails(
+        &mut transaction,
+        throttle_settings,
+        &new_subscriber.email,
+        None,
+    )
+    .await
+    {
+        Ok(Throttle::Allowed) => {}
+        Ok(Throttle::Exceeded) => return HttpResponse::TooManyRequests().finish(),
         Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let mut existing_subscriber =
+        match get_subscriber_by_email(&mut transaction, &new_subscriber.email).await {
+            Ok(existing_subscriber) => existing_subscriber,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+
+    // 同じアドレスでの初回登録が同時に届くと、どちらも未登録と判断して挿入しようとする
+    // 挿入できなかった側は、先に登録された行を読み直して既存のsubscriberとして扱う
+    let mut inserted_subscriber = None;
+    if existing_subscriber.is_none() {
+        let unsubscribe_token = generate_subscription_token();
+        match insert_subscriber(&mut transaction, &new_subscriber, &unsubscribe_token).await {
+            Ok(Some(subscriber_id)) => {
+                inserted_subscriber = Some((subscriber_id, unsubscribe_token))
+            }
+            Ok(None) => {
+                existing_subscriber =
+                    match get_subscriber_by_email(&mut transaction, &new_subscriber.email).await {
+                        Ok(existing_subscriber) => existing_subscriber,
+                        Err(_) => return HttpResponse::InternalServerError().finish(),
+                    }
+            }
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    let (subscriber_id, unsubscribe_token) = match existing_subscriber {
+        // 確認済みの場合は、既に購読していることを知らせるだけにする
+        Some(subscriber) if subscriber.status == SubscriptionStatus::Confirmed => {
+            if send_already_subscribed_email(
+                &mut transaction,
+                email_templates,
+                new_subscriber,
+                base_url,
+                &subscriber.unsubscribe_token,
+            )
+            .await
+            .is_err()
+            {
+                return HttpResponse::InternalServerError().finish();
+            }
+            return finish_subscribe(transaction, idempotent_request).await;
+        }
+        // 確認待ちの場合は、新しいトークンで確認メールを送り直す
+        Some(subscriber) if subscriber.status == SubscriptionStatus::PendingConfirmation => {
+            (subscriber.id, subscriber.unsubscribe_token)
+        }
+        // 購読解除済みなどの場合は、確認待ちに戻せるときだけ確認メールを送る
+        Some(subscriber) => {
+            match change_subscription_status(
+                &mut transaction,
+                subscriber.id,
+                SubscriptionStatus::PendingConfirmation,
+            )
+            .await
+            {
+                Ok(StatusChange::Applied) => {}
+                Ok(StatusChange::Rejected(_)) => {
+                    return finish_subscribe(transaction, idempotent_request).await
+                }
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            }
+            (subscriber.id, subscriber.unsubscribe_token)
+        }
+        // 新しく追加したsubscriber
+        None => match inserted_subscriber {
+            Some(inserted_subscriber) => inserted_subscriber,
+            None => return HttpResponse::InternalServerError().finish(),
+        },
     };
 
     // 新しいsubscriber_tokenのデータをDBに追加
-    let subscription_token = generate_subscription_token();
-    if store_token(&mut transaction, subscriber_id, &subscription_token)
-        .await
-        .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
-    }
+    let subscription_token =
+        match store_token(&mut transaction, token_hasher, subscriber_id, token_ttl).await {
+            Ok(subscription_token) => subscription_token,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
 
-    // 確認メールを送信
+    // 確認メールをoutboxに書き込み、コミット後にディスパッチャから送信する
     if send_confirmation_email(
-        &email_client,
+        &mut transaction,
+        email_templates,
         new_subscriber,
-        &base_url.0,
+        base_url,
         &subscription_token,
+        &unsubscribe_token,
     )
     .await
     .is_err()
@@ -83,27 +237,57 @@ pub async fn subscribe(
         return HttpResponse::InternalServerError().finish();
     }
 
+    finish_subscribe(transaction, idempotent_request).await
+}
+
+// レスポンスを保存してからコミットする
+async fn finish_subscribe(
+    mut transaction: Transaction<'_, Postgres>,
+    idempotent_request: Option<IdempotentRequest>,
+) -> HttpResponse {
+    let response = HttpResponse::Ok().finish();
+    let response = match &idempotent_request {
+        Some(idempotent_request) => {
+            match save_response(&mut transaction, idempotent_request, response).await {
+                Ok(response) => response,
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            }
+        }
+        None => response,
+    };
+
     if transaction.commit().await.is_err() {
         return HttpResponse::InternalServerError().finish();
     }
 
-    HttpResponse::Ok().finish()
+    response
 }
 
+// 新しいトークンを発行して保存し、確認メールのリンクに埋め込むトークンを返す
 #[tracing::instrument(
     name = "Store subscription token in the database",
-    skip(subscription_token, subscription_id, transaction)
+    skip(subscription_id, transaction, hasher)
 )]
 pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
+    hasher: &SubscriptionTokenHasher,
     subscription_id: Uuid,
-    subscription_token: &str,
-) -> Result<(), sqlx::Error> {
+    ttl: chrono::Duration,
+) -> Result<String, sqlx::Error> {
+    let now = Utc::now();
+    let token_id = Uuid::new_v4();
+    let subscription_token = generate_token(token_id);
+
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES ($1, $2)"#,
-        subscription_token,
-        subscription_id
+        r#"INSERT INTO subscription_tokens (
+            token_id, subscription_token_hash, subscriber_id, created_at, expires_at
+        )
+        VALUES ($1, $2, $3, $4, $5)"#,
+        token_id,
+        hasher.hash(&subscription_token),
+        subscription_id,
+        now,
+        now + ttl
     )
     .execute(transaction)
     .await
@@ -112,25 +296,129 @@ pub async fn store_token(
         e
     })?;
 
-    Ok(())
+    Ok(subscription_token)
 }
 
+// 既に同じメールアドレスの行がある場合は何もせずにNoneを返す
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
-    skip(new_subscriber, transaction)
+    skip(new_subscriber, transaction, unsubscribe_token)
 )]
 pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
+    unsubscribe_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"INSERT INTO subscriptions (
+            id, email, name, subscribed_at, status, unsubscribe_token
+        )
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (email) DO NOTHING
+        RETURNING id"#,
+        Uuid::new_v4(),
+        new_subscriber.email.as_ref(),
+        new_subscriber.name.as_ref(),
+        Utc::now(),
+        SubscriptionStatus::PendingConfirmation.as_str(),
+        unsubscribe_token
+    )
+    .fetch_optional(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(r.map(|r| r.id))
+}
+
+pub struct ExistingSubscriber {
+    pub id: Uuid,
+    pub name: String,
+    pub status: SubscriptionStatus,
+    pub unsubscribe_token: String,
+}
+
+// 同じメールアドレスでの同時の登録が、互いの処理を追い越さないよう行をロックする
+#[tracing::instrument(name = "Get subscriber by email", skip(transaction, email))]
+pub async fn get_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    let subscriber = sqlx::query!(
+        r#"SELECT id, name, status, unsubscribe_token
+        FROM subscriptions
+        WHERE email = $1
+        FOR UPDATE"#,
+        email.as_ref()
+    )
+    .fetch_optional(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    subscriber
+        .map(|r| {
+            Ok(ExistingSubscriber {
+                id: r.id,
+                name: r.name,
+                status: SubscriptionStatus::parse(r.status)
+                    .map_err(|e| sqlx::Error::Decode(e.into()))?,
+                unsubscribe_token: r.unsubscribe_token,
+            })
+        })
+        .transpose()
+}
+
+// 状態の変更結果
+// 拒否された場合は、呼び出し元が理由を判断できるよう現在の状態を返す
+pub enum StatusChange {
+    Applied,
+    Rejected(SubscriptionStatus),
+}
 
+// 購読の状態はすべてこの関数を通して変更し、許可されていない遷移はDBに書き込まない
+// 判定から更新までの間に他のリクエストが状態を変えないよう、行をロックする
+#[tracing::instrument(name = "Change subscription status", skip(transaction))]
+pub async fn change_subscription_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    next: SubscriptionStatus,
+) -> Result<StatusChange, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let current = SubscriptionStatus::parse(r.status).map_err(|e| sqlx::Error::Decode(e.into()))?;
+
+    let next = match current.transition_to(next) {
+        Ok(next) => next,
+        Err(e) => {
+            tracing::warn!("Rejected a subscription status change: {}", e);
+            return Ok(StatusChange::Rejected(current));
+        }
+    };
+
+    // 再登録で確認待ちに戻るときは、リマインダーと削除の期限を登録し直した時点から数え直す
+    let restarts_pending = next == SubscriptionStatus::PendingConfirmation;
     sqlx::query!(
-        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-        VALUES ($1, $2, $3, $4, 'pending_confirmation')"#,
+        r#"UPDATE subscriptions
+        SET status = $2,
+            subscribed_at = CASE WHEN $3 THEN $4 ELSE subscribed_at END,
+            reminder_sent_at = CASE WHEN $3 THEN NULL ELSE reminder_sent_at END
+        WHERE id = $1"#,
         subscriber_id,
-        new_subscriber.email.as_ref(),
-        new_subscriber.name.as_ref(),
+        next.as_str(),
+        restarts_pending,
         Utc::now()
     )
     .execute(transaction)
@@ -140,39 +428,153 @@ pub async fn insert_subscriber(
         e
     })?;
 
-    Ok(subscriber_id)
+    Ok(StatusChange::Applied)
+}
+
+pub struct PurgedSubscribers {
+    pub n_subscribers: u64,
+    pub n_tokens: u64,
+}
+
+// change_subscription_statusで削除済みにした行を、参照している行ごと消して個人情報を残さない
+// 削除済みでない行は消さないため、状態の遷移を経ずに行が消えることはない
+// 消した後に同じアドレスで登録し直すと、新しいsubscriberとして扱われる
+#[tracing::instrument(name = "Purge deleted subscribers", skip(transaction))]
+pub async fn purge_deleted_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    ids: &[Uuid],
+) -> Result<PurgedSubscribers, sqlx::Error> {
+    let deleted = SubscriptionStatus::Deleted.as_str();
+
+    // subscriptionsを参照している行から先に削除する
+    let n_tokens = sqlx::query!(
+        r#"DELETE FROM subscription_tokens
+        WHERE subscriber_id IN (SELECT id FROM subscriptions WHERE id = ANY($1) AND status = $2)"#,
+        ids,
+        deleted
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    sqlx::query!(
+        r#"DELETE FROM subscriber_preferences
+        WHERE subscriber_id IN (SELECT id FROM subscriptions WHERE id = ANY($1) AND status = $2)"#,
+        ids,
+        deleted
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let n_subscribers = sqlx::query!(
+        r#"DELETE FROM subscriptions WHERE id = ANY($1) AND status = $2"#,
+        ids,
+        deleted
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+
+    Ok(PurgedSubscribers {
+        n_subscribers,
+        n_tokens,
+    })
+}
+
+#[tracing::instrument(
+    name = "Send an already subscribed notice to a confirmed subscriber",
+    skip_all
+)]
+pub async fn send_already_subscribed_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_templates: &EmailTemplates,
+    subscriber: NewSubscriber,
+    base_url: &str,
+    unsubscribe_token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let body = email_templates
+        .render(&AlreadySubscribedEmail {
+            subscriber_name: subscriber.name.as_ref(),
+        })
+        .map_err(|e| {
+            tracing::error!("Failed to render the already subscribed email: {:?}", e);
+            e
+        })?;
+
+    enqueue_email(
+        transaction,
+        &subscriber.email,
+        "You're already subscribed",
+        &body.html,
+        &body.text,
+        Some(&unsubscribe_link(base_url, unsubscribe_token)),
+    )
+    .await?;
+
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    skip(
+        transaction,
+        email_templates,
+        new_subscriber,
+        base_url,
+        subscription_token,
+        unsubscribe_token
+    )
 )]
 pub async fn send_confirmation_email(
-    email_client: &EmailClient,
+    transaction: &mut Transaction<'_, Postgres>,
+    email_templates: &EmailTemplates,
     new_subscriber: NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
-    let confirmation_link = format!(
+    unsubscribe_token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let confirmation_link = confirmation_link(base_url, subscription_token);
+    let body = email_templates
+        .render(&ConfirmationEmail {
+            subscriber_name: new_subscriber.name.as_ref(),
+            confirmation_link: &confirmation_link,
+        })
+        .map_err(|e| {
+            tracing::error!("Failed to render the confirmation email: {:?}", e);
+            e
+        })?;
+
+    enqueue_email(
+        transaction,
+        &new_subscriber.email,
+        "Welcome!",
+        &body.html,
+        &body.text,
+        Some(&unsubscribe_link(base_url, unsubscribe_token)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub fn confirmation_link(base_url: &str, subscription_token: &str) -> String {
+    format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token
-    );
-    let plain_body = format!(
-        "Welcome to our newsletter!<br />\
-                Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our newsletter!<br />\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
-
-    email_client
-        .send_email(new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
-}
-
-fn generate_subscription_token() -> String {
+    )
+}
+
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -1,5 +1,13 @@
+use crate::domain::SubscriptionStatus;
+use crate::routes::html_response;
+use crate::routes::{change_subscription_status, StatusChange};
+use crate::startup::ConfirmOnGet;
+use crate::subscription_tokens::{token_id, SubscriptionTokenHasher};
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
@@ -7,58 +15,316 @@ pub struct Parameters {
     pub subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
-pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
+// トークンを使用済みにした結果
+pub enum ConsumedToken {
+    Consumed(Uuid),
+    Unknown,
+    Expired,
+    AlreadyUsed,
+}
 
-    match id {
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
+// 確認の結果
+// 結果ごとに表示するページと、リダイレクト先に付けるクエリパラメータが決まる
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfirmationOutcome {
+    Confirmed,
+    UnknownToken,
+    ExpiredToken,
+    AlreadyConfirmed,
+    // 購読解除された後に古いトークンが使われた場合など、状態の遷移が許可されていない
+    NotAllowed,
+    Error,
+}
+
+impl ConfirmationOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "confirmed",
+            Self::UnknownToken => "unknown",
+            Self::ExpiredToken => "expired",
+            Self::AlreadyConfirmed => "already_confirmed",
+            Self::NotAllowed => "not_allowed",
+            Self::Error => "error",
+        }
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Confirmed => StatusCode::OK,
+            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::ExpiredToken => StatusCode::GONE,
+            Self::AlreadyConfirmed | Self::NotAllowed => StatusCode::CONFLICT,
+            Self::Error => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "Thanks for confirming your subscription!",
+            Self::UnknownToken => "This confirmation link is not valid.",
+            Self::ExpiredToken => {
+                "This confirmation link has expired. Please subscribe again to get a new one."
             }
-            HttpResponse::Ok().finish()
+            Self::AlreadyConfirmed => "Your subscription has already been confirmed.",
+            Self::NotAllowed => "This subscription can no longer be confirmed.",
+            Self::Error => "Something went wrong. Please try again later.",
         }
     }
 }
 
+// 確認後の遷移先
+// 設定されていれば、結果を`outcome`クエリパラメータに付けてリダイレクトする
+// 設定されていなければ、アプリケーションでページを表示する
+pub struct ConfirmationRedirects {
+    pub success_url: Option<reqwest::Url>,
+    pub failure_url: Option<reqwest::Url>,
+}
+
+// メールのリンクを開いただけでは確認せず、トークンをPOSTするボタンを表示する
+// メールサーバのリンクスキャナがURLを先読みしても、購読が確認されないようにするため
+#[tracing::instrument(
+    name = "Show the confirmation page",
+    skip(parameters, pool, token_hasher, confirm_on_get, redirects)
+)]
+pub async fn confirmation_page(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    token_hasher: web::Data<SubscriptionTokenHasher>,
+    confirm_on_get: web::Data<ConfirmOnGet>,
+    redirects: web::Data<ConfirmationRedirects>,
+) -> HttpResponse {
+    // 以前と同じくGETで確認する設定の場合
+    if confirm_on_get.0 {
+        let outcome = try_confirm(&pool, &token_hasher, &parameters.subscription_token).await;
+        return outcome_response(outcome, &redirects);
+    }
+
+    html_response(
+        StatusCode::OK,
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirm your subscription</title>
+</head>
+<body>
+    <form action="/subscriptions/confirm" method="post">
+        <input type="hidden" name="subscription_token" value="{}">
+        <button type="submit">Confirm subscription</button>
+    </form>
+</body>
+</html>"#,
+            htmlescape::encode_attribute(&parameters.subscription_token)
+        ),
+    )
+}
+
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(form, pool, token_hasher, redirects)
+)]
+pub async fn confirm(
+    form: web::Form<Parameters>,
+    pool: web::Data<PgPool>,
+    token_hasher: web::Data<SubscriptionTokenHasher>,
+    redirects: web::Data<ConfirmationRedirects>,
+) -> HttpResponse {
+    let outcome = try_confirm(&pool, &token_hasher, &form.subscription_token).await;
+    outcome_response(outcome, &redirects)
+}
+
+async fn try_confirm(
+    pool: &PgPool,
+    token_hasher: &SubscriptionTokenHasher,
+    subscription_token: &str,
+) -> ConfirmationOutcome {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return ConfirmationOutcome::Error,
+    };
+
+    let subscriber_id = match consume_subscription_token(
+        &mut transaction,
+        token_hasher,
+        subscription_token,
+    )
+    .await
+    {
+        Ok(ConsumedToken::Consumed(subscriber_id)) => subscriber_id,
+        Ok(ConsumedToken::Unknown) => return ConfirmationOutcome::UnknownToken,
+        Ok(ConsumedToken::Expired) => return ConfirmationOutcome::ExpiredToken,
+        Ok(ConsumedToken::AlreadyUsed) => return ConfirmationOutcome::AlreadyConfirmed,
+        Err(_) => return ConfirmationOutcome::Error,
+    };
+
+    // 購読解除された後に古いトークンが使われた場合などは確認しない
+    // 新しいリンクで確認済みの場合は、古いリンクでも確認済みであることを伝える
+    // トランザクションをコミットしないため、トークンも使用済みにはならない
+    match confirm_subscriber(&mut transaction, subscriber_id).await {
+        Ok(StatusChange::Applied) => {}
+        Ok(StatusChange::Rejected(SubscriptionStatus::Confirmed)) => {
+            return ConfirmationOutcome::AlreadyConfirmed
+        }
+        Ok(StatusChange::Rejected(_)) => return ConfirmationOutcome::NotAllowed,
+        Err(_) => return ConfirmationOutcome::Error,
+    }
+
+    if transaction.commit().await.is_err() {
+        return ConfirmationOutcome::Error;
+    }
+
+    ConfirmationOutcome::Confirmed
+}
+
+fn outcome_response(
+    outcome: ConfirmationOutcome,
+    redirects: &ConfirmationRedirects,
+) -> HttpResponse {
+    let redirect_url = match outcome {
+        ConfirmationOutcome::Confirmed => &redirects.success_url,
+        _ => &redirects.failure_url,
+    };
+    if let Some(redirect_url) = redirect_url {
+        let mut location = redirect_url.clone();
+        location
+            .query_pairs_mut()
+            .append_pair("outcome", outcome.as_str());
+        return HttpResponse::SeeOther()
+            .insert_header((LOCATION, location.as_str()))
+            .finish();
+    }
+
+    html_response(
+        outcome.status_code(),
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscription confirmation</title>
+</head>
+<body>
+    <p>{}</p>
+</body>
+</html>"#,
+            outcome.message()
+        ),
+    )
+}
+
 #[tracing::instrument(
     name = "Mark subscriber as confirmed"
-    skip(subscriber_id, pool)
+    skip(subscriber_id, transaction)
+)]
+pub async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<StatusChange, sqlx::Error> {
+    change_subscription_status(transaction, subscriber_id, SubscriptionStatus::Confirmed).await
+}
+
+struct StoredToken {
+    subscription_token_hash: String,
+    subscriber_id: Uuid,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+}
+
+// 行をロックしてから使用済みにするため、同じトークンで同時に確認しても一度しか成功しない
+#[tracing::instrument(
+    name = "Consume a subscription token",
+    skip(subscription_token, transaction, hasher)
 )]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+pub async fn consume_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    hasher: &SubscriptionTokenHasher,
+    subscription_token: &str,
+) -> Result<ConsumedToken, sqlx::Error> {
+    // トークンIDで行を引き、保存したHMACとは定数時間で比較する
+    let token = match token_id(subscription_token) {
+        Some(token_id) => get_token_by_id(&mut *transaction, token_id)
+            .await?
+            .filter(|t| hasher.verify(subscription_token, &t.subscription_token_hash)),
+        None => find_token_without_id(&mut *transaction, hasher, subscription_token).await?,
+    };
+
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(ConsumedToken::Unknown),
+    };
+    if token.used_at.is_some() {
+        return Ok(ConsumedToken::AlreadyUsed);
+    }
+    let now = Utc::now();
+    if token.expires_at <= now {
+        return Ok(ConsumedToken::Expired);
+    }
+
     sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
-        subscriber_id
+        r#"UPDATE subscription_tokens SET used_at = $2 WHERE subscription_token_hash = $1"#,
+        token.subscription_token_hash,
+        now
     )
-    .execute(pool)
+    .execute(transaction)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
 
-    Ok(())
+    Ok(ConsumedToken::Consumed(token.subscriber_id))
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
-pub async fn get_subscriber_id_from_token(
-    pool: &PgPool,
+#[tracing::instrument(name = "Get a subscription token by id", skip(transaction))]
+async fn get_token_by_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    token_id: Uuid,
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    let token = sqlx::query_as!(
+        StoredToken,
+        r#"SELECT subscription_token_hash, subscriber_id, expires_at, used_at
+        FROM subscription_tokens
+        WHERE token_id = $1 AND NOT is_plaintext
+        FOR UPDATE"#,
+        token_id
+    )
+    .fetch_optional(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(token)
+}
+
+// IDを含む形式にする前に発行されたトークンは、まだ使える行だけを順に比較する
+// 新しくこの形式で発行されることはないため、対象の行は有効期限が切れるにつれて無くなる
+#[tracing::instrument(name = "Find a subscription token without an id", skip_all)]
+async fn find_token_without_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    hasher: &SubscriptionTokenHasher,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
-        subscription_token
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    let tokens = sqlx::query_as!(
+        StoredToken,
+        r#"SELECT subscription_token_hash, subscriber_id, expires_at, used_at
+        FROM subscription_tokens
+        WHERE token_id IS NULL
+            AND NOT is_plaintext
+            AND used_at IS NULL
+            AND expires_at > now()
+        FOR UPDATE"#
     )
-    .fetch_optional(pool)
+    .fetch_all(transaction)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
 
-    Ok(result.map(|r| r.subscriber_id))
+    Ok(tokens
+        .into_iter()
+        .find(|t| hasher.verify(subscription_token, &t.subscription_token_hash)))
 }
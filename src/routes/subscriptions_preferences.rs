@@ -0,0 +1,636 @@
+use crate::domain::{
+    DeliveryMode, NewSubscriber, SubscriberEmail, SubscriberName, SubscriberPreferences,
+    SubscriptionStatus, Topic, TOPICS,
+};
+use crate::email_templates::EmailTemplates;
+use crate::routes::{
+    change_subscription_status, send_confirmation_email, store_token, StatusChange,
+};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::subscription_tokens::SubscriptionTokenHasher;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+// 設定ページへのリンクに署名し、有効期限付きで発行する
+// リンクを知っていれば誰でも設定を変更できるため、改ざんされていないことを検証する
+// 鍵はhmac_secretから、この用途のためだけに導出したものを使う(KeyPurpose::PreferencesLinks)
+#[derive(Clone)]
+pub struct PreferencesLinkSigner {
+    key: Vec<u8>,
+    ttl: Duration,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesLinkParameters {
+    pub subscriber_id: Uuid,
+    pub expires: i64,
+    pub signature: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LinkVerificationError {
+    InvalidSignature,
+    Expired,
+}
+
+impl PreferencesLinkSigner {
+    pub fn new(key: Vec<u8>, ttl: Duration) -> Self {
+        Self { key, ttl }
+    }
+
+    pub fn link(&self, base_url: &str, subscriber_id: Uuid) -> String {
+        let expires = (Utc::now() + self.ttl).timestamp();
+        let signature = hex::encode(self.mac(subscriber_id, expires).finalize().into_bytes());
+        format!(
+            "{}/subscriptions/preferences?subscriber_id={}&expires={}&signature={}",
+            base_url, subscriber_id, expires, signature
+        )
+    }
+
+    // 署名の比較は定数時間で行う
+    pub fn verify(
+        &self,
+        parameters: &PreferencesLinkParameters,
+    ) -> Result<Uuid, LinkVerificationError> {
+        let signature = hex::decode(&parameters.signature)
+            .map_err(|_| LinkVerificationError::InvalidSignature)?;
+        self.mac(parameters.subscriber_id, parameters.expires)
+            .verify_slice(&signature)
+            .map_err(|_| LinkVerificationError::InvalidSignature)?;
+
+        if parameters.expires < Utc::now().timestamp() {
+            return Err(LinkVerificationError::Expired);
+        }
+
+        Ok(parameters.subscriber_id)
+    }
+
+    fn mac(&self, subscriber_id: Uuid, expires: i64) -> Hmac<Sha256> {
+        // HMACはどの長さの鍵でも受け付けるため、失敗しない
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(format!("{}:{}", subscriber_id, expires).as_bytes());
+        mac
+    }
+}
+
+// フォームに表示する値 (未検証)
+pub struct PreferencesFormValues {
+    pub name: String,
+    pub email: String,
+    pub topics: Vec<String>,
+    pub delivery_mode: String,
+}
+
+pub struct PreferencesUpdate {
+    pub name: SubscriberName,
+    pub email: SubscriberEmail,
+    pub preferences: SubscriberPreferences,
+}
+
+impl TryFrom<&PreferencesFormValues> for PreferencesUpdate {
+    type Error = Vec<String>;
+
+    // 入力の誤りをまとめて表示できるよう、すべてのエラーを集める
+    fn try_from(values: &PreferencesFormValues) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+
+        let name = SubscriberName::parse(values.name.clone()).map_err(|e| errors.push(e));
+        let email = SubscriberEmail::parse(values.email.clone()).map_err(|e| errors.push(e));
+        let topics = values
+            .topics
+            .iter()
+            .filter_map(|t| Topic::parse(t.clone()).map_err(|e| errors.push(e)).ok())
+            .collect();
+        let delivery_mode =
+            DeliveryMode::parse(values.delivery_mode.clone()).map_err(|e| errors.push(e));
+
+        match (name, email, delivery_mode) {
+            (Ok(name), Ok(email), Ok(delivery_mode)) if errors.is_empty() => Ok(Self {
+                name,
+                email,
+                preferences: SubscriberPreferences {
+                    topics,
+                    delivery_mode,
+                },
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Show subscriber preferences",
+    skip(parameters, pool, signer),
+    fields(subscriber_id = %parameters.subscriber_id)
+)]
+pub async fn preferences_form(
+    parameters: web::Query<PreferencesLinkParameters>,
+    pool: web::Data<PgPool>,
+    signer: web::Data<PreferencesLinkSigner>,
+) -> HttpResponse {
+    let subscriber_id = match signer.verify(&parameters) {
+        Ok(subscriber_id) => subscriber_id,
+        Err(e) => return invalid_link_response(e),
+    };
+
+    let values = match get_subscriber_preferences(&pool, subscriber_id).await {
+        Ok(Some(values)) => values,
+        Ok(None) => return invalid_link_response(LinkVerificationError::InvalidSignature),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    html_response(StatusCode::OK, preferences_page(&parameters, &values, &[]))
+}
+
+// メールアドレスを変更した場合は、新しいアドレスの持ち主が購読を望んでいるか確認するため、
+// 確認待ちに戻して新しいアドレスに確認メールを送る
+#[tracing::instrument(
+    name = "Update subscriber preferences",
+    skip(form, pool, signer, email_templates, base_url, token_ttl, token_hasher)
+)]
+pub async fn update_preferences(
+    form: web::Form<Vec<(String, String)>>,
+    pool: web::Data<PgPool>,
+    signer: web::Data<PreferencesLinkSigner>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    token_hasher: web::Data<SubscriptionTokenHasher>,
+) -> HttpResponse {
+    let (parameters, values) = match parse_preferences_form(form.0) {
+        Ok(form) => form,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    let subscriber_id = match signer.verify(&parameters) {
+        Ok(subscriber_id) => subscriber_id,
+        Err(e) => return invalid_link_response(e),
+    };
+
+    let stored = match get_subscriber_preferences(&pool, subscriber_id).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return invalid_link_response(LinkVerificationError::InvalidSignature),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let update = match PreferencesUpdate::try_from(&values) {
+        Ok(update) => update,
+        Err(errors) => {
+            return html_response(
+                StatusCode::BAD_REQUEST,
+                preferences_page(&parameters, &values, &errors),
+            )
+        }
+    };
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let email_changed = update.email.as_ref() != stored.email;
+    let unsubscribe_token = if email_changed {
+        // 苦情を受けたアドレスなど、確認待ちに戻せない場合はアドレスを変更しない
+        match change_subscription_status(
+            &mut transaction,
+            subscriber_id,
+            SubscriptionStatus::PendingConfirmation,
+        )
+        .await
+        {
+            Ok(StatusChange::Applied)
+            | Ok(StatusChange::Rejected(SubscriptionStatus::PendingConfirmation)) => {}
+            Ok(StatusChange::Rejected(_)) => {
+                return html_response(
+                    StatusCode::BAD_REQUEST,
+                    preferences_page(
+                        &parameters,
+                        &values,
+                        &["The email address of this subscription cannot be changed.".into()],
+                    ),
+                )
+            }
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+        match get_unsubscribe_token(&mut transaction, subscriber_id).await {
+            Ok(unsubscribe_token) => Some(unsubscribe_token),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+    } else {
+        None
+    };
+
+    match save_preferences(&mut transaction, subscriber_id, &update).await {
+        Ok(()) => {}
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(UNIQUE_VIOLATION) => {
+            return html_response(
+                StatusCode::BAD_REQUEST,
+                preferences_page(
+                    &parameters,
+                    &values,
+                    &[format!("{} is already in use.", values.email)],
+                ),
+            )
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let mut messages = vec!["Your preferences have been saved.".to_string()];
+    if let Some(unsubscribe_token) = unsubscribe_token {
+        // 古いアドレスに送った確認リンクでは、新しいアドレスを確認できないようにする
+        if delete_subscription_tokens(&mut transaction, subscriber_id)
+            .await
+            .is_err()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+        let subscription_token =
+            match store_token(&mut transaction, &token_hasher, subscriber_id, token_ttl.0).await {
+                Ok(subscription_token) => subscription_token,
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            };
+        if send_confirmation_email(
+            &mut transaction,
+            &email_templates,
+            NewSubscriber {
+                email: update.email,
+                name: update.name,
+            },
+            &base_url.0,
+            &subscription_token,
+            &unsubscribe_token,
+        )
+        .await
+        .is_err()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+        messages.push("Please confirm your new email address with the link we sent to it.".into());
+    }
+
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    html_response(
+        StatusCode::OK,
+        preferences_page(&parameters, &values, &messages),
+    )
+}
+
+const UNIQUE_VIOLATION: &str = "23505";
+
+// 同じ名前のキー(topic)が複数送られてくるため、キーと値の組として受け取る
+fn parse_preferences_form(
+    form: Vec<(String, String)>,
+) -> Result<(PreferencesLinkParameters, PreferencesFormValues), String> {
+    let mut subscriber_id = None;
+    let mut expires = None;
+    let mut signature = None;
+    let mut name = None;
+    let mut email = None;
+    let mut topics = Vec::new();
+    let mut delivery_mode = None;
+
+    for (key, value) in form {
+        match key.as_str() {
+            "subscriber_id" => {
+                subscriber_id = Some(Uuid::parse_str(&value).map_err(|e| e.to_string())?)
+            }
+            "expires" => expires = Some(value.parse::<i64>().map_err(|e| e.to_string())?),
+            "signature" => signature = Some(value),
+            "name" => name = Some(value),
+            "email" => email = Some(value),
+            "topic" => topics.push(value),
+            "delivery_mode" => delivery_mode = Some(value),
+            _ => {}
+        }
+    }
+
+    let missing = |field: &str| format!("Missing field: {}", field);
+    let parameters = PreferencesLinkParameters {
+        subscriber_id: subscriber_id.ok_or_else(|| missing("subscriber_id"))?,
+        expires: expires.ok_or_else(|| missing("expires"))?,
+        signature: signature.ok_or_else(|| missing("signature"))?,
+    };
+    let values = PreferencesFormValues {
+        name: name.ok_or_else(|| missing("name"))?,
+        email: email.ok_or_else(|| missing("email"))?,
+        topics,
+        delivery_mode: delivery_mode.unwrap_or_default(),
+    };
+
+    Ok((parameters, values))
+}
+
+#[tracing::instrument(name = "Get subscriber preferences", skip(pool))]
+pub async fn get_subscriber_preferences(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<PreferencesFormValues>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"SELECT s.name, s.email, p.topics AS "topics?", p.delivery_mode AS "delivery_mode?"
+        FROM subscriptions s
+        LEFT JOIN subscriber_preferences p ON p.subscriber_id = s.id
+        WHERE s.id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    // 設定を保存したことがない購読者には初期値を表示する
+    Ok(r.map(|r| {
+        let default = SubscriberPreferences::default();
+        PreferencesFormValues {
+            name: r.name,
+            email: r.email,
+            topics: r.topics.unwrap_or_else(|| {
+                default
+                    .topics
+                    .iter()
+                    .map(|t| t.as_ref().to_owned())
+                    .collect()
+            }),
+            delivery_mode: r
+                .delivery_mode
+                .unwrap_or_else(|| default.delivery_mode.as_str().to_owned()),
+        }
+    }))
+}
+
+#[tracing::instrument(name = "Save subscriber preferences", skip(transaction, update))]
+pub async fn save_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    update: &PreferencesUpdate,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET name = $2, email = $3 WHERE id = $1"#,
+        subscriber_id,
+        update.name.as_ref(),
+        update.email.as_ref()
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let topics: Vec<String> = update
+        .preferences
+        .topics
+        .iter()
+        .map(|t| t.as_ref().to_owned())
+        .collect();
+    sqlx::query!(
+        r#"INSERT INTO subscriber_preferences (subscriber_id, topics, delivery_mode, updated_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (subscriber_id) DO UPDATE
+        SET topics = EXCLUDED.topics,
+            delivery_mode = EXCLUDED.delivery_mode,
+            updated_at = EXCLUDED.updated_at"#,
+        subscriber_id,
+        &topics,
+        update.preferences.delivery_mode.as_str(),
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get unsubscribe token", skip(transaction))]
+async fn get_unsubscribe_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"SELECT unsubscribe_token FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_one(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(r.unsubscribe_token)
+}
+
+#[tracing::instrument(name = "Delete subscription tokens", skip(transaction))]
+async fn delete_subscription_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+pub(crate) fn html_response(status: StatusCode, body: String) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(ContentType::html())
+        .body(body)
+}
+
+fn invalid_link_response(e: LinkVerificationError) -> HttpResponse {
+    let message = match e {
+        LinkVerificationError::InvalidSignature => "This link is not valid.",
+        LinkVerificationError::Expired => {
+            "This link has expired. Please use the link in a more recent email."
+        }
+    };
+    html_response(
+        StatusCode::UNAUTHORIZED,
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscription preferences</title>
+</head>
+<body>
+    <p>{}</p>
+</body>
+</html>"#,
+            message
+        ),
+    )
+}
+
+fn preferences_page(
+    parameters: &PreferencesLinkParameters,
+    values: &PreferencesFormValues,
+    messages: &[String],
+) -> String {
+    let messages_html: String = messages
+        .iter()
+        .map(|m| format!("<p><i>{}</i></p>\n", htmlescape::encode_minimal(m)))
+        .collect();
+    let topics_html: String = TOPICS
+        .iter()
+        .map(|topic| {
+            let checked = if values.topics.iter().any(|t| t == topic) {
+                " checked"
+            } else {
+                ""
+            };
+            format!(
+                r#"<label><input type="checkbox" name="topic" value="{0}"{1}> {0}</label><br>
+"#,
+                topic, checked
+            )
+        })
+        .collect();
+    let delivery_html: String = [DeliveryMode::Instant, DeliveryMode::Digest]
+        .iter()
+        .map(|mode| {
+            let checked = if values.delivery_mode == mode.as_str() {
+                " checked"
+            } else {
+                ""
+            };
+            format!(
+                r#"<label><input type="radio" name="delivery_mode" value="{0}"{1}> {0}</label><br>
+"#,
+                mode.as_str(),
+                checked
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscription preferences</title>
+</head>
+<body>
+    {messages}
+    <form action="/subscriptions/preferences" method="post">
+        <input type="hidden" name="subscriber_id" value="{subscriber_id}">
+        <input type="hidden" name="expires" value="{expires}">
+        <input type="hidden" name="signature" value="{signature}">
+        <label>Name
+            <input type="text" name="name" value="{name}">
+        </label>
+        <br>
+        <label>Email
+            <input type="text" name="email" value="{email}">
+        </label>
+        <fieldset>
+            <legend>Topics</legend>
+            {topics}
+        </fieldset>
+        <fieldset>
+            <legend>Delivery</legend>
+            {delivery}
+        </fieldset>
+        <button type="submit">Save preferences</button>
+    </form>
+</body>
+</html>"#,
+        messages = messages_html,
+        subscriber_id = parameters.subscriber_id,
+        expires = parameters.expires,
+        signature = htmlescape::encode_attribute(&parameters.signature),
+        name = htmlescape::encode_attribute(&values.name),
+        email = htmlescape::encode_attribute(&values.email),
+        topics = topics_html,
+        delivery = delivery_html,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkVerificationError, PreferencesLinkParameters, PreferencesLinkSigner};
+    use actix_web::web;
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    fn parameters_from(link: &str) -> PreferencesLinkParameters {
+        let query = reqwest::Url::parse(link)
+            .unwrap()
+            .query()
+            .unwrap()
+            .to_owned();
+        web::Query::<PreferencesLinkParameters>::from_query(&query)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn a_signed_link_is_verified() {
+        let signer = PreferencesLinkSigner::new(b"key".to_vec(), Duration::hours(1));
+        let subscriber_id = Uuid::new_v4();
+
+        let parameters = parameters_from(&signer.link("http://127.0.0.1", subscriber_id));
+
+        assert_eq!(signer.verify(&parameters), Ok(subscriber_id));
+    }
+
+    #[test]
+    fn a_link_for_another_subscriber_is_rejected() {
+        let signer = PreferencesLinkSigner::new(b"key".to_vec(), Duration::hours(1));
+
+        let mut parameters = parameters_from(&signer.link("http://127.0.0.1", Uuid::new_v4()));
+        parameters.subscriber_id = Uuid::new_v4();
+
+        assert_eq!(
+            signer.verify(&parameters),
+            Err(LinkVerificationError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn a_link_signed_with_another_secret_is_rejected() {
+        let signer = PreferencesLinkSigner::new(b"key".to_vec(), Duration::hours(1));
+        let other = PreferencesLinkSigner::new(b"other-key".to_vec(), Duration::hours(1));
+
+        let parameters = parameters_from(&other.link("http://127.0.0.1", Uuid::new_v4()));
+
+        assert_eq!(
+            signer.verify(&parameters),
+            Err(LinkVerificationError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn an_expired_link_is_rejected() {
+        let signer = PreferencesLinkSigner::new(b"key".to_vec(), Duration::hours(-1));
+
+        let parameters = parameters_from(&signer.link("http://127.0.0.1", Uuid::new_v4()));
+
+        assert_eq!(
+            signer.verify(&parameters),
+            Err(LinkVerificationError::Expired)
+        );
+    }
+}
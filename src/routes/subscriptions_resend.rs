@@ -0,0 +1,194 @@
+use crate::configuration::ResendConfirmationSettings;
+use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus};
+use crate::email_templates::EmailTemplates;
+use crate::routes::{get_subscriber_by_email, send_confirmation_email, store_token};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::subscription_tokens::SubscriptionTokenHasher;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+
+#[derive(Deserialize)]
+pub struct ResendFormData {
+    pub email: String,
+}
+
+// 確認待ちのsubscriberにだけ新しいトークンで確認メールを送り直す
+// 登録の有無が分からないよう、スロットリングされた場合を除いて常に同じレスポンスを返す
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip_all,
+    fields(subscriber_email = %form.email)
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn resend_confirmation(
+    request: HttpRequest,
+    form: web::Form<ResendFormData>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    token_hasher: web::Data<SubscriptionTokenHasher>,
+    settings: web::Data<ResendConfirmationSettings>,
+) -> HttpResponse {
+    let email = match SubscriberEmail::parse(form.0.email) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let client_ip = client_ip(&request);
+    match throttle_confirmation_emails(&mut transaction, &settings, &email, Some(&client_ip)).await
+    {
+        Ok(Throttle::Allowed) => {}
+        Ok(Throttle::Exceeded) => return HttpResponse::TooManyRequests().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let subscriber = match get_subscriber_by_email(&mut transaction, &email).await {
+        Ok(subscriber) => subscriber,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    if let Some(subscriber) =
+        subscriber.filter(|s| s.status == SubscriptionStatus::PendingConfirmation)
+    {
+        let name = match SubscriberName::parse(subscriber.name) {
+            Ok(name) => name,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+
+        let subscription_token =
+            match store_token(&mut transaction, &token_hasher, subscriber.id, token_ttl.0).await {
+                Ok(subscription_token) => subscription_token,
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            };
+
+        if send_confirmation_email(
+            &mut transaction,
+            &email_templates,
+            NewSubscriber { email, name },
+            &base_url.0,
+            &subscription_token,
+            &subscriber.unsubscribe_token,
+        )
+        .await
+        .is_err()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+// Forwarded/X-Forwarded-Forヘッダはクライアントが自由に書けるため、接続元のアドレスを使う
+pub fn client_ip(request: &HttpRequest) -> String {
+    request
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+pub enum Throttle {
+    Allowed,
+    Exceeded,
+}
+
+// メール爆撃に使われないよう、確認メールを送るリクエストをアドレスごとに制限する
+// 登録と再送は同じ記録を数えるため、エンドポイントを使い分けても上限は増えない
+// client_ipを渡した場合は、IPアドレスごとにも制限する
+#[tracing::instrument(
+    name = "Throttle confirmation emails",
+    skip(transaction, settings, email)
+)]
+pub async fn throttle_confirmation_emails(
+    transaction: &mut Transaction<'_, Postgres>,
+    settings: &ResendConfirmationSettings,
+    email: &SubscriberEmail,
+    client_ip: Option<&str>,
+) -> Result<Throttle, sqlx::Error> {
+    let mut throttle_keys = vec![(
+        format!("email:{}", email.as_ref()),
+        settings.max_attempts_per_address,
+    )];
+    if let Some(client_ip) = client_ip {
+        throttle_keys.push((format!("ip:{}", client_ip), settings.max_attempts_per_ip));
+    }
+    for (throttle_key, max_attempts) in &throttle_keys {
+        let n_attempts =
+            count_recent_attempts(transaction, throttle_key, settings.window_minutes).await?;
+        if n_attempts >= *max_attempts {
+            tracing::warn!("Throttled a confirmation email request: {}", throttle_key);
+            return Ok(Throttle::Exceeded);
+        }
+    }
+    for (throttle_key, _) in &throttle_keys {
+        record_attempt(transaction, throttle_key).await?;
+    }
+
+    Ok(Throttle::Allowed)
+}
+
+// 同時に届いたリクエストが揃って上限をすり抜けないよう、キーごとにロックしてから数える
+// ロックはトランザクションの終了時に解放される
+#[tracing::instrument(name = "Count recent confirmation resend attempts", skip(transaction))]
+async fn count_recent_attempts(
+    transaction: &mut Transaction<'_, Postgres>,
+    throttle_key: &str,
+    window_minutes: i64,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_unchecked!("SELECT pg_advisory_xact_lock(hashtext($1))", throttle_key)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+
+    let r = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!"
+        FROM confirmation_resend_attempts
+        WHERE throttle_key = $1 AND attempted_at > $2"#,
+        throttle_key,
+        Utc::now() - chrono::Duration::minutes(window_minutes)
+    )
+    .fetch_one(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(r.count)
+}
+
+#[tracing::instrument(name = "Record a confirmation resend attempt", skip(transaction))]
+async fn record_attempt(
+    transaction: &mut Transaction<'_, Postgres>,
+    throttle_key: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO confirmation_resend_attempts (throttle_key, attempted_at)
+        VALUES ($1, $2)"#,
+        throttle_key,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
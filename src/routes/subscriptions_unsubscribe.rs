@@ -0,0 +1,124 @@
+use crate::domain::SubscriptionStatus;
+use crate::routes::{change_subscription_status, html_response};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    pub unsubscribe_token: String,
+}
+
+pub fn unsubscribe_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        base_url, unsubscribe_token
+    )
+}
+
+// メールのリンクを開いただけでは購読を解除せず、同じURLへPOSTするボタンを表示する
+// メールサーバのリンクスキャナがURLを先読みしても、購読が解除されないようにするため
+#[tracing::instrument(name = "Show the unsubscribe page", skip(parameters, pool))]
+pub async fn unsubscribe_page(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    match get_subscriber_id_from_unsubscribe_token(&pool, &parameters.unsubscribe_token).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    html_response(
+        StatusCode::OK,
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <form action="{}" method="post">
+        <input type="hidden" name="List-Unsubscribe" value="One-Click">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+            htmlescape::encode_attribute(&unsubscribe_link("", &parameters.unsubscribe_token))
+        ),
+    )
+}
+
+// ページのボタンと、RFC 8058のワンクリック購読解除によるPOSTを受け付ける
+// どちらもトークンはクエリパラメータで渡され、ボディ(List-Unsubscribe=One-Click)は使わない
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(parameters, pool))]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let id = match get_subscriber_id_from_unsubscribe_token(&pool, &parameters.unsubscribe_token)
+        .await
+    {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    match id {
+        None => HttpResponse::Unauthorized().finish(),
+        Some(subscriber_id) => {
+            let mut transaction = match pool.begin().await {
+                Ok(transaction) => transaction,
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            };
+            // 既に購読解除済みなどで遷移が拒否されても、もう配信されないため成功として扱う
+            if unsubscribe_subscriber(&mut transaction, subscriber_id)
+                .await
+                .is_err()
+            {
+                return HttpResponse::InternalServerError().finish();
+            }
+            if transaction.commit().await.is_err() {
+                return HttpResponse::InternalServerError().finish();
+            }
+            HttpResponse::Ok().finish()
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Mark subscriber as unsubscribed",
+    skip(subscriber_id, transaction)
+)]
+pub async fn unsubscribe_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    change_subscription_status(transaction, subscriber_id, SubscriptionStatus::Unsubscribed)
+        .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Get subscriber_id from unsubscribe token",
+    skip(unsubscribe_token, pool)
+)]
+pub async fn get_subscriber_id_from_unsubscribe_token(
+    pool: &PgPool,
+    unsubscribe_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE unsubscribe_token = $1"#,
+        unsubscribe_token
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(result.map(|r| r.id))
+}
@@ -0,0 +1,195 @@
+use crate::session::SessionState;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+const FLASH_MESSAGES_KEY: &str = "_flash_messages";
+
+// リクエストの処理中に読み書きするセッション
+// 変更はレスポンスを返すときにSessionMiddlewareが保存する
+#[derive(Clone)]
+pub struct Session(Rc<RefCell<SessionInner>>);
+
+pub(crate) struct SessionInner {
+    pub(crate) state: SessionState,
+    pub(crate) status: SessionStatus,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SessionStatus {
+    Unchanged,
+    Changed,
+    // セッションIDを新しくする(古いIDのセッションは削除される)
+    Renewed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashLevel {
+    Info,
+    Error,
+}
+
+// 次のリクエストで一度だけ表示するメッセージ
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlashMessage {
+    pub level: FlashLevel,
+    pub content: String,
+}
+
+impl FlashMessage {
+    pub fn info(content: impl Into<String>) -> Self {
+        Self {
+            level: FlashLevel::Info,
+            content: content.into(),
+        }
+    }
+
+    pub fn error(content: impl Into<String>) -> Self {
+        Self {
+            level: FlashLevel::Error,
+            content: content.into(),
+        }
+    }
+}
+
+impl Session {
+    pub(crate) fn new(state: SessionState) -> Self {
+        Self(Rc::new(RefCell::new(SessionInner {
+            state,
+            status: SessionStatus::Unchanged,
+        })))
+    }
+
+    pub(crate) fn into_parts(self) -> (SessionState, SessionStatus) {
+        let inner = self.0.borrow();
+        (inner.state.clone(), inner.status)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, serde_json::Error> {
+        match self.0.borrow().state.get(key) {
+            Some(value) => serde_json::from_str(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<(), serde_json::Error> {
+        let value = serde_json::to_string(&value)?;
+        let mut inner = self.0.borrow_mut();
+        inner.state.insert(key.to_owned(), value);
+        inner.mark_changed();
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut inner = self.0.borrow_mut();
+        if inner.state.remove(key).is_some() {
+            inner.mark_changed();
+        }
+    }
+
+    // ログインなど権限が変わるときに呼び、セッション固定攻撃を防ぐ
+    pub fn renew(&self) {
+        self.0.borrow_mut().status = SessionStatus::Renewed;
+    }
+
+    // 保存している値をすべて消し、IDも新しくする
+    pub fn purge(&self) {
+        let mut inner = self.0.borrow_mut();
+        inner.state.clear();
+        inner.status = SessionStatus::Renewed;
+    }
+
+    pub fn add_flash_message(&self, message: FlashMessage) -> Result<(), serde_json::Error> {
+        let mut messages: Vec<FlashMessage> = self.get(FLASH_MESSAGES_KEY)?.unwrap_or_default();
+        messages.push(message);
+        self.insert(FLASH_MESSAGES_KEY, messages)
+    }
+
+    // 取り出したメッセージはセッションから削除され、次のリクエストでは表示されない
+    pub fn take_flash_messages(&self) -> Result<Vec<FlashMessage>, serde_json::Error> {
+        let messages = self.get(FLASH_MESSAGES_KEY)?.unwrap_or_default();
+        self.remove(FLASH_MESSAGES_KEY);
+        Ok(messages)
+    }
+}
+
+impl SessionInner {
+    fn mark_changed(&mut self) {
+        if self.status == SessionStatus::Unchanged {
+            self.status = SessionStatus::Changed;
+        }
+    }
+}
+
+impl FromRequest for Session {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            request
+                .extensions()
+                .get::<Session>()
+                .cloned()
+                .ok_or_else(|| {
+                    tracing::error!("SessionMiddleware is not registered.");
+                    actix_web::error::ErrorInternalServerError("")
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlashMessage, Session, SessionStatus};
+    use crate::session::SessionState;
+
+    #[test]
+    fn reading_a_session_does_not_change_it() {
+        let session = Session::new(SessionState::new());
+
+        assert_eq!(session.get::<String>("user_id").unwrap(), None);
+        session.remove("user_id");
+
+        assert!(session.into_parts().1 == SessionStatus::Unchanged);
+    }
+
+    #[test]
+    fn inserted_values_can_be_read_back() {
+        let session = Session::new(SessionState::new());
+
+        session.insert("user_id", 42).unwrap();
+
+        assert_eq!(session.get::<i32>("user_id").unwrap(), Some(42));
+        assert!(session.into_parts().1 == SessionStatus::Changed);
+    }
+
+    #[test]
+    fn a_renewed_session_stays_renewed_after_changes() {
+        let session = Session::new(SessionState::new());
+
+        session.renew();
+        session.insert("user_id", 42).unwrap();
+
+        assert!(session.into_parts().1 == SessionStatus::Renewed);
+    }
+
+    #[test]
+    fn flash_messages_are_only_taken_once() {
+        let session = Session::new(SessionState::new());
+        session
+            .add_flash_message(FlashMessage::error("Invalid credentials."))
+            .unwrap();
+
+        assert_eq!(
+            session.take_flash_messages().unwrap(),
+            vec![FlashMessage::error("Invalid credentials.")]
+        );
+        assert_eq!(session.take_flash_messages().unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::session::extractor::SessionStatus;
+use crate::session::{Session, SessionState, SessionStore};
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::HttpMessage;
+use chrono::Duration;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+const SESSION_COOKIE_NAME: &str = "session_id";
+
+// Cookieにはセッションのidだけを暗号化(認証付き)して保存し、中身はSessionStoreに保存する
+#[derive(Clone)]
+pub struct SessionMiddleware {
+    store: Arc<dyn SessionStore>,
+    key: Key,
+    ttl: Duration,
+    cookie_secure: bool,
+}
+
+impl SessionMiddleware {
+    pub fn new(store: Arc<dyn SessionStore>, key: Key, ttl: Duration, cookie_secure: bool) -> Self {
+        Self {
+            store,
+            key,
+            ttl,
+            cookie_secure,
+        }
+    }
+
+    fn session_id_from_cookie(&self, request: &ServiceRequest) -> Option<String> {
+        let cookie = request.cookie(SESSION_COOKIE_NAME)?;
+        // 改ざんされたり、別の鍵で暗号化されたりしたCookieは無視する
+        CookieJar::new()
+            .private(&self.key)
+            .decrypt(cookie)
+            .map(|cookie| cookie.value().to_owned())
+    }
+
+    fn session_cookie(&self, session_id: String) -> Cookie<'static> {
+        let cookie = Cookie::build(SESSION_COOKIE_NAME, session_id)
+            .path("/")
+            .http_only(true)
+            .secure(self.cookie_secure)
+            .same_site(SameSite::Lax)
+            .max_age(actix_web::cookie::time::Duration::seconds(
+                self.ttl.num_seconds(),
+            ))
+            .finish();
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.key).add(cookie);
+        jar.get(SESSION_COOKIE_NAME)
+            .expect("The session cookie was just added.")
+            .clone()
+    }
+
+    async fn load(&self, session_id: &Option<String>) -> Result<SessionState, actix_web::Error> {
+        let session_id = match session_id {
+            Some(session_id) => session_id,
+            None => return Ok(SessionState::new()),
+        };
+
+        let state = self.store.load(session_id).await.map_err(|e| {
+            tracing::error!("Failed to load the session: {:?}", e);
+            actix_web::error::ErrorInternalServerError("")
+        })?;
+
+        Ok(state.unwrap_or_default())
+    }
+
+    // 変更されたセッションを保存し、必要に応じてCookieを更新する
+    async fn persist<B>(
+        &self,
+        session_id: Option<String>,
+        session: Session,
+        response: &mut ServiceResponse<B>,
+    ) -> Result<(), actix_web::Error> {
+        let (state, status) = session.into_parts();
+        if status == SessionStatus::Unchanged {
+            return Ok(());
+        }
+
+        let to_error = |e| {
+            tracing::error!("Failed to save the session: {:?}", e);
+            actix_web::error::ErrorInternalServerError("")
+        };
+
+        let mut session_id = session_id;
+        if status == SessionStatus::Renewed {
+            if let Some(old_session_id) = session_id.take() {
+                self.store.delete(&old_session_id).await.map_err(to_error)?;
+            }
+        }
+
+        // 空になったセッションは保存せず、Cookieも削除する
+        if state.is_empty() {
+            if let Some(session_id) = session_id {
+                self.store.delete(&session_id).await.map_err(to_error)?;
+            }
+            response
+                .response_mut()
+                .add_removal_cookie(&Cookie::build(SESSION_COOKIE_NAME, "").path("/").finish())?;
+            return Ok(());
+        }
+
+        let session_id = session_id.unwrap_or_else(generate_session_id);
+        self.store
+            .save(&session_id, &state, self.ttl)
+            .await
+            .map_err(to_error)?;
+        response
+            .response_mut()
+            .add_cookie(&self.session_cookie(session_id))?;
+
+        Ok(())
+    }
+}
+
+fn generate_session_id() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(64)
+        .collect()
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SessionMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = SessionMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionMiddlewareService {
+            service: Rc::new(service),
+            middleware: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct SessionMiddlewareService<S> {
+    service: Rc<S>,
+    middleware: Rc<SessionMiddleware>,
+}
+
+impl<S, B> Service<ServiceRequest> for SessionMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let middleware = Rc::clone(&self.middleware);
+
+        Box::pin(async move {
+            let session_id = middleware.session_id_from_cookie(&request);
+            let state = middleware.load(&session_id).await?;
+            let session = Session::new(state);
+            request.extensions_mut().insert(session.clone());
+
+            let mut response = service.call(request).await?;
+            middleware
+                .persist(session_id, session, &mut response)
+                .await?;
+
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,9 @@
+mod extractor;
+mod middleware;
+mod store;
+
+pub use extractor::{FlashLevel, FlashMessage, Session};
+pub use middleware::SessionMiddleware;
+pub use store::{
+    InMemorySessionStore, PostgresSessionStore, SessionError, SessionState, SessionStore,
+};
@@ -0,0 +1,160 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// セッションに保存する値(値はJSONにシリアライズした文字列)
+pub type SessionState = HashMap<String, String>;
+
+pub type SessionError = Box<dyn std::error::Error + Send + Sync>;
+
+// セッションの保存先は設定で切り替える
+#[async_trait::async_trait]
+pub trait SessionStore: Send + Sync {
+    // 存在しないか、有効期限が切れている場合はNoneを返す
+    async fn load(&self, session_id: &str) -> Result<Option<SessionState>, SessionError>;
+
+    async fn save(
+        &self,
+        session_id: &str,
+        state: &SessionState,
+        ttl: Duration,
+    ) -> Result<(), SessionError>;
+
+    async fn delete(&self, session_id: &str) -> Result<(), SessionError>;
+}
+
+pub struct PostgresSessionStore {
+    pool: PgPool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for PostgresSessionStore {
+    async fn load(&self, session_id: &str) -> Result<Option<SessionState>, SessionError> {
+        let r = sqlx::query!(
+            r#"SELECT state FROM sessions WHERE session_id = $1 AND expires_at > now()"#,
+            session_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match r {
+            Some(r) => Ok(Some(serde_json::from_str(&r.state)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn save(
+        &self,
+        session_id: &str,
+        state: &SessionState,
+        ttl: Duration,
+    ) -> Result<(), SessionError> {
+        sqlx::query!(
+            r#"INSERT INTO sessions (session_id, state, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (session_id) DO UPDATE
+            SET state = EXCLUDED.state, expires_at = EXCLUDED.expires_at"#,
+            session_id,
+            serde_json::to_string(state)?,
+            Utc::now() + ttl
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, session_id: &str) -> Result<(), SessionError> {
+        sqlx::query!(r#"DELETE FROM sessions WHERE session_id = $1"#, session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// セッションの値と有効期限
+type StoredSession = (SessionState, DateTime<Utc>);
+
+// テスト用に、セッションをメモリ上に保存する
+#[derive(Clone, Default)]
+pub struct InMemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, StoredSession>>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn load(&self, session_id: &str) -> Result<Option<SessionState>, SessionError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(session_id)
+            .filter(|(_, expires_at)| *expires_at > Utc::now())
+            .map(|(state, _)| state.clone()))
+    }
+
+    async fn save(
+        &self,
+        session_id: &str,
+        state: &SessionState,
+        ttl: Duration,
+    ) -> Result<(), SessionError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.to_owned(), (state.clone(), Utc::now() + ttl));
+
+        Ok(())
+    }
+
+    async fn delete(&self, session_id: &str) -> Result<(), SessionError> {
+        self.sessions.lock().unwrap().remove(session_id);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InMemorySessionStore, SessionState, SessionStore};
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn a_saved_session_can_be_loaded_until_it_is_deleted() {
+        let store = InMemorySessionStore::new();
+        let state = SessionState::from([("user_id".to_string(), "\"42\"".to_string())]);
+
+        store
+            .save("id", &state, Duration::minutes(5))
+            .await
+            .unwrap();
+        assert_eq!(store.load("id").await.unwrap(), Some(state));
+
+        store.delete("id").await.unwrap();
+        assert_eq!(store.load("id").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn an_expired_session_is_not_loaded() {
+        let store = InMemorySessionStore::new();
+
+        store
+            .save("id", &SessionState::new(), Duration::minutes(-1))
+            .await
+            .unwrap();
+
+        assert_eq!(store.load("id").await.unwrap(), None);
+    }
+}
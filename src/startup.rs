@@ -1,35 +1,67 @@
-use crate::configuration::{DatabaseSettings, Settings};
-use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, subscribe};
-use actix_web::dev::Server;
-use actix_web::{web, App, HttpServer};
+use crate::authentication::{ensure_initial_admin, Permission, RequireLogin, RequirePermission};
+use crate::configuration::{ApplicationSettings, DatabaseSettings, Settings};
+use crate::email_client::EmailSender;
+use crate::email_outbox::run_dispatcher_until_stopped;
+use crate::email_templates::EmailTemplates;
+use crate::housekeeping::{run_housekeeping_job_until_stopped, HousekeepingJob};
+use crate::issue_delivery_worker::run_worker_until_stopped;
+use crate::pending_subscribers::{run_pending_subscriber_job_until_stopped, PendingSubscriberJob};
+use crate::routes::{
+    admin_dashboard, admin_newsletter_form, admin_preview_newsletter, admin_publish_newsletter,
+    admin_subscribers, confirm, confirmation_page, create_api_key, create_subscriber,
+    delete_subscriber, health_check, list_api_keys, list_subscribers, login, login_form, logout,
+    preferences_form, publish_newsletter, resend_confirmation, revoke_api_key, subscribe,
+    subscriber_counts, unsubscribe, unsubscribe_page, update_preferences, PreferencesLinkSigner,
+};
+use crate::subscription_tokens::hash_plaintext_tokens;
+use actix_web::dev::{HttpServiceFactory, Server};
+use actix_web::http::Method;
+use actix_web::{guard, web, App, FromRequest, Handler, HttpServer, Responder, Route};
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 
 pub struct Application {
     port: u16,
     server: Server,
+    connection_pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    email_templates: Arc<EmailTemplates>,
+    base_url: String,
+    preferences_link_signer: PreferencesLinkSigner,
+    pending_subscriber_job: PendingSubscriberJob,
+    housekeeping_job: HousekeepingJob,
 }
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
 
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address");
+        // 以前のバージョンで平文のまま保存されたトークンをハッシュ化する
+        hash_plaintext_tokens(
+            &connection_pool,
+            &configuration.application.subscription_token_hasher(),
+        )
+        .await
+        .expect("Failed to hash plaintext subscription tokens.");
+
+        ensure_initial_admin(&connection_pool, &configuration.application.initial_admin)
+            .await
+            .expect("Failed to create the initial admin.");
 
-        let timeout = configuration.email_client.timeout();
+        let email_client = configuration.email_client.client();
 
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.api_key,
-            timeout,
+        // 必須テンプレートが欠けている場合は起動しない
+        let email_templates = Arc::new(
+            EmailTemplates::from_directory(&configuration.application.templates_directory)
+                .expect("Failed to load email templates."),
         );
 
+        let preferences_link_signer = configuration.application.preferences_link_signer();
+        let pending_subscriber_job = configuration.application.pending_subscriber_job();
+        let housekeeping_job = configuration.application.housekeeping_job();
+
         let address = format!(
             "{}:{}",
             configuration.application.host, configuration.application.port
@@ -39,12 +71,23 @@ impl Application {
         let port = listener.local_addr().unwrap().port();
         let server = run(
             listener,
-            connection_pool,
-            email_client,
-            configuration.application.base_url,
+            connection_pool.clone(),
+            email_client.clone(),
+            email_templates.clone(),
+            &configuration.application,
         )?;
 
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            connection_pool,
+            email_client,
+            email_templates,
+            base_url: configuration.application.base_url,
+            preferences_link_signer,
+            pending_subscriber_job,
+            housekeeping_job,
+        })
     }
 
     pub fn port(&self) -> u16 {
@@ -52,32 +95,108 @@ impl Application {
     }
 
     // この関数はアプリケーションが停止したときのみ返される
+    // HTTPサーバ、ニュースレター配信ワーカー、outboxのディスパッチャ、
+    // 確認待ちのsubscriberを整理するジョブ、不要になった行を削除するジョブを並行して動かし、
+    // いずれかが停止した時点で終了する
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let pending_subscriber_job = run_pending_subscriber_job_until_stopped(
+            self.connection_pool.clone(),
+            self.email_templates.clone(),
+            self.pending_subscriber_job,
+        );
+        let housekeeping_job =
+            run_housekeeping_job_until_stopped(self.connection_pool.clone(), self.housekeeping_job);
+        let worker = run_worker_until_stopped(
+            self.connection_pool.clone(),
+            self.email_client.clone(),
+            self.email_templates,
+            self.base_url,
+            self.preferences_link_signer,
+        );
+        let dispatcher = run_dispatcher_until_stopped(self.connection_pool, self.email_client);
+
+        tokio::select! {
+            outcome = self.server => outcome,
+            outcome = worker => outcome,
+            outcome = dispatcher => outcome,
+            outcome = pending_subscriber_job => outcome,
+            outcome = housekeeping_job => outcome,
+        }
     }
 }
 
 pub struct ApplicationBaseUrl(pub String);
 
+// 確認メールのトークンの有効期間
+pub struct SubscriptionTokenTtl(pub chrono::Duration);
+
+// 確認リンクへのGETだけで購読を確認するかどうか
+pub struct ConfirmOnGet(pub bool);
+
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    email_client: Arc<dyn EmailSender>,
+    email_templates: Arc<EmailTemplates>,
+    application: &ApplicationSettings,
 ) -> Result<Server, std::io::Error> {
     let db_pool = web::Data::new(db_pool);
-    let email_client = web::Data::new(email_client);
-    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let email_client = web::Data::from(email_client);
+    let email_templates = web::Data::from(email_templates);
+    let base_url = web::Data::new(ApplicationBaseUrl(application.base_url.clone()));
+    let preferences_link_signer = web::Data::new(application.preferences_link_signer());
+    let subscription_token_ttl = web::Data::new(SubscriptionTokenTtl(chrono::Duration::hours(
+        application.subscription_token_ttl_hours,
+    )));
+    let subscription_token_hasher = web::Data::new(application.subscription_token_hasher());
+    let resend_confirmation_settings = web::Data::new(application.resend_confirmation.clone());
+    let session_middleware = application.session_middleware(db_pool.get_ref().clone());
+    let confirm_on_get = web::Data::new(ConfirmOnGet(application.confirm_on_get));
+    let confirmation_redirects = web::Data::new(
+        application
+            .confirmation_redirects()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
 
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(session_middleware.clone())
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
             .route("/subscriptions", web::post().to(subscribe))
-            .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/subscriptions/confirm", web::get().to(confirmation_page))
+            .route("/subscriptions/confirm", web::post().to(confirm))
+            .route(
+                "/subscriptions/resend-confirmation",
+                web::post().to(resend_confirmation),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(unsubscribe_page),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route(
+                "/subscriptions/preferences",
+                web::get().to(preferences_form),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(update_preferences),
+            )
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .route("/logout", web::post().to(logout))
+            .configure(configure_admin_routes)
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
+            .app_data(email_templates.clone())
             .app_data(base_url.clone())
+            .app_data(preferences_link_signer.clone())
+            .app_data(subscription_token_ttl.clone())
+            .app_data(subscription_token_hasher.clone())
+            .app_data(resend_confirmation_settings.clone())
+            .app_data(confirm_on_get.clone())
+            .app_data(confirmation_redirects.clone())
     })
     .listen(listener)?
     .run();
@@ -85,6 +204,119 @@ pub fn run(
     Ok(server)
 }
 
+// ブラウザから使う管理画面(/admin以下)
+// ログインしていない場合はログインページへリダイレクトする
+// ルートを追加するときは、必要な権限も合わせてここで宣言する
+pub fn admin_routes() -> Vec<AdminRoute> {
+    vec![
+        AdminRoute::get("/dashboard", admin_dashboard).requires(Permission::ReadSubscribers),
+        AdminRoute::get("/subscribers", admin_subscribers).requires(Permission::ReadSubscribers),
+        AdminRoute::get("/newsletters", admin_newsletter_form).requires(Permission::DraftIssues),
+        AdminRoute::post("/newsletters/preview", admin_preview_newsletter)
+            .requires(Permission::DraftIssues),
+        AdminRoute::post("/newsletters", admin_publish_newsletter)
+            .requires(Permission::PublishIssues),
+    ]
+}
+
+// 管理者向けのAPI
+// 管理者のほか、ルートの権限に対応するスコープを持つAPIキーでも呼び出せる
+// ルートを追加するときは、必要な権限も合わせてここで宣言する
+pub fn admin_api_routes() -> Vec<AdminRoute> {
+    vec![
+        AdminRoute::post("/newsletters", publish_newsletter).requires(Permission::PublishIssues),
+        AdminRoute::get("/subscribers", list_subscribers).requires(Permission::ReadSubscribers),
+        AdminRoute::get("/subscribers/counts", subscriber_counts)
+            .requires(Permission::ReadSubscribers),
+        AdminRoute::post("/subscribers", create_subscriber).requires(Permission::WriteSubscribers),
+        AdminRoute::delete("/subscribers/{subscriber_id}", delete_subscriber)
+            .requires(Permission::DeleteSubscribers),
+        AdminRoute::post("/api-keys", create_api_key).requires(Permission::ManageApiKeys),
+        AdminRoute::get("/api-keys", list_api_keys).requires(Permission::ManageApiKeys),
+        AdminRoute::delete("/api-keys/{api_key_id}", revoke_api_key)
+            .requires(Permission::ManageApiKeys),
+    ]
+}
+
+// 管理者だけが使えるルートと、そのルートに必要な権限
+// 権限を宣言していないルートは、RequirePermissionによって誰にも許可されない
+pub struct AdminRoute {
+    pub method: Method,
+    pub path: &'static str,
+    pub permission: Option<Permission>,
+    route: Route,
+}
+
+impl AdminRoute {
+    pub fn get<F, Args>(path: &'static str, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        Self::new(Method::GET, path, web::get().to(handler))
+    }
+
+    pub fn post<F, Args>(path: &'static str, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        Self::new(Method::POST, path, web::post().to(handler))
+    }
+
+    pub fn delete<F, Args>(path: &'static str, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        Self::new(Method::DELETE, path, web::delete().to(handler))
+    }
+
+    fn new(method: Method, path: &'static str, route: Route) -> Self {
+        Self {
+            method,
+            path,
+            permission: None,
+            route,
+        }
+    }
+
+    pub fn requires(mut self, permission: Permission) -> Self {
+        self.permission = Some(permission);
+        self
+    }
+
+    // 同じパスでもメソッドごとに権限が異なるため、メソッドごとにリソースを分けて登録する
+    fn into_service(self) -> impl HttpServiceFactory {
+        web::resource(self.path)
+            .guard(guard::Method(self.method))
+            .wrap(RequirePermission(self.permission))
+            .route(self.route)
+    }
+}
+
+// 管理者向けのルートはadmin_routesとadmin_api_routesのVecからのみ登録する
+// /adminのスコープを外から触れないようにして、権限を宣言せずにルートを足せないようにする
+fn configure_admin_routes(config: &mut web::ServiceConfig) {
+    config.service(
+        web::scope("/admin")
+            .wrap(RequireLogin)
+            .configure(register_admin_routes(admin_routes())),
+    );
+    register_admin_routes(admin_api_routes())(config);
+}
+
+fn register_admin_routes(routes: Vec<AdminRoute>) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |config| {
+        for route in routes {
+            config.service(route.into_service());
+        }
+    }
+}
+
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
     PgPool::connect_lazy(&configuration.connection_string())
         .expect("Failed to connect to Postgres.")
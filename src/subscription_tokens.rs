@@ -0,0 +1,152 @@
+use crate::routes::generate_subscription_token;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// 確認メールのトークンは、サーバの鍵によるHMAC-SHA256としてのみ保存する
+// DBやバックアップを読めても、トークンを復元して購読を確認することはできない
+// 鍵はhmac_secretから、この用途のためだけに導出したものを使う(KeyPurpose::SubscriptionTokens)
+#[derive(Clone)]
+pub struct SubscriptionTokenHasher {
+    key: Vec<u8>,
+}
+
+impl SubscriptionTokenHasher {
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key }
+    }
+
+    pub fn hash(&self, subscription_token: &str) -> String {
+        hex::encode(self.mac(subscription_token).finalize().into_bytes())
+    }
+
+    // 保存したハッシュとの比較は定数時間で行う
+    pub fn verify(&self, subscription_token: &str, hash: &str) -> bool {
+        let hash = match hex::decode(hash) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        self.mac(subscription_token).verify_slice(&hash).is_ok()
+    }
+
+    fn mac(&self, subscription_token: &str) -> Hmac<Sha256> {
+        // HMACはどの長さの鍵でも受け付けるため、失敗しない
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(subscription_token.as_bytes());
+        mac
+    }
+}
+
+// トークンは`<トークンID>.<ランダムな値>`の形式で発行する
+// 行はIDで引くため、秘密の値やそのハッシュをDBの検索に使わない
+pub fn generate_token(token_id: Uuid) -> String {
+    format!("{}.{}", token_id, generate_subscription_token())
+}
+
+// IDを含まないトークンは、IDを含む形式にする前に発行されたもの
+pub fn token_id(subscription_token: &str) -> Option<Uuid> {
+    let (token_id, _) = subscription_token.split_once('.')?;
+    Uuid::parse_str(token_id).ok()
+}
+
+// ハッシュ化して保存するようになる前に発行された、平文のトークンをハッシュ化する
+// 起動時に毎回呼ばれるが、平文の行が残っていなければ何もしない
+#[tracing::instrument(name = "Hash plaintext subscription tokens", skip_all)]
+pub async fn hash_plaintext_tokens(
+    pool: &PgPool,
+    hasher: &SubscriptionTokenHasher,
+) -> Result<usize, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let rows = sqlx::query!(
+        r#"SELECT subscription_token_hash AS subscription_token
+        FROM subscription_tokens
+        WHERE is_plaintext
+        FOR UPDATE"#
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    for row in &rows {
+        sqlx::query!(
+            r#"UPDATE subscription_tokens
+            SET subscription_token_hash = $2, is_plaintext = false
+            WHERE subscription_token_hash = $1"#,
+            row.subscription_token,
+            hasher.hash(&row.subscription_token)
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    }
+
+    transaction.commit().await?;
+
+    if !rows.is_empty() {
+        tracing::info!("Hashed {} plaintext subscription tokens", rows.len());
+    }
+
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_token, token_id, SubscriptionTokenHasher};
+    use uuid::Uuid;
+
+    #[test]
+    fn the_same_token_always_has_the_same_hash() {
+        let hasher = SubscriptionTokenHasher::new(b"key".to_vec());
+        let hash = hasher.hash("token");
+
+        assert_ne!(hash, "token");
+        assert_eq!(hash, hasher.hash("token"));
+    }
+
+    #[test]
+    fn a_different_token_has_a_different_hash() {
+        let hasher = SubscriptionTokenHasher::new(b"key".to_vec());
+
+        assert_ne!(hasher.hash("other-token"), hasher.hash("token"));
+    }
+
+    #[test]
+    fn a_different_key_gives_a_different_hash() {
+        let hasher = SubscriptionTokenHasher::new(b"key".to_vec());
+        let other = SubscriptionTokenHasher::new(b"other-key".to_vec());
+
+        assert_ne!(hasher.hash("token"), other.hash("token"));
+    }
+
+    #[test]
+    fn a_token_is_verified_against_its_hash() {
+        let hasher = SubscriptionTokenHasher::new(b"key".to_vec());
+        let hash = hasher.hash("token");
+
+        assert!(hasher.verify("token", &hash));
+        assert!(!hasher.verify("other-token", &hash));
+        assert!(!hasher.verify("token", "not-hex"));
+    }
+
+    #[test]
+    fn a_generated_token_carries_its_id() {
+        let id = Uuid::new_v4();
+
+        assert_eq!(token_id(&generate_token(id)), Some(id));
+        assert_ne!(generate_token(id), generate_token(id));
+    }
+
+    #[test]
+    fn a_token_without_an_id_has_no_id() {
+        assert_eq!(token_id("legacy-plaintext-token"), None);
+        assert_eq!(token_id("not-a-uuid.secret"), None);
+    }
+}
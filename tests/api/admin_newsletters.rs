@@ -0,0 +1,184 @@
+use crate::admin_subscribers::insert_subscriber;
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use chrono::Duration;
+use uuid::Uuid;
+
+fn newsletter_form(idempotency_key: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "idempotency_key": idempotency_key,
+    })
+}
+
+async fn n_queued_deliveries(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count the queued deliveries.")
+        .count
+}
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_publish_from_the_admin_area() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin_newsletters(&newsletter_form(&Uuid::new_v4().to_string()))
+        .await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert_eq!(n_queued_deliveries(&app).await, 0);
+}
+
+#[actix_rt::test]
+async fn the_form_embeds_a_fresh_idempotency_key() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let first = app.get_admin_newsletters_html().await;
+    let second = app.get_admin_newsletters_html().await;
+
+    assert!(first.contains(r#"name="idempotency_key""#));
+    assert_ne!(first, second);
+}
+
+#[actix_rt::test]
+async fn a_preview_shows_the_issue_without_publishing_it() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "a@example.com", "a", "confirmed", Duration::zero()).await;
+    app.login_as_test_user().await;
+
+    let response = app
+        .post_admin_newsletter_preview(&newsletter_form(&Uuid::new_v4().to_string()))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("<h2>Newsletter title</h2>"));
+    assert!(html_page.contains("<pre>Newsletter body as plain text</pre>"));
+    // HTML版はエスケープしてiframeのsrcdocに埋め込まれる
+    assert!(html_page.contains(&format!(
+        r#"srcdoc="{}""#,
+        htmlescape::encode_attribute("<p>Newsletter body as HTML</p>")
+    )));
+    assert_eq!(n_queued_deliveries(&app).await, 0);
+}
+
+#[actix_rt::test]
+async fn publishing_from_the_admin_area_enqueues_deliveries() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "a@example.com", "a", "confirmed", Duration::zero()).await;
+    insert_subscriber(
+        &app,
+        "b@example.com",
+        "b",
+        "pending_confirmation",
+        Duration::zero(),
+    )
+    .await;
+    app.login_as_test_user().await;
+
+    let response = app
+        .post_admin_newsletters(&newsletter_form(&Uuid::new_v4().to_string()))
+        .await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+
+    let html_page = app.get_admin_newsletters_html().await;
+    assert!(html_page
+        .contains(r#"<p class="info"><i>The newsletter issue has been published!</i></p>"#));
+    assert_eq!(n_queued_deliveries(&app).await, 1);
+}
+
+#[actix_rt::test]
+async fn submitting_the_same_form_twice_publishes_once() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "a@example.com", "a", "confirmed", Duration::zero()).await;
+    app.login_as_test_user().await;
+    let form = newsletter_form(&Uuid::new_v4().to_string());
+
+    let response = app.post_admin_newsletters(&form).await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    app.get_admin_newsletters_html().await;
+    let response = app.post_admin_newsletters(&form).await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+
+    // 二度目の送信では発行していないため、発行したとは表示しない
+    let html_page = app.get_admin_newsletters_html().await;
+    assert!(!html_page.contains("The newsletter issue has been published!"));
+    assert!(html_page.contains(
+        r#"<p class="error"><i>This newsletter issue has already been published.</i></p>"#
+    ));
+
+    let n_issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count the newsletter issues.")
+        .count;
+    assert_eq!(n_issues, 1);
+    assert_eq!(n_queued_deliveries(&app).await, 1);
+}
+
+#[actix_rt::test]
+async fn reusing_a_form_with_different_content_shows_an_error() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    let idempotency_key = Uuid::new_v4().to_string();
+    let form = newsletter_form(&idempotency_key);
+    app.post_admin_newsletters(&form).await;
+    app.get_admin_newsletters_html().await;
+
+    let mut changed_form = form.clone();
+    changed_form["title"] = "Another title".into();
+    let response = app.post_admin_newsletters(&changed_form).await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+
+    let html_page = app.get_admin_newsletters_html().await;
+    assert!(!html_page.contains("The newsletter issue has been published!"));
+    assert!(html_page.contains("The form was already submitted with different content."));
+}
+
+#[actix_rt::test]
+async fn an_incomplete_issue_is_not_published() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = app
+        .post_admin_newsletters(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("The title and both contents are required."));
+    // 入力した内容はフォームに残る
+    assert!(html_page.contains(&format!(
+        r#"value="{}""#,
+        htmlescape::encode_attribute("Newsletter title")
+    )));
+}
+
+#[actix_rt::test]
+async fn an_issue_with_an_unknown_topic_is_not_published() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    let mut form = newsletter_form(&Uuid::new_v4().to_string());
+    form["topic"] = "gossip".into();
+
+    let response = app.post_admin_newsletters(&form).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("gossip is not a valid topic."));
+    let n_issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count the newsletter issues.")
+        .count;
+    assert_eq!(n_issues, 0);
+}
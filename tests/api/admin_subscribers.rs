@@ -0,0 +1,234 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+pub async fn insert_subscriber(
+    app: &TestApp,
+    email: &str,
+    name: &str,
+    status: &str,
+    age: Duration,
+) {
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        Uuid::new_v4(),
+        email,
+        name,
+        Utc::now() - age,
+        status,
+        Uuid::new_v4().to_string()
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert a subscriber.");
+}
+
+// リンクのクエリ文字列は属性値としてエスケープされる
+fn page_link(query: &str, label: &str) -> String {
+    format!(
+        r#"<a href="/admin/subscribers?{}">{}</a>"#,
+        htmlescape::encode_attribute(query),
+        label
+    )
+}
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_see_the_subscriber_list() {
+    let app = spawn_app().await;
+
+    let response = app.get_admin_subscribers("").await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[actix_rt::test]
+async fn the_dashboard_shows_counts_per_status() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "a@example.com", "a", "confirmed", Duration::zero()).await;
+    insert_subscriber(&app, "b@example.com", "b", "confirmed", Duration::zero()).await;
+    insert_subscriber(&app, "c@example.com", "c", "unsubscribed", Duration::zero()).await;
+    app.login_as_test_user().await;
+
+    let html_page = app.get_admin_dashboard().await.text().await.unwrap();
+
+    assert!(html_page.contains(
+        r#"<tr><td><a href="/admin/subscribers?status=confirmed">confirmed</a></td><td>2</td></tr>"#
+    ));
+    assert!(html_page.contains(
+        r#"<tr><td><a href="/admin/subscribers?status=unsubscribed">unsubscribed</a></td><td>1</td></tr>"#
+    ));
+    assert!(html_page.contains(
+        r#"<tr><td><a href="/admin/subscribers?status=bounced">bounced</a></td><td>0</td></tr>"#
+    ));
+    assert!(html_page.contains("<tr><th>Total</th><th>3</th></tr>"));
+}
+
+#[actix_rt::test]
+async fn subscribers_can_be_filtered_by_status_and_searched() {
+    let app = spawn_app().await;
+    insert_subscriber(
+        &app,
+        "ursula@example.com",
+        "le guin",
+        "confirmed",
+        Duration::zero(),
+    )
+    .await;
+    insert_subscriber(
+        &app,
+        "octavia@example.com",
+        "butler",
+        "confirmed",
+        Duration::zero(),
+    )
+    .await;
+    insert_subscriber(
+        &app,
+        "ursula@old.example.com",
+        "le guin",
+        "unsubscribed",
+        Duration::zero(),
+    )
+    .await;
+    app.login_as_test_user().await;
+
+    let response = app
+        .get_admin_subscribers("status=confirmed&search=URSULA")
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+
+    assert!(html_page.contains("<td>ursula@example.com</td>"));
+    assert!(!html_page.contains("octavia@example.com"));
+    assert!(!html_page.contains("ursula@old.example.com"));
+    assert!(html_page.contains("<p>1 subscribers found</p>"));
+}
+
+#[actix_rt::test]
+async fn search_terms_are_escaped_in_the_subscriber_list() {
+    let app = spawn_app().await;
+    insert_subscriber(
+        &app,
+        "a@example.com",
+        "<script>",
+        "confirmed",
+        Duration::zero(),
+    )
+    .await;
+    app.login_as_test_user().await;
+
+    let html_page = app
+        .get_admin_subscribers("search=%3Cscript%3E")
+        .await
+        .text()
+        .await
+        .unwrap();
+
+    assert!(!html_page.contains("<script>"));
+    assert!(html_page.contains("&lt;script&gt;"));
+}
+
+#[actix_rt::test]
+async fn subscribers_are_paginated_newest_first() {
+    let app = spawn_app().await;
+    for i in 0..25 {
+        insert_subscriber(
+            &app,
+            &format!("subscriber{:02}@example.com", i),
+            "name",
+            "confirmed",
+            Duration::minutes(i),
+        )
+        .await;
+    }
+    app.login_as_test_user().await;
+
+    let first_page = app.get_admin_subscribers("").await.text().await.unwrap();
+    assert!(first_page.contains("subscriber00@example.com"));
+    assert!(first_page.contains("subscriber19@example.com"));
+    assert!(!first_page.contains("subscriber20@example.com"));
+    assert!(first_page.contains("Page 1 of 2"));
+    assert!(first_page.contains(&page_link("page=2", "Next")));
+
+    let second_page = app
+        .get_admin_subscribers("page=2")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(second_page.contains("subscriber20@example.com"));
+    assert!(second_page.contains("subscriber24@example.com"));
+    assert!(!second_page.contains("subscriber19@example.com"));
+    assert!(second_page.contains(&page_link("page=1", "Previous")));
+}
+
+#[actix_rt::test]
+async fn an_unknown_status_filter_is_rejected() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = app.get_admin_subscribers("status=active").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[actix_rt::test]
+async fn the_json_api_returns_the_same_subscriber_page() {
+    let app = spawn_app().await;
+    insert_subscriber(
+        &app,
+        "ursula@example.com",
+        "le guin",
+        "confirmed",
+        Duration::zero(),
+    )
+    .await;
+    insert_subscriber(
+        &app,
+        "octavia@example.com",
+        "butler",
+        "pending_confirmation",
+        Duration::zero(),
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscribers?status=confirmed", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+
+    assert_eq!(body["total"], 1);
+    assert_eq!(body["page"], 1);
+    assert_eq!(body["subscribers"][0]["email"], "ursula@example.com");
+    assert_eq!(body["subscribers"][0]["status"], "confirmed");
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscribers/counts", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    let counts: serde_json::Value = response.json().await.unwrap();
+    assert!(counts
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!({"status": "pending_confirmation", "count": 1})));
+}
+
+#[actix_rt::test]
+async fn the_json_api_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscribers", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(response.status().as_u16(), 401);
+}
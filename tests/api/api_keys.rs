@@ -0,0 +1,373 @@
+use crate::admin_subscribers::insert_subscriber;
+use crate::helpers::{spawn_app, TestApp, TestUser};
+use api::authentication::Role;
+use chrono::Duration;
+use uuid::Uuid;
+
+// テスト用の管理者(owner)でAPIキーを作成し、(api_key_id, api_key)を返す
+async fn create_api_key(app: &TestApp, scopes: &[&str]) -> (String, String) {
+    let response = post_api_keys(
+        app,
+        &app.test_user,
+        serde_json::json!({ "name": "cms", "scopes": scopes }),
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    (
+        body["api_key_id"].as_str().unwrap().to_owned(),
+        body["api_key"].as_str().unwrap().to_owned(),
+    )
+}
+
+async fn post_api_keys(
+    app: &TestApp,
+    user: &TestUser,
+    body: serde_json::Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/api-keys", &app.address))
+        .basic_auth(&user.username, Some(&user.password))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+async fn get_subscribers_with(app: &TestApp, api_key: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/subscribers", &app.address))
+        .bearer_auth(api_key)
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+async fn post_newsletters_with(app: &TestApp, api_key: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .bearer_auth(api_key)
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+#[actix_rt::test]
+async fn api_keys_are_stored_hashed() {
+    let app = spawn_app().await;
+
+    let (api_key_id, api_key) = create_api_key(&app, &["subscribers:read"]).await;
+
+    let saved = sqlx::query!(
+        "SELECT key_hash, scopes FROM api_keys WHERE api_key_id = $1",
+        Uuid::parse_str(&api_key_id).unwrap()
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the saved API key.");
+    assert_ne!(saved.key_hash, api_key);
+    assert!(!saved.key_hash.contains(&api_key));
+    assert_eq!(saved.scopes, vec!["subscribers:read".to_string()]);
+}
+
+#[actix_rt::test]
+async fn a_key_can_call_the_routes_its_scopes_cover() {
+    let app = spawn_app().await;
+    let (_, api_key) = create_api_key(&app, &["subscribers:read"]).await;
+
+    let response = get_subscribers_with(&app, &api_key).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = post_newsletters_with(&app, &api_key).await;
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[actix_rt::test]
+async fn a_key_with_the_publish_scope_can_publish() {
+    let app = spawn_app().await;
+    let (_, api_key) = create_api_key(&app, &["issues:publish"]).await;
+
+    let response = post_newsletters_with(&app, &api_key).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn a_key_with_the_delete_scope_can_delete_subscribers() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "a@example.com", "a", "confirmed", Duration::zero()).await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.")
+        .id;
+    let (_, api_key) = create_api_key(&app, &["subscribers:delete"]).await;
+
+    let delete_subscriber = |id: Uuid| {
+        reqwest::Client::new()
+            .delete(format!("{}/subscribers/{}", &app.address, id))
+            .bearer_auth(&api_key)
+            .send()
+    };
+    let response = delete_subscriber(subscriber_id).await.unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+    let response = delete_subscriber(Uuid::new_v4()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    // 行ごと削除され、個人情報は残らない
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch subscribers.");
+    assert!(saved.is_empty());
+    let response = delete_subscriber(subscriber_id).await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[actix_rt::test]
+async fn a_key_with_the_write_scope_can_create_but_not_delete_subscribers() {
+    let app = spawn_app().await;
+    let (_, api_key) = create_api_key(&app, &["subscribers:write"]).await;
+    let create_subscriber = || {
+        reqwest::Client::new()
+            .post(format!("{}/subscribers", &app.address))
+            .bearer_auth(&api_key)
+            .json(&serde_json::json!({
+                "name": "le guin",
+                "email": "ursula_le_guin@gmail.com",
+            }))
+            .send()
+    };
+
+    // 登録済みのアドレスでも、同じレスポンスを返す
+    for _ in 0..2 {
+        let response = create_subscriber().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.text().await.unwrap().is_empty());
+    }
+
+    // 確認待ちとして登録され、登録のたびに確認メールが送られる
+    let saved = sqlx::query!("SELECT id, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.");
+    assert_eq!(saved.status, "pending_confirmation");
+    let subscriber_id = saved.id;
+    let outbox = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.count, 2);
+
+    let response = reqwest::Client::new()
+        .delete(format!("{}/subscribers/{}", &app.address, subscriber_id))
+        .bearer_auth(&api_key)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[actix_rt::test]
+async fn creating_a_subscriber_is_idempotent() {
+    let app = spawn_app().await;
+    let (_, api_key) = create_api_key(&app, &["subscribers:write"]).await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    for _ in 0..2 {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscribers", &app.address))
+            .bearer_auth(&api_key)
+            .header("Idempotency-Key", &idempotency_key)
+            .json(&serde_json::json!({
+                "name": "le guin",
+                "email": "ursula_le_guin@gmail.com",
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    // 同じキーで再送した場合は、確認メールを送り直さない
+    let outbox = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.count, 1);
+}
+
+#[actix_rt::test]
+async fn a_deleted_subscriber_can_sign_up_again() {
+    let app = spawn_app().await;
+    insert_subscriber(
+        &app,
+        "ursula_le_guin@gmail.com",
+        "le guin",
+        "confirmed",
+        Duration::zero(),
+    )
+    .await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.")
+        .id;
+    let (_, api_key) = create_api_key(&app, &["subscribers:delete"]).await;
+    reqwest::Client::new()
+        .delete(format!("{}/subscribers/{}", &app.address, subscriber_id))
+        .bearer_auth(&api_key)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT id, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.");
+    assert_ne!(saved.id, subscriber_id);
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[actix_rt::test]
+async fn the_last_use_of_a_key_is_recorded() {
+    let app = spawn_app().await;
+    let (api_key_id, api_key) = create_api_key(&app, &["subscribers:read"]).await;
+
+    // 転送ヘッダの値ではなく、接続元のアドレスが記録される
+    reqwest::Client::new()
+        .get(format!("{}/subscribers", &app.address))
+        .bearer_auth(&api_key)
+        .header("X-Forwarded-For", "203.0.113.7")
+        .send()
+        .await
+        .expect("Failed to execute request")
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/api-keys", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 200);
+    let api_keys: serde_json::Value = response.json().await.unwrap();
+    let listed = &api_keys[0];
+    assert_eq!(listed["api_key_id"], api_key_id.as_str());
+    assert_eq!(listed["name"], "cms");
+    assert!(!listed["last_used_at"].is_null());
+    assert_eq!(listed["last_used_ip"], "127.0.0.1");
+    // 一覧にはキーもハッシュも含まれない
+    assert!(listed.get("api_key").is_none());
+    assert!(listed.get("key_hash").is_none());
+}
+
+#[actix_rt::test]
+async fn a_revoked_key_is_rejected() {
+    let app = spawn_app().await;
+    let (api_key_id, api_key) = create_api_key(&app, &["subscribers:read"]).await;
+
+    let revoke = || {
+        reqwest::Client::new()
+            .delete(format!("{}/api-keys/{}", &app.address, api_key_id))
+            .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+            .send()
+    };
+    assert_eq!(revoke().await.unwrap().status().as_u16(), 204);
+    // すでに失効しているキー
+    assert_eq!(revoke().await.unwrap().status().as_u16(), 404);
+
+    let response = get_subscribers_with(&app, &api_key).await;
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!("Bearer", response.headers()["WWW-Authenticate"]);
+}
+
+#[actix_rt::test]
+async fn an_unknown_key_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = get_subscribers_with(&app, "z2p_not-a-real-key").await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn a_key_cannot_manage_api_keys() {
+    let app = spawn_app().await;
+    let (_, api_key) = create_api_key(
+        &app,
+        &["subscribers:read", "subscribers:write", "issues:publish"],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/api-keys", &app.address))
+        .bearer_auth(&api_key)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[actix_rt::test]
+async fn only_owners_can_create_api_keys() {
+    let app = spawn_app().await;
+    let publisher = TestUser::generate_with_role(Role::Publisher);
+    publisher.store(&app.db_pool).await;
+
+    let response = post_api_keys(
+        &app,
+        &publisher,
+        serde_json::json!({ "name": "cms", "scopes": ["issues:publish"] }),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[actix_rt::test]
+async fn api_keys_with_invalid_data_are_rejected() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({ "name": "cms", "scopes": ["subscribers:*"] }),
+            "an unknown scope",
+        ),
+        (
+            serde_json::json!({ "name": "cms", "scopes": [] }),
+            "no scopes",
+        ),
+        (
+            serde_json::json!({ "name": " ", "scopes": ["issues:publish"] }),
+            "an empty name",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        let response = post_api_keys(&app, &app.test_user, body).await;
+
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
@@ -1,8 +1,17 @@
-use api::configuration::{get_configuration, DatabaseSettings};
-use api::startup::{get_connection_pool, Application};
+use api::authentication::{compute_password_hash, Role};
+use api::configuration::{get_configuration, DatabaseSettings, SessionStoreKind, Settings};
+use api::email_client::EmailSender;
+use api::email_outbox::try_dispatch_email;
+use api::email_templates::EmailTemplates;
+use api::housekeeping::HousekeepingJob;
+use api::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use api::pending_subscribers::PendingSubscriberJob;
+use api::routes::PreferencesLinkSigner;
+use api::startup::{admin_api_routes, admin_routes, get_connection_pool, Application};
 use api::telemetry::{get_subscriber, init_subscriber};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::sync::Arc;
 use uuid::Uuid;
 use wiremock::MockServer;
 
@@ -27,6 +36,54 @@ pub struct TestApp {
     pub db_pool: PgPool,
     pub email_server: MockServer,
     pub port: u16,
+    pub email_client: Arc<dyn EmailSender>,
+    pub email_templates: EmailTemplates,
+    pub base_url: String,
+    pub preferences_link_signer: PreferencesLinkSigner,
+    pub pending_subscriber_job: PendingSubscriberJob,
+    pub housekeeping_job: HousekeepingJob,
+    pub test_user: TestUser,
+    // ログインのセッションを保持するため、Cookieを保存しリダイレクトを追わないクライアント
+    pub api_client: reqwest::Client,
+}
+
+// ニュースレターの発行などに使う管理者
+// spawn_appで作成する管理者はすべての権限を持つ
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+    pub role: Role,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self::generate_with_role(Role::Owner)
+    }
+
+    pub fn generate_with_role(role: Role) -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+            role,
+        }
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
+        let password_hash = compute_password_hash(&self.password).unwrap();
+        sqlx::query!(
+            r#"INSERT INTO users (user_id, username, password_hash, role, created_at)
+            VALUES ($1, $2, $3, $4, now())"#,
+            self.user_id,
+            self.username,
+            password_hash,
+            self.role.as_str()
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
 }
 
 pub struct ConfirmationLinks {
@@ -35,9 +92,56 @@ pub struct ConfirmationLinks {
 }
 
 impl TestApp {
+    // バックグラウンドのワーカーとは別に、送信待ちのメールがなくなるまで処理する
+    // ワーカーが処理中の行はSKIP LOCKEDで見えないため、テーブルが空になるまで待つ
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_dispatch_email(&self.db_pool, self.email_client.as_ref())
+                    .await
+                    .unwrap()
+            {
+                let remaining = sqlx::query!(
+                    "SELECT COUNT(*) AS count FROM email_outbox WHERE next_attempt_at <= now()"
+                )
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap();
+                if remaining.count == Some(0) {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.db_pool,
+                self.email_client.as_ref(),
+                &self.email_templates,
+                &self.base_url,
+                &self.preferences_link_signer,
+            )
+            .await
+            .unwrap()
+            {
+                let remaining = sqlx::query!(
+                    "SELECT COUNT(*) AS count FROM issue_delivery_queue WHERE deliver_after <= now()"
+                )
+                    .fetch_one(&self.db_pool)
+                    .await
+                    .unwrap();
+                if remaining.count == Some(0) {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -45,6 +149,186 @@ impl TestApp {
             .expect("Failed to execute request")
     }
 
+    pub async fn post_resend_confirmation(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/subscriptions/resend-confirmation",
+                &self.address
+            ))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_subscriptions_with_idempotency_key(
+        &self,
+        body: String,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Idempotency-Key", idempotency_key)
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_confirm(&self, body: String) -> reqwest::Response {
+        no_redirect_client()
+            .post(format!("{}/subscriptions/confirm", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    // 確認リンクのページにあるボタンと同じく、リンクのトークンをPOSTして購読を確認する
+    pub async fn confirm_subscription(&self, confirmation_link: reqwest::Url) -> reqwest::Response {
+        let (_, subscription_token) = confirmation_link
+            .query_pairs()
+            .find(|(key, _)| key == "subscription_token")
+            .expect("The confirmation link has no subscription_token.");
+        no_redirect_client()
+            .post(format!("{}/subscriptions/confirm", &self.address))
+            .form(&[("subscription_token", subscription_token)])
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    // 購読解除のページにあるボタンや、RFC 8058のメールクライアントと同じく、リンクへPOSTする
+    pub async fn unsubscribe(&self, unsubscribe_link: reqwest::Url) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(unsubscribe_link)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_login<Body: serde::Serialize>(&self, body: &Body) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    // テスト用の管理者でログインする
+    pub async fn login_as_test_user(&self) -> reqwest::Response {
+        self.login_as(&self.test_user).await
+    }
+
+    pub async fn login_as(&self, user: &TestUser) -> reqwest::Response {
+        self.post_login(&serde_json::json!({
+            "username": &user.username,
+            "password": &user.password,
+        }))
+        .await
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_subscribers(&self, query: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers?{}", &self.address, query))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_newsletters_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/newsletters", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_admin_newsletters<Body: serde::Serialize>(
+        &self,
+        body: &Body,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/newsletters", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_admin_newsletter_preview<Body: serde::Serialize>(
+        &self,
+        body: &Body,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/newsletters/preview", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
@@ -64,11 +348,94 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(&body["html"].as_str().unwrap());
-        let text = get_link(&body["text"].as_str().unwrap());
+        let html = get_link(body["html"].as_str().unwrap());
+        let text = get_link(body["text"].as_str().unwrap());
 
         ConfirmationLinks { html, text }
     }
+
+    // List-Unsubscribeヘッダ(<URL>の形式)から購読解除用のリンクを取り出す
+    // 一括送信APIへのリクエストの場合は、最初のメッセージから取り出す
+    pub fn get_unsubscribe_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let message = if body.is_array() { &body[0] } else { &body };
+        let headers = message["headers"].as_array().unwrap();
+
+        let header_value = |name: &str| {
+            headers
+                .iter()
+                .find(|h| h["name"] == name)
+                .and_then(|h| h["value"].as_str())
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(
+            header_value("List-Unsubscribe-Post"),
+            "List-Unsubscribe=One-Click"
+        );
+
+        let raw_link = header_value("List-Unsubscribe");
+        let raw_link = raw_link.trim_start_matches('<').trim_end_matches('>');
+        let mut unsubscribe_link = reqwest::Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+
+        // spawn_appで生成したランダムなポート値をセットする
+        unsubscribe_link.set_port(Some(self.port)).unwrap();
+        unsubscribe_link
+    }
+}
+
+// 管理者向けのルートには、すべて必要な権限が宣言されていること
+// 宣言のないルートは誰も使えないため、追加したときの宣言漏れをここで検出する
+pub fn assert_every_admin_route_has_a_permission() {
+    let undeclared: Vec<_> = admin_routes()
+        .into_iter()
+        .map(|route| {
+            (
+                route.method,
+                format!("/admin{}", route.path),
+                route.permission,
+            )
+        })
+        .chain(
+            admin_api_routes()
+                .into_iter()
+                .map(|route| (route.method, route.path.to_string(), route.permission)),
+        )
+        .filter(|(_, _, permission)| permission.is_none())
+        .map(|(method, path, _)| format!("{} {}", method, path))
+        .collect();
+
+    assert!(
+        undeclared.is_empty(),
+        "No permission is declared for: {}",
+        undeclared.join(", ")
+    );
+}
+
+// 一括送信APIへのリクエストに、メッセージごとの成功の結果を返す
+pub fn batch_delivery_response() -> impl wiremock::Respond {
+    |request: &wiremock::Request| {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|_| serde_json::json!({"error_code": 0, "message": "OK"}))
+            .collect();
+        wiremock::ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
+
+// 確認後のリダイレクト先を検証できるよう、リダイレクトを追わないクライアント
+fn no_redirect_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
 }
 
 // INFO: actix_webのテスト用のHelper関数を使えばより簡単に実装できる。
@@ -76,6 +443,11 @@ impl TestApp {
 // そのまま使うことができるので、わざわざtokioを用いてアプリケーションを背後で実行している。
 // TODO: テスト終了時に、作成したDBインスタンスを削除する処理を追加
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+// テストごとに設定を変えてアプリケーションを起動する
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     // メールテスト用のモックサーバを起動
@@ -87,6 +459,12 @@ pub async fn spawn_app() -> TestApp {
         c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
         c.email_client.base_url = email_server.uri();
+        // 再送の待ち時間でテストが遅くならないようにする
+        c.email_client.retry.base_delay_milliseconds = 1;
+        c.email_client.retry.max_delay_milliseconds = 10;
+        // セッションはメモリ上に保存する
+        c.application.session.store = SessionStoreKind::InMemory;
+        configure(&mut c);
         c
     };
 
@@ -98,14 +476,32 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build application.");
     let application_port = application.port();
-    let _ = tokio::spawn(application.run_until_stopped()); // INFO: テスト終了時にサーバは落ちる
+    drop(tokio::spawn(application.run_until_stopped())); // INFO: テスト終了時にサーバは落ちる
 
-    TestApp {
+    let test_app = TestApp {
         address: format!("http://127.0.0.1:{}", application_port),
         db_pool: get_connection_pool(&configuration.database),
         email_server,
         port: application_port,
-    }
+        email_client: configuration.email_client.client(),
+        email_templates: EmailTemplates::from_directory(
+            &configuration.application.templates_directory,
+        )
+        .expect("Failed to load email templates."),
+        preferences_link_signer: configuration.application.preferences_link_signer(),
+        pending_subscriber_job: configuration.application.pending_subscriber_job(),
+        housekeeping_job: configuration.application.housekeeping_job(),
+        base_url: configuration.application.base_url,
+        test_user: TestUser::generate(),
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+
+    test_app
 }
 
 pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
@@ -0,0 +1,59 @@
+use crate::helpers::spawn_app;
+
+// バックグラウンドのジョブが先に削除している場合もあるため、削除した件数ではなく残った行を確かめる
+
+#[actix_rt::test]
+async fn resend_attempts_older_than_the_window_are_purged() {
+    let app = spawn_app().await;
+
+    app.post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await;
+    // base.ymlでは60分の期間で数えるため、それより前の記録を作る
+    sqlx::query!(
+        r#"INSERT INTO confirmation_resend_attempts (throttle_key, attempted_at)
+        VALUES ('email:old@gmail.com', now() - interval '61 minutes')"#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    app.housekeeping_job
+        .purge_resend_attempts(&app.db_pool)
+        .await
+        .unwrap();
+
+    let remaining = sqlx::query!("SELECT throttle_key FROM confirmation_resend_attempts")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    // 期間内の記録(アドレスとIPアドレスの2件)は残る
+    assert_eq!(remaining.len(), 2);
+    assert!(remaining
+        .iter()
+        .all(|r| r.throttle_key != "email:old@gmail.com"));
+}
+
+#[actix_rt::test]
+async fn expired_sessions_are_purged() {
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"INSERT INTO sessions (session_id, state, expires_at)
+        VALUES ('expired', '{}', now() - interval '1 minute'),
+            ('active', '{}', now() + interval '1 hour')"#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    app.housekeeping_job
+        .purge_expired_sessions(&app.db_pool)
+        .await
+        .unwrap();
+
+    let remaining = sqlx::query!("SELECT session_id FROM sessions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].session_id, "active");
+}
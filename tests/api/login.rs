@@ -0,0 +1,142 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, spawn_app_with};
+use api::configuration::SessionStoreKind;
+
+#[actix_rt::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    let app = spawn_app().await;
+
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password",
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/login");
+
+    // リダイレクト先のログインページにメッセージが表示される
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains(r#"<p class="error"><i>Invalid credentials.</i></p>"#));
+
+    // メッセージは一度だけ表示される
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains("Invalid credentials."));
+}
+
+#[actix_rt::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    let app = spawn_app().await;
+
+    let response = app.login_as_test_user().await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    let response = app.get_admin_dashboard().await;
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(&format!("Welcome {}!", app.test_user.username)));
+}
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_access_the_admin_dashboard() {
+    let app = spawn_app().await;
+
+    let response = app.get_admin_dashboard().await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[actix_rt::test]
+async fn logout_clears_session_state() {
+    let app = spawn_app().await;
+
+    app.login_as_test_user().await;
+
+    let response = app.post_logout().await;
+    assert_is_redirect_to(&response, "/login");
+
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains(r#"<p class="info"><i>You have successfully logged out.</i></p>"#));
+
+    // ログアウト後は管理画面にアクセスできない
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[actix_rt::test]
+async fn a_tampered_session_cookie_is_ignored() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(format!("{}/admin/dashboard", &app.address))
+        .header("Cookie", "session_id=not-a-valid-encrypted-session-id")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[actix_rt::test]
+async fn the_session_cookie_is_http_only_and_does_not_expose_the_session_id() {
+    let app = spawn_app_with(|c| c.application.session.store = SessionStoreKind::Postgres).await;
+
+    let response = app.login_as_test_user().await;
+
+    let cookie = response
+        .cookies()
+        .find(|c| c.name() == "session_id")
+        .expect("The session cookie was not set.");
+    assert!(cookie.http_only());
+    let saved = sqlx::query!("SELECT session_id FROM sessions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the saved session.");
+    assert_ne!(cookie.value(), saved.session_id);
+}
+
+#[actix_rt::test]
+async fn the_session_id_is_rotated_on_login() {
+    let app = spawn_app_with(|c| c.application.session.store = SessionStoreKind::Postgres).await;
+
+    // ログインに失敗すると、フラッシュメッセージのためのセッションが作られる
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": "wrong-password",
+    }))
+    .await;
+    let anonymous = sqlx::query!("SELECT session_id FROM sessions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the saved session.");
+
+    app.login_as_test_user().await;
+
+    // ログイン前のセッションは破棄され、別のidでセッションが保存される
+    let sessions = sqlx::query!("SELECT session_id FROM sessions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the saved sessions.");
+    assert_eq!(sessions.len(), 1);
+    assert_ne!(sessions[0].session_id, anonymous.session_id);
+
+    let response = app.get_admin_dashboard().await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn logout_deletes_the_stored_session() {
+    let app = spawn_app_with(|c| c.application.session.store = SessionStoreKind::Postgres).await;
+
+    app.login_as_test_user().await;
+    app.post_logout().await;
+    // フラッシュメッセージを読むと、ログアウト後のセッションも空になる
+    app.get_login_html().await;
+
+    let n_sessions = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM sessions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count the saved sessions.")
+        .count;
+    assert_eq!(n_sessions, 0);
+}
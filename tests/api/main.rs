@@ -1,4 +1,16 @@
+mod admin_newsletters;
+mod admin_subscribers;
+mod api_keys;
 mod health_check;
 mod helpers;
+mod housekeeping;
+mod login;
+mod newsletters;
+mod pending_subscribers;
+mod roles;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_resend;
+mod subscriptions_unsubscribe;
+mod users;
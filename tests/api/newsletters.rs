@@ -0,0 +1,664 @@
+use crate::admin_subscribers::insert_subscriber;
+use crate::helpers::{batch_delivery_response, spawn_app, ConfirmationLinks, TestApp};
+use api::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let _mock_guard = Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_links = create_unconfirmed_subscriber(app).await;
+
+    app.confirm_subscription(confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+#[actix_rt::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    // 未確認のsubscriberにはメールが送信されないことを確認
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[actix_rt::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[actix_rt::test]
+async fn newsletters_carry_a_one_click_unsubscribe_link() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    app.post_newsletters(newsletter_request_body).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let unsubscribe_link = app.get_unsubscribe_link(&email_request);
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body[0]["text"]
+        .as_str()
+        .unwrap()
+        .contains(unsubscribe_link.path()));
+}
+
+#[actix_rt::test]
+async fn confirmed_subscribers_with_an_invalid_stored_email_are_skipped() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // 保存済みのメールアドレスを不正な値に書き換える
+    sqlx::query!("UPDATE subscriptions SET email = 'definitely-not-an-email'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[actix_rt::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        let response = app.post_newsletters(invalid_body).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn concurrent_workers_do_not_deliver_the_same_task_twice() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    app.post_newsletters(newsletter_request_body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // 複数のインスタンスが同時にキューを処理している状況を再現する
+    let (first, second) = tokio::join!(
+        try_execute_task(
+            &app.db_pool,
+            app.email_client.as_ref(),
+            &app.email_templates,
+            &app.base_url,
+            &app.preferences_link_signer
+        ),
+        try_execute_task(
+            &app.db_pool,
+            app.email_client.as_ref(),
+            &app.email_templates,
+            &app.base_url,
+            &app.preferences_link_signer
+        )
+    );
+    first.unwrap();
+    second.unwrap();
+    app.dispatch_all_pending_emails().await;
+}
+
+#[actix_rt::test]
+async fn a_task_leased_by_another_worker_is_skipped_until_the_lease_expires() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    let execute_task = || {
+        try_execute_task(
+            &app.db_pool,
+            app.email_client.as_ref(),
+            &app.email_templates,
+            &app.base_url,
+            &app.preferences_link_signer,
+        )
+    };
+    let set_lease = |seconds: f64| {
+        sqlx::query!(
+            "UPDATE issue_delivery_queue SET locked_until = now() + make_interval(secs => $1)",
+            seconds
+        )
+        .execute(&app.db_pool)
+    };
+
+    // 送信中のワーカーが確保しているタスクは処理しない
+    set_lease(3600.0).await.unwrap();
+    assert!(matches!(
+        execute_task().await.unwrap(),
+        ExecutionOutcome::EmptyQueue
+    ));
+
+    // 確保したワーカーが落ちて期限が切れたタスクは、別のワーカーが処理し直す
+    set_lease(-1.0).await.unwrap();
+    assert!(matches!(
+        execute_task().await.unwrap(),
+        ExecutionOutcome::TaskCompleted
+    ));
+    let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, 0);
+}
+
+async fn publish_a_newsletter_and_try_to_deliver_it(app: &TestApp) -> ExecutionOutcome {
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    try_execute_task(
+        &app.db_pool,
+        app.email_client.as_ref(),
+        &app.email_templates,
+        &app.base_url,
+        &app.preferences_link_signer,
+    )
+    .await
+    .unwrap()
+}
+
+fn failed_delivery_response() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(serde_json::json!([
+        { "error_code": 406, "message": "Inactive recipient" }
+    ]))
+}
+
+#[actix_rt::test]
+async fn a_failed_delivery_is_retried_later() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(failed_delivery_response())
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    publish_a_newsletter_and_try_to_deliver_it(&app).await;
+
+    // 失敗したタスクは残り、確保を解除したうえで待ち時間の後に回される
+    let task = sqlx::query!(
+        r#"SELECT n_attempts, locked_until, deliver_after > now() AS "waiting!"
+        FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(task.n_attempts, 1);
+    assert!(task.locked_until.is_none());
+    assert!(task.waiting);
+
+    // 待ち時間が過ぎれば送り直される
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!("UPDATE issue_delivery_queue SET deliver_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, 0);
+}
+
+#[actix_rt::test]
+async fn a_delivery_is_given_up_after_too_many_failed_attempts() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(failed_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // 上限(10回)の一歩手前まで失敗している状態にする
+    sqlx::query!("ALTER TABLE issue_delivery_queue ALTER COLUMN n_attempts SET DEFAULT 9")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    publish_a_newsletter_and_try_to_deliver_it(&app).await;
+
+    let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, 0);
+}
+
+#[actix_rt::test]
+async fn concurrent_form_submission_is_handled_gracefully() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let response1 = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body.clone(), &idempotency_key);
+    let response2 =
+        app.post_newsletters_with_idempotency_key(newsletter_request_body, &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(
+        response1.text().await.unwrap(),
+        response2.text().await.unwrap()
+    );
+
+    app.dispatch_all_pending_emails().await;
+}
+
+#[actix_rt::test]
+async fn an_idempotency_key_used_on_another_route_does_not_replay_its_response() {
+    let app = spawn_app().await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_with_idempotency_key(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com".into(),
+            &idempotency_key,
+        )
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    // キーはルートごとに区別されるため、号はそのまま発行される
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT COUNT(*) AS count FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved issues.");
+    assert_eq!(saved.count, Some(1));
+}
+
+// 購読者の設定を直接保存する
+async fn save_preferences(app: &TestApp, email: &str, topics: &[&str], delivery_mode: &str) {
+    let topics: Vec<String> = topics.iter().map(|t| t.to_string()).collect();
+    sqlx::query!(
+        r#"INSERT INTO subscriber_preferences (subscriber_id, topics, delivery_mode, updated_at)
+        SELECT id, $2, $3, now() FROM subscriptions WHERE email = $1"#,
+        email,
+        &topics,
+        delivery_mode
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to save the preferences.");
+}
+
+async fn queued_recipients(app: &TestApp) -> Vec<String> {
+    sqlx::query!(
+        r#"SELECT subscriber_email FROM issue_delivery_queue
+        WHERE deliver_after <= now()
+        ORDER BY subscriber_email"#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| r.subscriber_email)
+    .collect()
+}
+
+#[actix_rt::test]
+async fn an_issue_with_a_topic_is_only_queued_for_subscribers_who_chose_it() {
+    let app = spawn_app().await;
+    for email in ["a@example.com", "b@example.com", "c@example.com"] {
+        insert_subscriber(&app, email, "name", "confirmed", chrono::Duration::zero()).await;
+    }
+    save_preferences(&app, "a@example.com", &["events"], "instant").await;
+    save_preferences(&app, "b@example.com", &["articles"], "instant").await;
+
+    let mut body = newsletter_request_body();
+    body["topic"] = "events".into();
+    app.post_newsletters(body).await.error_for_status().unwrap();
+
+    // 設定を保存していない購読者は、すべてのトピックを受け取る
+    assert_eq!(
+        queued_recipients(&app).await,
+        vec!["a@example.com", "c@example.com"]
+    );
+}
+
+#[actix_rt::test]
+async fn an_unknown_topic_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let mut body = newsletter_request_body();
+    body["topic"] = "gossip".into();
+    let response = app.post_newsletters(body).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[actix_rt::test]
+async fn digest_subscribers_are_not_sent_the_issue_right_away() {
+    let app = spawn_app().await;
+    for email in ["a@example.com", "b@example.com"] {
+        insert_subscriber(&app, email, "name", "confirmed", chrono::Duration::zero()).await;
+    }
+    save_preferences(&app, "b@example.com", &["articles"], "digest").await;
+
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(queued_recipients(&app).await, vec!["a@example.com"]);
+    let deferred = sqlx::query!(
+        r#"SELECT subscriber_email FROM issue_delivery_queue WHERE deliver_after > now()"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(deferred.subscriber_email, "b@example.com");
+}
+
+#[actix_rt::test]
+async fn digest_subscribers_get_the_issues_of_the_day_in_one_email() {
+    let app = spawn_app().await;
+    insert_subscriber(
+        &app,
+        "a@example.com",
+        "name",
+        "confirmed",
+        chrono::Duration::zero(),
+    )
+    .await;
+    save_preferences(&app, "a@example.com", &["articles"], "digest").await;
+
+    Mock::given(path("/messages/batch"))
+        .and(method("POST"))
+        .respond_with(batch_delivery_response())
+        .mount(&app.email_server)
+        .await;
+
+    for title in ["First issue", "Second issue"] {
+        let mut body = newsletter_request_body();
+        body["title"] = title.into();
+        app.post_newsletters(body).await.error_for_status().unwrap();
+    }
+    // 翌日の配信時刻になったことにする
+    sqlx::query!("UPDATE issue_delivery_queue SET deliver_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let messages: Vec<serde_json::Value> = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .flat_map(|r| serde_json::from_slice::<Vec<serde_json::Value>>(&r.body).unwrap())
+        .collect();
+    assert_eq!(messages.len(), 1);
+    let text = messages[0]["text"].as_str().unwrap();
+    assert!(text.contains("First issue"));
+    assert!(text.contains("Second issue"));
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[actix_rt::test]
+async fn requests_missing_authorization_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[actix_rt::test]
+async fn non_existing_user_is_rejected() {
+    let app = spawn_app().await;
+    let username = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[actix_rt::test]
+async fn invalid_password_is_rejected() {
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    let password = Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(401, response.status().as_u16());
+    let saved = sqlx::query!("SELECT COUNT(*) AS count FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, Some(0));
+}
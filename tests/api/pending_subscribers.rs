@@ -0,0 +1,173 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// 登録日時を過去にずらし、時間が経過した状態を再現する
+async fn backdate_subscriptions(app: &TestApp, duration: chrono::Duration) {
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = subscribed_at - make_interval(secs => $1)",
+        duration.num_seconds() as f64
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[actix_rt::test]
+async fn a_reminder_is_sent_once_to_a_stale_pending_subscriber() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    backdate_subscriptions(&app, chrono::Duration::hours(25)).await;
+
+    let n_sent = app
+        .pending_subscriber_job
+        .send_reminders(&app.db_pool, &app.email_templates)
+        .await
+        .unwrap();
+    assert_eq!(n_sent, 1);
+    // 二度目の実行では送られない
+    let n_sent = app
+        .pending_subscriber_job
+        .send_reminders(&app.db_pool, &app.email_templates)
+        .await
+        .unwrap();
+    assert_eq!(n_sent, 0);
+    app.dispatch_all_pending_emails().await;
+
+    // リマインダーのリンクで確認できる
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_requests[1]);
+    let response = app.confirm_subscription(confirmation_links.html).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn no_reminder_is_sent_before_the_delay() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    let n_sent = app
+        .pending_subscriber_job
+        .send_reminders(&app.db_pool, &app.email_templates)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(n_sent, 0);
+}
+
+#[actix_rt::test]
+async fn stale_pending_subscribers_are_purged_with_their_tokens() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // 確認済みのsubscriberは削除されない
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_requests[0]);
+    app.confirm_subscription(confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.post_subscriptions("name=octavia&email=octavia_butler%40gmail.com".into())
+        .await;
+    backdate_subscriptions(&app, chrono::Duration::days(15)).await;
+
+    let outcome = app
+        .pending_subscriber_job
+        .purge(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(outcome.n_subscribers, 1);
+    assert_eq!(outcome.n_tokens, 1);
+    let subscribers = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.len(), 1);
+    assert_eq!(subscribers[0].email, "ursula_le_guin@gmail.com");
+    let remaining_tokens = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM subscription_tokens t
+        LEFT JOIN subscriptions s ON s.id = t.subscriber_id
+        WHERE s.id IS NULL"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(remaining_tokens.count, 0);
+}
+
+#[actix_rt::test]
+async fn signing_up_again_restarts_the_reminder_and_purge_clocks() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // リマインダーを受け取った後に購読解除し、しばらくしてから登録し直す
+    app.post_subscriptions(body.into()).await;
+    backdate_subscriptions(&app, chrono::Duration::hours(25)).await;
+    app.pending_subscriber_job
+        .send_reminders(&app.db_pool, &app.email_templates)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    backdate_subscriptions(&app, chrono::Duration::days(15)).await;
+    app.post_subscriptions(body.into()).await;
+
+    // 登録し直した直後は、リマインダーも削除も対象にならない
+    let n_sent = app
+        .pending_subscriber_job
+        .send_reminders(&app.db_pool, &app.email_templates)
+        .await
+        .unwrap();
+    assert_eq!(n_sent, 0);
+    let outcome = app
+        .pending_subscriber_job
+        .purge(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outcome.n_subscribers, 0);
+
+    // 期限が過ぎれば、改めてリマインダーが送られる
+    backdate_subscriptions(&app, chrono::Duration::hours(25)).await;
+    let n_sent = app
+        .pending_subscriber_job
+        .send_reminders(&app.db_pool, &app.email_templates)
+        .await
+        .unwrap();
+    assert_eq!(n_sent, 1);
+}
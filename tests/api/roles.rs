@@ -0,0 +1,125 @@
+use crate::helpers::{
+    assert_every_admin_route_has_a_permission, assert_is_redirect_to, spawn_app, TestApp, TestUser,
+};
+use api::authentication::Role;
+use uuid::Uuid;
+
+async fn login_with_role(app: &TestApp, role: Role) -> TestUser {
+    let user = TestUser::generate_with_role(role);
+    user.store(&app.db_pool).await;
+    app.login_as(&user).await;
+    user
+}
+
+fn newsletter_form() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "idempotency_key": Uuid::new_v4().to_string(),
+    })
+}
+
+fn newsletter_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[test]
+fn every_admin_route_has_a_declared_permission() {
+    assert_every_admin_route_has_a_permission();
+}
+
+#[actix_rt::test]
+async fn a_viewer_can_browse_but_not_write_issues() {
+    let app = spawn_app().await;
+    login_with_role(&app, Role::Viewer).await;
+
+    assert_eq!(app.get_admin_dashboard().await.status().as_u16(), 200);
+    assert_eq!(app.get_admin_subscribers("").await.status().as_u16(), 200);
+
+    let response = app
+        .api_client
+        .get(format!("{}/admin/newsletters", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 403);
+    let response = app.post_admin_newsletter_preview(&newsletter_form()).await;
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[actix_rt::test]
+async fn an_editor_can_preview_but_not_publish() {
+    let app = spawn_app().await;
+    login_with_role(&app, Role::Editor).await;
+
+    let response = app.post_admin_newsletter_preview(&newsletter_form()).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = app.post_admin_newsletters(&newsletter_form()).await;
+    assert_eq!(response.status().as_u16(), 403);
+    let n_issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count the newsletter issues.")
+        .count;
+    assert_eq!(n_issues, 0);
+}
+
+#[actix_rt::test]
+async fn a_publisher_can_publish() {
+    let app = spawn_app().await;
+    login_with_role(&app, Role::Publisher).await;
+
+    let response = app.post_admin_newsletters(&newsletter_form()).await;
+
+    assert_is_redirect_to(&response, "/admin/newsletters");
+}
+
+#[actix_rt::test]
+async fn api_permissions_are_checked_for_basic_auth_users() {
+    let app = spawn_app().await;
+    let editor = TestUser::generate_with_role(Role::Editor);
+    editor.store(&app.db_pool).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(&editor.username, Some(&editor.password))
+        .json(&newsletter_body())
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscribers", &app.address))
+        .basic_auth(&editor.username, Some(&editor.password))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn anonymous_api_requests_are_still_rejected_with_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&newsletter_body())
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
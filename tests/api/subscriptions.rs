@@ -1,4 +1,6 @@
 use crate::helpers::spawn_app;
+use api::email_outbox::try_dispatch_email;
+use api::issue_delivery_worker::ExecutionOutcome;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -36,6 +38,7 @@ async fn subscribe_returns_a_200_for_valid_from_data() {
         .await;
 
     let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     assert_eq!(200, response.status().as_u16());
 }
@@ -53,6 +56,7 @@ async fn subscribe_persists_the_new_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
         .fetch_one(&app.db_pool)
@@ -98,9 +102,381 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     assert_eq!(confirmation_links.html, confirmation_links.text);
 }
+
+#[actix_rt::test]
+async fn subscribe_is_idempotent() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    // 同じキーで再送した場合は、保存済みのレスポンスが返される
+    let response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let saved = sqlx::query!("SELECT COUNT(*) AS count FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.count, Some(1));
+}
+
+#[actix_rt::test]
+async fn reusing_an_idempotency_key_for_a_different_subscription_is_rejected() {
+    let app = spawn_app().await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_with_idempotency_key(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com".into(),
+            &idempotency_key,
+        )
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    // 同じキーで別のメールアドレスを送った場合は、保存済みのレスポンスを返さずに拒否する
+    let response = app
+        .post_subscriptions_with_idempotency_key(
+            "name=tolkien&email=tolkien%40gmail.com".into(),
+            &idempotency_key,
+        )
+        .await;
+    assert_eq!(422, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT COUNT(*) AS count FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.count, Some(1));
+}
+
+#[actix_rt::test]
+async fn subscribe_returns_a_400_when_the_idempotency_key_is_invalid() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    for idempotency_key in [" ".to_string(), "a".repeat(50)] {
+        let response = app
+            .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+            .await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the idempotency key was {:?}.",
+            idempotency_key
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn subscribe_succeeds_even_if_the_email_provider_is_down() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    // 送信に失敗したメールはoutboxに残り、後で再送される
+    app.dispatch_all_pending_emails().await;
+    let saved = sqlx::query!("SELECT n_attempts, last_error FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the outbox email.");
+
+    assert!(saved.n_attempts >= 1);
+    assert!(saved.last_error.is_some());
+}
+
+#[actix_rt::test]
+async fn an_email_is_not_locked_in_the_database_while_it_is_being_sent() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(500)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+
+    // 送信中の行は確保済みとして扱われるが、行ロックは取られていない
+    let (dispatched, locked) = tokio::join!(
+        try_dispatch_email(&app.db_pool, app.email_client.as_ref()),
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            let mut transaction = app.db_pool.begin().await.unwrap();
+            let locked = sqlx::query!("SELECT email_id FROM email_outbox FOR UPDATE NOWAIT")
+                .fetch_all(&mut transaction)
+                .await;
+            let claimed = try_dispatch_email(&app.db_pool, app.email_client.as_ref()).await;
+            (locked, claimed)
+        }
+    );
+
+    assert!(matches!(
+        dispatched.unwrap(),
+        ExecutionOutcome::TaskCompleted
+    ));
+    let (locked, claimed) = locked;
+    assert_eq!(locked.unwrap().len(), 1);
+    // 他のディスパッチャは、確保中のメールを送信しない
+    assert!(matches!(claimed.unwrap(), ExecutionOutcome::EmptyQueue));
+}
+
+#[actix_rt::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // outboxへの書き込みに失敗した場合、subscriberも保存されない
+    sqlx::query!("ALTER TABLE email_outbox DROP COLUMN recipient;")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(500, response.status().as_u16());
+
+    let saved = sqlx::query!("SELECT COUNT(*) AS count FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.count, Some(0));
+}
+
+#[actix_rt::test]
+async fn subscribing_again_while_pending_sends_a_new_confirmation_link() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let first = app.post_subscriptions(body.into()).await;
+    let second = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]);
+    let second_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(first_links.html, second_links.html);
+
+    // 新しいリンクで確認できる
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn subscribing_again_when_confirmed_sends_an_already_subscribed_notice() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.confirm_subscription(confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(email["text"]
+        .as_str()
+        .unwrap()
+        .contains("you're already subscribed"));
+    assert!(!email["text"]
+        .as_str()
+        .unwrap()
+        .contains("subscription_token"));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn repeat_sign_ups_get_the_same_response_as_new_ones() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let new_sign_up = app.post_subscriptions(body.into()).await;
+    let new_status = new_sign_up.status();
+    let new_body = new_sign_up.bytes().await.unwrap();
+
+    let repeat_sign_up = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(repeat_sign_up.status(), new_status);
+    assert_eq!(repeat_sign_up.bytes().await.unwrap(), new_body);
+}
+
+#[actix_rt::test]
+async fn subscribing_again_after_a_complaint_sends_nothing() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'complained'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "complained");
+}
+
+#[actix_rt::test]
+async fn subscriptions_reject_an_unknown_status() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+
+    let result = sqlx::query!("UPDATE subscriptions SET status = 'active'")
+        .execute(&app.db_pool)
+        .await;
+
+    assert!(result.is_err());
+}
+
+#[actix_rt::test]
+async fn repeated_sign_ups_for_the_same_address_are_throttled() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // 設定上の上限(base.ymlでは3回)までは受け付ける
+    for _ in 0..3 {
+        let response = app.post_subscriptions(body.into()).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(response.status().as_u16(), 429);
+
+    // 確認メールは受け付けた分だけ送られる
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 3);
+}
+
+#[actix_rt::test]
+async fn concurrent_first_sign_ups_for_the_same_address_both_succeed() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let (first, second) = tokio::join!(
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into())
+    );
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 1);
+}
+
+#[actix_rt::test]
+async fn sign_ups_for_different_addresses_are_not_throttled_by_client_ip() {
+    let app = spawn_app().await;
+
+    // 再送のIPアドレスごとの上限(base.ymlでは10回)を超えても受け付ける
+    for i in 0..11 {
+        let response = app
+            .post_subscriptions(format!("name=reader&email=reader{}%40gmail.com", i))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with};
+use api::configuration::get_configuration;
+use api::subscription_tokens::hash_plaintext_tokens;
+use uuid::Uuid;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -11,6 +14,8 @@ async fn confirmations_without_token_are_rejected_with_a_400() {
         .unwrap();
 
     assert_eq!(response.status().as_u16(), 400);
+    let response = app.post_confirm("".into()).await;
+    assert_eq!(response.status().as_u16(), 400);
 }
 
 #[actix_rt::test]
@@ -26,10 +31,11 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
-    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    let response = app.confirm_subscription(confirmation_links.html).await;
     assert_eq!(response.status().as_u16(), 200);
 }
 
@@ -46,12 +52,12 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
-    reqwest::get(confirmation_links.html)
+    app.confirm_subscription(confirmation_links.html)
         .await
-        .unwrap()
         .error_for_status()
         .unwrap();
 
@@ -64,3 +70,409 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[actix_rt::test]
+async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = app.post_confirm("subscription_token=unknown".into()).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn a_token_with_a_known_id_but_the_wrong_secret_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let (_, subscription_token) = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(k, _)| k == "subscription_token")
+        .unwrap();
+    let (token_id, _) = subscription_token.split_once('.').unwrap();
+
+    let response = app
+        .post_confirm(format!("subscription_token={}.wrong-secret", token_id))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[actix_rt::test]
+async fn a_confirmation_link_can_only_be_used_once() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    let first = app
+        .confirm_subscription(confirmation_links.html.clone())
+        .await;
+    let second = app.confirm_subscription(confirmation_links.html).await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 409);
+}
+
+#[actix_rt::test]
+async fn an_expired_confirmation_link_is_rejected_with_a_410() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    // トークンの有効期限を過ぎた状態にする
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.confirm_subscription(confirmation_links.html).await;
+
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[actix_rt::test]
+async fn subscription_tokens_are_not_stored_in_plaintext() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let (_, subscription_token) = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(k, _)| k == "subscription_token")
+        .unwrap();
+
+    let saved = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved token.");
+    assert_ne!(saved.subscription_token_hash, subscription_token);
+}
+
+#[actix_rt::test]
+async fn plaintext_tokens_from_before_hashing_can_still_be_used() {
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'le guin', now(), 'pending_confirmation', 'token')"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"INSERT INTO subscription_tokens (
+            subscription_token_hash, subscriber_id, created_at, expires_at, is_plaintext
+        )
+        VALUES ('legacy-plaintext-token', $1, now(), now() + interval '1 hour', true)"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let hasher = get_configuration()
+        .unwrap()
+        .application
+        .subscription_token_hasher();
+    let n_hashed = hash_plaintext_tokens(&app.db_pool, &hasher).await.unwrap();
+    assert_eq!(n_hashed, 1);
+
+    let response = app
+        .post_confirm("subscription_token=legacy-plaintext-token".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn an_old_confirmation_link_does_not_resubscribe_an_unsubscribed_address() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.unsubscribe(app.get_unsubscribe_link(email_request))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = app.confirm_subscription(confirmation_links.html).await;
+
+    assert_eq!(response.status().as_u16(), 409);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[actix_rt::test]
+async fn an_older_link_used_after_confirming_with_a_newer_one_reports_already_confirmed() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let older_links = app.get_confirmation_links(&email_requests[0]);
+    let newer_links = app.get_confirmation_links(&email_requests[1]);
+    app.confirm_subscription(newer_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = app.confirm_subscription(older_links.html).await;
+
+    assert_eq!(response.status().as_u16(), 409);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Your subscription has already been confirmed."));
+}
+
+#[actix_rt::test]
+async fn opening_the_confirmation_link_shows_a_button_without_confirming() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let (_, subscription_token) = confirmation_links
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap();
+    let subscription_token = subscription_token.into_owned();
+
+    // リンクスキャナによる先読みを想定して、二度開く
+    reqwest::get(confirmation_links.html.clone()).await.unwrap();
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(r#"<form action="/subscriptions/confirm" method="post">"#));
+    // 属性値はエスケープされて埋め込まれる
+    assert!(html_page.contains(&format!(
+        r#"name="subscription_token" value="{}""#,
+        htmlescape::encode_attribute(&subscription_token)
+    )));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[actix_rt::test]
+async fn the_confirmation_link_confirms_on_get_when_configured() {
+    let app = spawn_app_with(|c| c.application.confirm_on_get = true).await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn confirming_shows_a_page_for_each_outcome() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    let test_cases = vec![
+        (
+            app.confirm_subscription(confirmation_links.html.clone())
+                .await,
+            200,
+            "Thanks for confirming your subscription!",
+        ),
+        (
+            app.confirm_subscription(confirmation_links.html).await,
+            409,
+            "Your subscription has already been confirmed.",
+        ),
+        (
+            app.post_confirm("subscription_token=unknown".into()).await,
+            401,
+            "This confirmation link is not valid.",
+        ),
+    ];
+    for (response, expected_status, expected_message) in test_cases {
+        assert_eq!(response.status().as_u16(), expected_status);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert!(response.text().await.unwrap().contains(expected_message));
+    }
+}
+
+#[actix_rt::test]
+async fn confirming_redirects_to_the_configured_urls_with_the_outcome() {
+    let app = spawn_app_with(|c| {
+        c.application.confirm_success_url = Some("https://example.com/subscribed".into());
+        c.application.confirm_failure_url = Some("https://example.com/failed?lang=en".into());
+    })
+    .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let test_cases = vec![
+        (
+            app.confirm_subscription(confirmation_links.html).await,
+            "https://example.com/failed?lang=en&outcome=expired",
+        ),
+        (
+            app.post_confirm("subscription_token=unknown".into()).await,
+            "https://example.com/failed?lang=en&outcome=unknown",
+        ),
+    ];
+    for (response, expected_location) in test_cases {
+        assert_eq!(response.status().as_u16(), 303);
+        assert_eq!(response.headers()["location"], expected_location);
+    }
+
+    // 新しいリンクで確認すると成功時のURLへ移動する
+    app.post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let response = app.confirm_subscription(confirmation_links.html).await;
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(
+        response.headers()["location"],
+        "https://example.com/subscribed?outcome=confirmed"
+    );
+}
@@ -0,0 +1,302 @@
+use crate::helpers::{spawn_app, TestApp};
+use api::configuration::{get_configuration, KeyPurpose};
+use api::routes::PreferencesLinkSigner;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_confirmed_subscriber(app: &TestApp) -> Uuid {
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'le guin', now(), 'confirmed', 'token')"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    subscriber_id
+}
+
+// 署名付きリンクのクエリを、フォームのhiddenフィールドと同じ組に変換する
+fn link_parameters(link: &str) -> Vec<(String, String)> {
+    reqwest::Url::parse(link)
+        .unwrap()
+        .query_pairs()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect()
+}
+
+async fn post_preferences(app: &TestApp, link: &str, fields: &[(&str, &str)]) -> reqwest::Response {
+    let mut form = link_parameters(link);
+    form.extend(fields.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions/preferences", app.address))
+        .form(&form)
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+#[actix_rt::test]
+async fn a_signed_link_shows_the_current_preferences() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    let link = app
+        .preferences_link_signer
+        .link(&app.address, subscriber_id);
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.text().await.unwrap();
+    // 属性値はエスケープされて埋め込まれる
+    for value in ["le guin", "ursula_le_guin@gmail.com"] {
+        assert!(body.contains(&format!(
+            r#"value="{}""#,
+            htmlescape::encode_attribute(value)
+        )));
+    }
+}
+
+#[actix_rt::test]
+async fn a_tampered_link_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let other_subscriber_link = app
+        .preferences_link_signer
+        .link(&app.address, Uuid::new_v4());
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+
+    // 別のsubscriber宛のリンクのIDだけを書き換える
+    let mut link = reqwest::Url::parse(&other_subscriber_link).unwrap();
+    let query: Vec<(String, String)> = link_parameters(&other_subscriber_link)
+        .into_iter()
+        .map(|(k, v)| match k.as_str() {
+            "subscriber_id" => (k, subscriber_id.to_string()),
+            _ => (k, v),
+        })
+        .collect();
+    link.query_pairs_mut().clear().extend_pairs(query);
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn an_expired_link_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    let configuration = get_configuration().unwrap();
+    let expired_signer = PreferencesLinkSigner::new(
+        configuration
+            .application
+            .derive_key(KeyPurpose::PreferencesLinks),
+        chrono::Duration::hours(-1),
+    );
+    let link = expired_signer.link(&app.address, subscriber_id);
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert!(response.text().await.unwrap().contains("expired"));
+}
+
+#[actix_rt::test]
+async fn valid_preferences_are_saved() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    let link = app
+        .preferences_link_signer
+        .link(&app.address, subscriber_id);
+
+    let response = post_preferences(
+        &app,
+        &link,
+        &[
+            ("name", "Ursula"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("topic", "articles"),
+            ("topic", "events"),
+            ("delivery_mode", "digest"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "Ursula");
+    // メールアドレスが変わらなければ、確認済みのまま
+    assert_eq!(saved.status, "confirmed");
+
+    let saved = sqlx::query!("SELECT topics, delivery_mode FROM subscriber_preferences")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved preferences.");
+    assert_eq!(saved.topics, vec!["articles", "events"]);
+    assert_eq!(saved.delivery_mode, "digest");
+}
+
+#[actix_rt::test]
+async fn invalid_preferences_show_the_validation_messages() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    let link = app
+        .preferences_link_signer
+        .link(&app.address, subscriber_id);
+
+    let test_cases = vec![
+        (
+            "",
+            "ursula@example.com",
+            "instant",
+            " is not a valid subscriber name.",
+        ),
+        (
+            "Ursula",
+            "not-an-email",
+            "instant",
+            "not-an-email is not a valid subscriber email.",
+        ),
+        (
+            "<Ursula>",
+            "ursula@example.com",
+            "instant",
+            "&lt;Ursula&gt; is not a valid subscriber name.",
+        ),
+        (
+            "Ursula",
+            "ursula@example.com",
+            "weekly",
+            "weekly is not a valid delivery mode.",
+        ),
+    ];
+
+    for (name, email, delivery_mode, message) in test_cases {
+        let response = post_preferences(
+            &app,
+            &link,
+            &[
+                ("name", name),
+                ("email", email),
+                ("delivery_mode", delivery_mode),
+            ],
+        )
+        .await;
+
+        assert_eq!(response.status().as_u16(), 400);
+        assert!(response.text().await.unwrap().contains(message));
+    }
+
+    let saved = sqlx::query!("SELECT name, email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+#[actix_rt::test]
+async fn changing_the_email_requires_confirming_the_new_address() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    let link = app
+        .preferences_link_signer
+        .link(&app.address, subscriber_id);
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = post_preferences(
+        &app,
+        &link,
+        &[
+            ("name", "le guin"),
+            ("email", "ursula@example.com"),
+            ("delivery_mode", "instant"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Please confirm your new email address"));
+    let saved = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@example.com");
+    assert_eq!(saved.status, "pending_confirmation");
+
+    // 確認メールは新しいアドレスに届き、そのリンクで再び確認済みになる
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    assert!(String::from_utf8_lossy(&email_request.body).contains("ursula@example.com"));
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.confirm_subscription(confirmation_links.html).await;
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn an_email_already_in_use_is_rejected() {
+    let app = spawn_app().await;
+    let subscriber_id = create_confirmed_subscriber(&app).await;
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, 'ursula@example.com', 'ursula', now(), 'confirmed', 'other-token')"#,
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let link = app
+        .preferences_link_signer
+        .link(&app.address, subscriber_id);
+
+    let response = post_preferences(
+        &app,
+        &link,
+        &[
+            ("name", "le guin"),
+            ("email", "ursula@example.com"),
+            ("delivery_mode", "instant"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("ursula@example.com is already in use."));
+    let saved = sqlx::query!(
+        "SELECT status FROM subscriptions WHERE id = $1",
+        subscriber_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
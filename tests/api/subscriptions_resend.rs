@@ -0,0 +1,127 @@
+use crate::helpers::spawn_app;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[actix_rt::test]
+async fn resend_confirmation_sends_a_new_link_to_a_pending_subscriber() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let response = app
+        .post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_requests[1]);
+    let response = app.confirm_subscription(confirmation_links.html).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn resend_confirmation_does_not_reveal_unknown_addresses() {
+    let app = spawn_app().await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_resend_confirmation("email=nobody%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn resend_confirmation_returns_a_400_for_an_invalid_email() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_resend_confirmation("email=definitely-not-an-email".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[actix_rt::test]
+async fn resend_confirmation_is_throttled_per_address() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // 登録と再送で合わせて設定上の上限(base.ymlでは3回)までは受け付ける
+    for _ in 0..2 {
+        let response = app
+            .post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = app
+        .post_resend_confirmation("email=ursula_le_guin%40gmail.com".into())
+        .await;
+    assert_eq!(response.status().as_u16(), 429);
+
+    // 確認メールは登録時の1通と再送の2通だけ
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 3);
+}
+
+#[actix_rt::test]
+async fn resend_confirmation_is_throttled_per_client_ip() {
+    let app = spawn_app().await;
+
+    // 設定上の上限(base.ymlでは10回)までは、アドレスが違っても同じIPアドレスからは受け付ける
+    for i in 0..10 {
+        let response = app
+            .post_resend_confirmation(format!("email=reader{}%40gmail.com", i))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = app
+        .post_resend_confirmation("email=reader10%40gmail.com".into())
+        .await;
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[actix_rt::test]
+async fn forwarded_headers_do_not_bypass_the_client_ip_throttle() {
+    let app = spawn_app().await;
+
+    // 転送ヘッダを毎回変えても、接続元のアドレスで数えられる
+    for i in 0..11 {
+        let response = reqwest::Client::new()
+            .post(format!(
+                "{}/subscriptions/resend-confirmation",
+                &app.address
+            ))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", format!("203.0.113.{}", i))
+            .header("Forwarded", format!("for=198.51.100.{}", i))
+            .body(format!("email=reader{}%40gmail.com", i))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        let expected = if i < 10 { 200 } else { 429 };
+        assert_eq!(response.status().as_u16(), expected);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// subscriberを登録して確認まで済ませ、確認メールに付与された購読解除リンクを返す
+async fn create_confirmed_subscriber(app: &TestApp) -> reqwest::Url {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let _mock_guard = Mock::given(path("/messages"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.confirm_subscription(confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    app.get_unsubscribe_link(email_request)
+}
+
+#[actix_rt::test]
+async fn unsubscribe_without_token_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/subscriptions/unsubscribe", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[actix_rt::test]
+async fn unsubscribe_with_an_unknown_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let unsubscribe_link = reqwest::Url::parse(&format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token=unknown",
+        app.address
+    ))
+    .unwrap();
+
+    let response = reqwest::get(unsubscribe_link.clone()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = app.unsubscribe(unsubscribe_link).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn opening_the_unsubscribe_link_does_not_unsubscribe_a_subscriber() {
+    let app = spawn_app().await;
+    let unsubscribe_link = create_confirmed_subscriber(&app).await;
+
+    // メールサーバのリンクスキャナによる先読みを想定する
+    let response = reqwest::get(unsubscribe_link.clone()).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"method="post""#));
+    assert!(html.contains(&format!(
+        r#"action="{}""#,
+        htmlescape::encode_attribute(&format!(
+            "{}?{}",
+            unsubscribe_link.path(),
+            unsubscribe_link.query().unwrap()
+        ))
+    )));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn one_click_unsubscribe_post_unsubscribes_a_subscriber() {
+    let app = spawn_app().await;
+    let unsubscribe_link = create_confirmed_subscriber(&app).await;
+
+    // RFC 8058でメールクライアントが送るリクエスト
+    let response = app.unsubscribe(unsubscribe_link).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[actix_rt::test]
+async fn newsletters_are_not_delivered_to_unsubscribed_subscribers() {
+    let app = spawn_app().await;
+    let unsubscribe_link = create_confirmed_subscriber(&app).await;
+    app.unsubscribe(unsubscribe_link)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+}
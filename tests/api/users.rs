@@ -0,0 +1,36 @@
+use crate::helpers::spawn_app_with;
+use api::configuration::InitialAdminSettings;
+
+#[actix_rt::test]
+async fn the_initial_admin_is_created_with_a_hashed_password() {
+    let app = spawn_app_with(|c| {
+        c.application.initial_admin = Some(InitialAdminSettings {
+            username: "admin".into(),
+            password: "correct horse battery staple".into(),
+        })
+    })
+    .await;
+
+    let saved = sqlx::query!("SELECT password_hash FROM users WHERE username = 'admin'")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the initial admin.");
+    assert!(saved.password_hash.starts_with("$argon2id$"));
+    assert!(!saved.password_hash.contains("correct horse battery staple"));
+
+    // 作成した管理者で認証できる
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth("admin", Some("correct horse battery staple"))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status().as_u16(), 200);
+}